@@ -1,8 +1,12 @@
+use crate::cli::otlp;
 use clap::Parser;
 use std::env;
+use std::path::PathBuf;
 use tracing::metadata::LevelFilter;
 use tracing::warn;
 use tracing_subscriber::EnvFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
 
 /// Attached database name
 pub const UQ_ATTACHED_DB_NAME: &str = "uquery_attached_db";
@@ -97,14 +101,145 @@ pub struct Options {
     #[arg(long, env = "UQ_ALLOWED_DIRECTORIES")]
     pub allowed_directories: Option<Vec<String>>,
 
+    /// Kinds of statements allowed to run, by leading keyword (`*` allows every statement)
+    #[arg(
+        default_value = "SELECT,WITH,DESCRIBE,SUMMARIZE",
+        long,
+        env = "UQ_ALLOWED_STATEMENTS",
+        value_delimiter = ','
+    )]
+    pub allowed_statements: Vec<String>,
+
+    /// Functions that queries may not call, e.g. read_text,read_blob,glob,getenv
+    #[arg(long, env = "UQ_DENIED_FUNCTIONS", value_delimiter = ',')]
+    pub denied_functions: Vec<String>,
+
+    /// JSON file of the row policies filtering the tables and files read by queries with
+    /// predicates on the caller's claims
+    #[arg(long, env = "UQ_ROW_POLICIES")]
+    pub row_policies: Option<PathBuf>,
+
+    /// JSON file of the masking policies hiding columns of the tables and files read by
+    /// queries from the callers who are not exempt
+    #[arg(long, env = "UQ_MASKING_POLICIES")]
+    pub masking_policies: Option<PathBuf>,
+
     /// Number of pre-cloned DuckDB connections kept in the pool
     #[arg(default_value = "4", long, env = "UQ_POOL_SIZE")]
     pub pool_size: usize,
 
+    /// Queries waiting at most for a connection when the pool is busy, the next ones
+    /// being refused with 503
+    #[arg(default_value = "16", long, env = "UQ_POOL_MAX_WAITING")]
+    pub pool_max_waiting: usize,
+
+    /// Maximum time in seconds a query waits for a connection (0 = no timeout)
+    #[arg(default_value = "10", long, env = "UQ_POOL_ACQUIRE_TIMEOUT")]
+    pub pool_acquire_timeout_secs: u64,
+
     /// Maximum query execution time in seconds (0 = no timeout)
     #[arg(default_value = "30", long, env = "UQ_QUERY_TIMEOUT")]
     pub query_timeout_secs: u64,
 
+    /// Maximum total execution time in seconds, including result streaming (0 = no timeout)
+    #[arg(default_value = "0", long, env = "UQ_EXECUTION_TIMEOUT")]
+    pub execution_timeout_secs: u64,
+
+    /// Directory where asynchronous job results are stored (defaults to a directory of the
+    /// process in the temporary directory)
+    #[arg(long, env = "UQ_JOBS_DIR")]
+    pub jobs_dir: Option<PathBuf>,
+
+    /// Retention time in seconds of finished asynchronous jobs and their results
+    #[arg(default_value = "3600", long, env = "UQ_JOBS_RETENTION")]
+    pub jobs_retention_secs: u64,
+
+    /// Asynchronous jobs queued or running at most, the next ones being refused with 503
+    #[arg(default_value = "64", long, env = "UQ_JOBS_MAX_PENDING")]
+    pub jobs_max_pending: usize,
+
+    /// Directory of `.sql` files exposed as `/q/{name}` endpoints, reloaded when they change
+    #[arg(long, env = "UQ_QUERIES_DIR")]
+    pub queries_dir: Option<PathBuf>,
+
+    /// Only run the saved queries of --queries-dir and refuse ad-hoc SQL
+    #[arg(long, env = "UQ_SAVED_QUERIES_ONLY", requires = "queries_dir")]
+    pub saved_queries_only: bool,
+
+    /// API keys as a JSON array of `{"name", "sha256", ...}` objects; every request but
+    /// /health then requires one of them
+    #[arg(long, env = "UQ_API_KEYS", conflicts_with = "api_keys_file")]
+    pub api_keys: Option<String>,
+
+    /// JSON file of API keys, like --api-keys, reloaded when it changes
+    #[arg(long, env = "UQ_API_KEYS_FILE")]
+    pub api_keys_file: Option<PathBuf>,
+
+    /// Accept JWT bearer tokens signed by a key of this JSON Web Key Set, a file path or
+    /// an http(s) URL
+    #[arg(long, env = "UQ_JWT_JWKS")]
+    pub jwt_jwks: Option<String>,
+
+    /// Time in seconds the JWT key set is cached before being fetched again
+    #[arg(default_value = "300", long, env = "UQ_JWT_JWKS_CACHE")]
+    pub jwt_jwks_cache_secs: u64,
+
+    /// Required `iss` claim of the JWTs
+    #[arg(long, env = "UQ_JWT_ISSUER", requires = "jwt_jwks")]
+    pub jwt_issuer: Option<String>,
+
+    /// Audience required in the `aud` claim of the JWTs
+    #[arg(long, env = "UQ_JWT_AUDIENCE", requires = "jwt_jwks")]
+    pub jwt_audience: Option<String>,
+
+    /// JWT claims exposed to the query policies besides `sub`, e.g. `tenant,roles`
+    #[arg(
+        long,
+        env = "UQ_JWT_CLAIMS",
+        value_delimiter = ',',
+        requires = "jwt_jwks"
+    )]
+    pub jwt_claims: Vec<String>,
+
+    /// JSON file of the policies granting permissions to JWTs by claim
+    #[arg(long, env = "UQ_JWT_POLICIES", requires = "jwt_jwks")]
+    pub jwt_policies: Option<PathBuf>,
+
+    /// Requests per second allowed to each API key, token subject or client address
+    #[arg(long, env = "UQ_RATE_LIMIT")]
+    pub rate_limit: Option<f64>,
+
+    /// Requests a caller may send at once after being idle, --rate-limit rounded up by
+    /// default
+    #[arg(long, env = "UQ_RATE_LIMIT_BURST")]
+    pub rate_limit_burst: Option<u32>,
+
+    /// Queries each API key, token subject or client address may run at the same time
+    #[arg(long, env = "UQ_MAX_CONCURRENT_QUERIES")]
+    pub max_concurrent_queries: Option<usize>,
+
+    /// Requests per second allowed across all callers
+    #[arg(long, env = "UQ_GLOBAL_RATE_LIMIT")]
+    pub global_rate_limit: Option<f64>,
+
+    /// Queries run at the same time across all callers
+    #[arg(long, env = "UQ_GLOBAL_MAX_CONCURRENT_QUERIES")]
+    pub global_max_concurrent_queries: Option<usize>,
+
+    /// Port serving the Prometheus metrics on /metrics, instead of the main port, on the
+    /// same address
+    #[arg(long, env = "UQ_METRICS_PORT")]
+    pub metrics_port: Option<u16>,
+
+    /// OpenTelemetry collector receiving the traces over OTLP/HTTP, such as
+    /// http://localhost:4318
+    #[arg(long, env = "UQ_OTLP_ENDPOINT")]
+    pub otlp_endpoint: Option<String>,
+
+    /// Service name of the exported traces
+    #[arg(default_value = "uquery", long, env = "UQ_OTLP_SERVICE_NAME")]
+    pub otlp_service_name: String,
+
     /// Install all DuckDB extensions and exit. Use this once after installation
     /// to pre-download extensions so the server starts without network access.
     #[arg(long, env = "UQ_INSTALL_EXTENSIONS")]
@@ -226,11 +361,17 @@ pub fn parse() -> Options {
         1 => tracing::Level::DEBUG,
         _ => tracing::Level::TRACE,
     };
-    tracing_subscriber::fmt()
-        .with_env_filter(
+    let otlp = opts.otlp_endpoint.as_deref().map(|endpoint| {
+        otlp::layer(endpoint, &opts.otlp_service_name)
+            .unwrap_or_else(|e| panic!("invalid OTLP settings: {e}"))
+    });
+    tracing_subscriber::registry()
+        .with(
             EnvFilter::new("pingora_core=off,pingora_pool=off,pingora_proxy=off")
                 .add_directive(LevelFilter::from(debug_level).into()),
         )
+        .with(tracing_subscriber::fmt::layer())
+        .with(otlp)
         .init();
     opts
 }
@@ -257,8 +398,36 @@ mod tests {
             ic_user: None,
             ic_secret: None,
             allowed_directories: None,
+            allowed_statements: vec!["SELECT".to_string()],
+            denied_functions: Vec::new(),
+            row_policies: None,
+            masking_policies: None,
             pool_size: 4,
+            pool_max_waiting: 16,
+            pool_acquire_timeout_secs: 10,
             query_timeout_secs: 30,
+            execution_timeout_secs: 0,
+            jobs_dir: None,
+            jobs_retention_secs: 3600,
+            jobs_max_pending: 64,
+            queries_dir: None,
+            saved_queries_only: false,
+            api_keys: None,
+            api_keys_file: None,
+            jwt_jwks: None,
+            jwt_jwks_cache_secs: 300,
+            jwt_issuer: None,
+            jwt_audience: None,
+            jwt_claims: Vec::new(),
+            jwt_policies: None,
+            rate_limit: None,
+            rate_limit_burst: None,
+            max_concurrent_queries: None,
+            global_rate_limit: None,
+            global_max_concurrent_queries: None,
+            metrics_port: None,
+            otlp_endpoint: None,
+            otlp_service_name: "uquery".into(),
             install_extensions: false,
         }
     }
@@ -0,0 +1,37 @@
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+/// Claims by name: those exposed to the queries of a caller, or those of a token.
+pub trait Claims {
+    fn get(&self, name: &str) -> Option<&Value>;
+}
+
+impl Claims for BTreeMap<String, Value> {
+    fn get(&self, name: &str) -> Option<&Value> {
+        BTreeMap::get(self, name)
+    }
+}
+
+impl Claims for Map<String, Value> {
+    fn get(&self, name: &str) -> Option<&Value> {
+        Map::get(self, name)
+    }
+}
+
+/// The claim `name`, or the nested claim `a.b` for `{"a": {"b": ...}}`.
+pub fn claim<'a>(claims: &'a impl Claims, name: &str) -> Option<&'a Value> {
+    claims.get(name).or_else(|| {
+        let (parent, child) = name.split_once('.')?;
+        claim(claims.get(parent)?.as_object()?, child)
+    })
+}
+
+/// Whether a claim has the `expected` value: one of its values for a list claim such
+/// as `roles`, and any of the values when `expected` is a list.
+pub fn claim_matches(claim: &Value, expected: &Value) -> bool {
+    match (claim, expected) {
+        (_, Value::Array(any)) => any.iter().any(|expected| claim_matches(claim, expected)),
+        (Value::Array(values), expected) => values.contains(expected),
+        (claim, expected) => claim == expected,
+    }
+}
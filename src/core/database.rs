@@ -0,0 +1,377 @@
+use crate::core::engine::{QueryDescription, QueryParameter};
+use arrow::datatypes::Schema;
+use arrow::ffi::FFI_ArrowSchema;
+use duckdb::{Connection, ffi};
+use std::ffi::{CStr, CString, c_char};
+use std::ops::Deref;
+use std::ptr;
+use std::sync::Arc;
+
+/// A DuckDB database opened through the C API. duckdb-rs connections don't expose
+/// their handles, so the database also hands out [`PooledConnection`]s pairing a
+/// duckdb-rs connection with a [`RawConnection`] for what duckdb-rs doesn't cover.
+pub struct Database {
+    raw: ffi::duckdb_database,
+}
+
+// SAFETY: a DuckDB instance may be connected to from any thread, and the handle is
+// only closed when the database is dropped, once it is borrowed by no thread.
+unsafe impl Send for Database {}
+// SAFETY: `&Database` only opens connections, which DuckDB synchronizes.
+unsafe impl Sync for Database {}
+
+impl Database {
+    /// An in-memory database, with the settings duckdb-rs opens its own databases with.
+    pub fn open_in_memory() -> Result<Self, String> {
+        let config = Config::new()?;
+        config.set("duckdb_api", "rust")?;
+        let mut raw = ptr::null_mut();
+        let mut error = ptr::null_mut();
+        // SAFETY: the path is a C string, `raw` and `error` are valid out pointers, and
+        // DuckDB copies the settings of `config`, alive during the call.
+        let state =
+            unsafe { ffi::duckdb_open_ext(c":memory:".as_ptr(), &mut raw, config.0, &mut error) };
+        if state != ffi::DuckDBSuccess {
+            // SAFETY: a failed open sets `error` to a message allocated for the caller.
+            return Err(unsafe { take_string(error) }
+                .unwrap_or_else(|| "failed to open the database".to_string()));
+        }
+        Ok(Self { raw })
+    }
+
+    /// A new connection to the database. Connections keep the instance alive, so they
+    /// may outlive the database.
+    pub fn connect(&self) -> Result<Connection, String> {
+        // SAFETY: `self.raw` is open until the database is dropped, and duckdb-rs
+        // doesn't close the databases it didn't open.
+        unsafe { Connection::open_from_raw(self.raw) }.map_err(|e| e.to_string())
+    }
+
+    /// A new connection to the database, with the raw connection inspecting its
+    /// statements. Both are used together, by one thread at a time.
+    pub(crate) fn connect_pooled(&self) -> Result<PooledConnection, String> {
+        let conn = self.connect()?;
+        let mut raw = ptr::null_mut();
+        // SAFETY: `self.raw` is an open database and `raw` a valid out pointer.
+        if unsafe { ffi::duckdb_connect(self.raw, &mut raw) } != ffi::DuckDBSuccess {
+            return Err("failed to connect to the database".to_string());
+        }
+        Ok(PooledConnection {
+            conn,
+            raw: RawConnection { raw },
+        })
+    }
+}
+
+impl Drop for Database {
+    fn drop(&mut self) {
+        // SAFETY: the database was opened by `open_in_memory` and is closed once; the
+        // connections still open keep the instance alive.
+        unsafe { ffi::duckdb_close(&mut self.raw) };
+    }
+}
+
+/// Settings to open a database with, destroyed when dropped.
+struct Config(ffi::duckdb_config);
+
+impl Config {
+    fn new() -> Result<Self, String> {
+        let mut config = ptr::null_mut();
+        // SAFETY: `config` is a valid out pointer.
+        if unsafe { ffi::duckdb_create_config(&mut config) } != ffi::DuckDBSuccess {
+            return Err("failed to create the database settings".to_string());
+        }
+        Ok(Self(config))
+    }
+
+    fn set(&self, name: &str, value: &str) -> Result<(), String> {
+        let (c_name, c_value) = CString::new(name)
+            .and_then(|name| Ok((name, CString::new(value)?)))
+            .map_err(|e| e.to_string())?;
+        // SAFETY: `self.0` is a live config and both strings are C strings, which
+        // DuckDB copies.
+        let state = unsafe { ffi::duckdb_set_config(self.0, c_name.as_ptr(), c_value.as_ptr()) };
+        match state == ffi::DuckDBSuccess {
+            true => Ok(()),
+            false => Err(format!("invalid database setting {name}={value}")),
+        }
+    }
+}
+
+impl Drop for Config {
+    fn drop(&mut self) {
+        // SAFETY: the config was created by `Config::new` and is destroyed once.
+        unsafe { ffi::duckdb_destroy_config(&mut self.0) };
+    }
+}
+
+/// A duckdb-rs connection and its raw connection to the same database. The raw
+/// connection only exists as part of it: it is only lent by [`PooledConnection::raw`],
+/// and isn't [`Send`] on its own.
+pub(crate) struct PooledConnection {
+    conn: Connection,
+    raw: RawConnection,
+}
+
+// SAFETY: both connections move together, and a DuckDB connection may be used from
+// any thread as long as it is used by one thread at a time, which `&mut` and the
+// `!Sync` duckdb-rs connection ensure.
+unsafe impl Send for PooledConnection {}
+
+impl PooledConnection {
+    /// The raw connection inspecting the statements before they are prepared.
+    pub(crate) fn raw(&self) -> &RawConnection {
+        &self.raw
+    }
+
+    /// Run `sql` on both connections, so they share the same settings.
+    pub(crate) fn execute_on_both(&self, sql: &str) -> Result<(), String> {
+        self.conn.execute_batch(sql).map_err(|e| e.to_string())?;
+        self.raw.execute(sql)
+    }
+}
+
+impl Deref for PooledConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        &self.conn
+    }
+}
+
+/// A connection through the C API, splitting queries into their statements without
+/// running any of them, as preparing with duckdb-rs runs every statement but the last,
+/// and reading the result columns and parameter types of prepared statements.
+pub(crate) struct RawConnection {
+    raw: ffi::duckdb_connection,
+}
+
+impl RawConnection {
+    /// Run `sql`, discarding its result.
+    fn execute(&self, sql: &str) -> Result<(), String> {
+        let sql = CString::new(sql).map_err(|e| e.to_string())?;
+        // SAFETY: an all-zero `duckdb_result` is the empty result DuckDB fills in.
+        let mut result: ffi::duckdb_result = unsafe { std::mem::zeroed() };
+        // SAFETY: the connection is open, `sql` a C string and `result` a valid out
+        // pointer.
+        let state = unsafe { ffi::duckdb_query(self.raw, sql.as_ptr(), &mut result) };
+        let outcome = match state == ffi::DuckDBSuccess {
+            true => Ok(()),
+            false => Err(
+                // SAFETY: the error of the result is null or a string owned by it.
+                unsafe { borrowed_string(ffi::duckdb_result_error(&mut result)) }
+                    .unwrap_or_else(|| "failed to run the statement".to_string()),
+            ),
+        };
+        // SAFETY: the result was filled in by `duckdb_query`, even on failure, and is
+        // destroyed once.
+        unsafe { ffi::duckdb_destroy_result(&mut result) };
+        outcome
+    }
+
+    /// How many statements `sql` holds, parsing it without running any.
+    pub(crate) fn statement_count(&self, sql: &str) -> Result<usize, String> {
+        let sql = CString::new(sql).map_err(|e| e.to_string())?;
+        let mut extracted = ptr::null_mut();
+        // SAFETY: the connection is open, `sql` a C string and `extracted` a valid out
+        // pointer.
+        let count =
+            unsafe { ffi::duckdb_extract_statements(self.raw, sql.as_ptr(), &mut extracted) };
+        // SAFETY: `extracted` was set by `duckdb_extract_statements`, and its error is
+        // null or a string owned by it.
+        let outcome =
+            match unsafe { borrowed_string(ffi::duckdb_extract_statements_error(extracted)) } {
+                Some(error) => Err(error),
+                None => Ok(count as usize),
+            };
+        // SAFETY: `extracted` was set by `duckdb_extract_statements` and is destroyed
+        // once.
+        unsafe { ffi::duckdb_destroy_extracted(&mut extracted) };
+        outcome
+    }
+
+    /// Prepare `sql`, a single statement, and describe its result columns and its
+    /// parameters, as typed by DuckDB when it binds the statement. Nothing runs.
+    pub(crate) fn describe(&self, sql: &str) -> Result<QueryDescription, String> {
+        let sql = CString::new(sql).map_err(|e| e.to_string())?;
+        let mut prepared = Prepared(ptr::null_mut());
+        // SAFETY: the connection is open, `sql` a C string and `prepared.0` a valid out
+        // pointer; the statement is destroyed when `prepared` is dropped.
+        if unsafe { ffi::duckdb_prepare(self.raw, sql.as_ptr(), &mut prepared.0) }
+            != ffi::DuckDBSuccess
+        {
+            return Err(
+                // SAFETY: the error of the statement is null or a string owned by it.
+                unsafe { borrowed_string(ffi::duckdb_prepare_error(prepared.0)) }
+                    .unwrap_or_else(|| "failed to prepare the statement".to_string()),
+            );
+        }
+        // SAFETY: the statement is prepared.
+        let column_count = unsafe { ffi::duckdb_prepared_statement_column_count(prepared.0) };
+        let mut names = Vec::new();
+        let mut types = Vec::new();
+        for idx in 0..column_count {
+            // SAFETY: `idx` is a column of the prepared statement, and its name is null
+            // or a string allocated for the caller.
+            let name = unsafe {
+                take_string(ffi::duckdb_prepared_statement_column_name(prepared.0, idx).cast_mut())
+            };
+            // SAFETY: `idx` is a column of the prepared statement; the type, allocated
+            // for the caller, is destroyed when dropped.
+            let column_type = LogicalType(unsafe {
+                ffi::duckdb_prepared_statement_column_logical_type(prepared.0, idx)
+            });
+            if column_type.0.is_null() {
+                return Err(format!("unknown type of column {}", idx + 1));
+            }
+            names.push(name.unwrap_or_default());
+            types.push(column_type);
+        }
+        let schema = self.arrow_schema(&names, &types)?;
+        // SAFETY: the statement is prepared.
+        let parameter_count = unsafe { ffi::duckdb_nparams(prepared.0) };
+        let parameters = (1..=parameter_count)
+            .map(|idx| {
+                // SAFETY: `idx` is a parameter of the prepared statement, and its name is
+                // null or a string allocated for the caller.
+                let name =
+                    unsafe { take_string(ffi::duckdb_parameter_name(prepared.0, idx).cast_mut()) }
+                        .unwrap_or_else(|| idx.to_string());
+                // SAFETY: `idx` is a parameter of the prepared statement; the type,
+                // allocated for the caller, is destroyed when dropped.
+                let parameter_type =
+                    LogicalType(unsafe { ffi::duckdb_param_logical_type(prepared.0, idx) });
+                // unconstrained parameters have no type, or one without an Arrow type
+                let data_type = (!parameter_type.0.is_null())
+                    .then(|| {
+                        self.arrow_schema(std::slice::from_ref(&name), &[parameter_type])
+                            .ok()
+                    })
+                    .flatten()
+                    .map(|schema| schema.field(0).data_type().clone());
+                QueryParameter { name, data_type }
+            })
+            .collect();
+        Ok(QueryDescription {
+            schema: Arc::new(schema),
+            parameters,
+        })
+    }
+
+    /// The Arrow schema of columns of the given DuckDB types, as query results are
+    /// converted on this connection.
+    fn arrow_schema(&self, names: &[String], types: &[LogicalType]) -> Result<Schema, String> {
+        let names = names
+            .iter()
+            .map(|name| CString::new(name.as_str()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| e.to_string())?;
+        let mut name_ptrs: Vec<*const c_char> = names.iter().map(|name| name.as_ptr()).collect();
+        let mut type_ptrs: Vec<ffi::duckdb_logical_type> =
+            types.iter().map(|logical_type| logical_type.0).collect();
+        let mut options = ptr::null_mut();
+        // SAFETY: the connection is open and `options` a valid out pointer.
+        unsafe { ffi::duckdb_connection_get_arrow_options(self.raw, &mut options) };
+        let mut schema = FFI_ArrowSchema::empty();
+        // SAFETY: `type_ptrs` and `name_ptrs` hold `types.len()` live types and C
+        // strings, and `schema` is an empty Arrow C schema for DuckDB to fill in.
+        let error = unsafe {
+            ffi::duckdb_to_arrow_schema(
+                options,
+                type_ptrs.as_mut_ptr(),
+                name_ptrs.as_mut_ptr(),
+                types.len() as ffi::idx_t,
+                (&mut schema as *mut FFI_ArrowSchema).cast(),
+            )
+        };
+        // SAFETY: `options` was allocated by `duckdb_connection_get_arrow_options` and
+        // is destroyed once.
+        unsafe { ffi::duckdb_destroy_arrow_options(&mut options) };
+        // SAFETY: `error` is null or error data allocated for the caller.
+        if let Some(error) = unsafe { take_error(error) } {
+            return Err(error);
+        }
+        Schema::try_from(&schema).map_err(|e| e.to_string())
+    }
+}
+
+impl Drop for RawConnection {
+    fn drop(&mut self) {
+        // SAFETY: the connection was opened by `connect_pooled` and is closed once.
+        unsafe { ffi::duckdb_disconnect(&mut self.raw) };
+    }
+}
+
+/// A prepared statement, destroyed when dropped.
+struct Prepared(ffi::duckdb_prepared_statement);
+
+impl Drop for Prepared {
+    fn drop(&mut self) {
+        // SAFETY: the statement is null or was allocated by `duckdb_prepare`, even when
+        // it failed, and is destroyed once.
+        unsafe { ffi::duckdb_destroy_prepare(&mut self.0) };
+    }
+}
+
+/// A DuckDB type, destroyed when dropped.
+struct LogicalType(ffi::duckdb_logical_type);
+
+impl Drop for LogicalType {
+    fn drop(&mut self) {
+        // SAFETY: the type is null or was allocated for the caller, and is destroyed
+        // once.
+        unsafe { ffi::duckdb_destroy_logical_type(&mut self.0) };
+    }
+}
+
+/// Copy a string owned by DuckDB, still freed by DuckDB.
+///
+/// # Safety
+///
+/// `ptr` is null or a C string alive during the call.
+unsafe fn borrowed_string(ptr: *const c_char) -> Option<String> {
+    (!ptr.is_null()).then(|| {
+        // SAFETY: `ptr` is a live C string, as required from the caller.
+        unsafe { CStr::from_ptr(ptr) }
+            .to_string_lossy()
+            .into_owned()
+    })
+}
+
+/// The message of an error DuckDB reported, freeing the error.
+///
+/// # Safety
+///
+/// `error` is null or error data allocated by DuckDB for the caller, not used after.
+unsafe fn take_error(mut error: ffi::duckdb_error_data) -> Option<String> {
+    if error.is_null() {
+        return None;
+    }
+    // SAFETY: `error` is live error data, as required from the caller.
+    let message = match unsafe { ffi::duckdb_error_data_has_error(error) } {
+        true => Some(
+            // SAFETY: the message is null or a string owned by the error data.
+            unsafe { borrowed_string(ffi::duckdb_error_data_message(error)) }
+                .unwrap_or_else(|| "unknown DuckDB error".to_string()),
+        ),
+        false => None,
+    };
+    // SAFETY: the caller hands the error data over, so it is destroyed once.
+    unsafe { ffi::duckdb_destroy_error_data(&mut error) };
+    message
+}
+
+/// Copy then free a string DuckDB allocated for the caller.
+///
+/// # Safety
+///
+/// `ptr` is null or a C string allocated by DuckDB for the caller, not used after.
+unsafe fn take_string(ptr: *mut c_char) -> Option<String> {
+    // SAFETY: `ptr` is null or a live C string, as required from the caller.
+    let string = unsafe { borrowed_string(ptr) };
+    if !ptr.is_null() {
+        // SAFETY: the caller hands the string over, so it is freed once.
+        unsafe { ffi::duckdb_free(ptr.cast()) };
+    }
+    string
+}
@@ -1,96 +1,796 @@
 use crate::cli::options::UQ_ATTACHED_DB_NAME;
-use crate::core::engine::{ExecutableQuery, RecordBatchConsumer, UQueryEngine};
-use duckdb::Connection;
-use std::collections::VecDeque;
+use crate::core::database::{Database, PooledConnection};
+use crate::core::engine::{
+    DescribeError, EngineStats, ExecutableQuery, MemoryUsage, PrepareError, QueryDescription,
+    QueryError, QueryInterrupt, QueryScope, RecordBatchConsumer, SqlPosition, UQueryEngine,
+};
+use crate::core::masking::{MaskingPolicy, can_mask};
+use crate::core::params::{QueryParam, QueryParams, TypedParam};
+use crate::core::row_policy::{OPAQUE_FUNCTIONS, RowPolicy};
+use crate::core::statement::{
+    DEFAULT_ALLOWED_STATEMENTS, TableName, TableRead, called_functions, is_allowed,
+    referenced_tables, replace_table_refs, statement_kind, wrap_table_ref,
+};
+use arrow::compute::kernels::cast_utils::Parser;
+use arrow::datatypes::{Date32Type, TimestampMicrosecondType};
+use duckdb::types::{TimeUnit, Value};
+use duckdb::{Connection, InterruptHandle, Statement, params_from_iter};
+use serde_json::Value as JsonValue;
+use std::cell::RefCell;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
 use tokio::time::Instant;
-use tracing::debug;
+use tracing::{debug, info_span};
+
+/// Memory used by DuckDB by component, in memory and spilled to temporary storage.
+const MEMORY_SQL: &str =
+    "SELECT tag, memory_usage_bytes, temporary_storage_bytes FROM duckdb_memory()";
+
+/// How many requests may wait for a connection when all are in use, and for how long.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PoolQueue {
+    /// Requests waiting at most, the next ones being refused. Unbounded when `None`.
+    pub max_waiting: Option<usize>,
+    /// Longest a request waits before being refused. Forever when `None`.
+    pub timeout: Option<Duration>,
+}
+
+struct PoolState {
+    idle: VecDeque<PooledConnection>,
+    waiting: usize,
+}
 
 struct ConnectionPool {
-    connections: Mutex<VecDeque<Connection>>,
+    state: Mutex<PoolState>,
     condvar: Condvar,
+    size: usize,
+    queue: PoolQueue,
+    /// Open as long as the pool, after its connections are closed.
+    _database: Database,
 }
 
 impl ConnectionPool {
-    fn new(root: &Connection, size: usize, attached: bool) -> Result<Self, String> {
+    fn new(database: Database, size: usize, attached: bool) -> Result<Self, String> {
         let mut conns = VecDeque::with_capacity(size);
         for _ in 0..size {
-            let conn = root.try_clone().map_err(|e| e.to_string())?;
+            let conn = database.connect_pooled()?;
             if attached {
-                conn.execute(format!("USE {UQ_ATTACHED_DB_NAME};").as_str(), [])
-                    .map_err(|e| e.to_string())?;
+                conn.execute_on_both(&format!("USE {UQ_ATTACHED_DB_NAME};"))?;
             }
             conns.push_back(conn);
         }
         Ok(Self {
-            connections: Mutex::new(conns),
+            state: Mutex::new(PoolState {
+                idle: conns,
+                waiting: 0,
+            }),
             condvar: Condvar::new(),
+            size,
+            queue: PoolQueue::default(),
+            _database: database,
         })
     }
 
-    fn acquire(&self) -> Connection {
-        let mut guard = self.connections.lock().unwrap();
-        loop {
-            if let Some(conn) = guard.pop_front() {
-                return conn;
-            }
-            guard = self.condvar.wait(guard).unwrap();
+    /// Take an idle connection, waiting for one to be released when they are all in
+    /// use. Fails when too many requests are already waiting, or after the timeout.
+    fn acquire(&self) -> Result<PooledConnection, String> {
+        let _span = info_span!("pool.acquire", pool.size = self.size).entered();
+        let start = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        if let Some(conn) = state.idle.pop_front() {
+            metrics::histogram!("uquery_pool_wait_seconds").record(0.0);
+            return Ok(conn);
         }
+        if let Some(max) = self.queue.max_waiting
+            && state.waiting >= max
+        {
+            metrics::counter!("uquery_pool_rejected_total", "reason" => "queue_full").increment(1);
+            return Err(format!(
+                "all {} connections are busy and {max} queries are already waiting",
+                self.size
+            ));
+        }
+        state.waiting += 1;
+        metrics::gauge!("uquery_pool_waiting").set(state.waiting as f64);
+        let acquired = loop {
+            if let Some(conn) = state.idle.pop_front() {
+                break Ok(conn);
+            }
+            match self.queue.timeout {
+                None => state = self.condvar.wait(state).unwrap(),
+                Some(timeout) => match timeout.checked_sub(start.elapsed()) {
+                    Some(remaining) => {
+                        state = self.condvar.wait_timeout(state, remaining).unwrap().0;
+                    }
+                    None => break Err(timeout),
+                },
+            }
+        };
+        state.waiting -= 1;
+        metrics::gauge!("uquery_pool_waiting").set(state.waiting as f64);
+        metrics::histogram!("uquery_pool_wait_seconds").record(start.elapsed().as_secs_f64());
+        acquired.map_err(|timeout| {
+            metrics::counter!("uquery_pool_rejected_total", "reason" => "timeout").increment(1);
+            format!("no connection became available within {timeout:?}")
+        })
     }
 
-    fn release(&self, conn: Connection) {
-        self.connections.lock().unwrap().push_back(conn);
+    fn release(&self, conn: PooledConnection) {
+        self.state.lock().unwrap().idle.push_back(conn);
         self.condvar.notify_one();
     }
 }
 
 pub struct DuckDbEngine {
     pool: Arc<ConnectionPool>,
+    policy: StatementPolicy,
+    /// Connection outside the pool reading the stats, so they are available when the
+    /// pool is busy.
+    monitor: Mutex<Connection>,
 }
 
 impl DuckDbEngine {
-    pub fn new(connection: Connection, attached: bool, pool_size: usize) -> Result<Self, String> {
+    pub fn new(database: Database, attached: bool, pool_size: usize) -> Result<Self, String> {
         Ok(Self {
-            pool: Arc::new(ConnectionPool::new(&connection, pool_size, attached)?),
+            monitor: Mutex::new(database.connect()?),
+            pool: Arc::new(ConnectionPool::new(database, pool_size, attached)?),
+            policy: StatementPolicy {
+                allowed_statements: Some(
+                    DEFAULT_ALLOWED_STATEMENTS
+                        .iter()
+                        .map(|kind| kind.to_string())
+                        .collect(),
+                ),
+                denied_functions: Arc::new([]),
+                row_policies: Arc::new([]),
+                masking_policies: Arc::new([]),
+            },
+        })
+    }
+
+    /// Only run the statements of the given kinds, see [`statement_kind`]: `*` allows
+    /// every statement.
+    pub fn with_allowed_statements(mut self, kinds: Vec<String>) -> Self {
+        self.policy.allowed_statements = match kinds.iter().any(|kind| kind == "*") {
+            true => None,
+            false => Some(kinds.into()),
+        };
+        self
+    }
+
+    /// Refuse the queries calling any of the given functions.
+    pub fn with_denied_functions(mut self, functions: Vec<String>) -> Self {
+        self.policy.denied_functions = functions
+            .iter()
+            .map(|function| function.trim().to_lowercase())
+            .filter(|function| !function.is_empty())
+            .collect();
+        self
+    }
+
+    /// Bound the queue of the requests waiting for a connection.
+    pub fn with_pool_queue(mut self, queue: PoolQueue) -> Self {
+        Arc::get_mut(&mut self.pool)
+            .expect("the pool is not shared before the engine is built")
+            .queue = queue;
+        self
+    }
+
+    /// Filter the rows of the tables and files matching the given policies, refusing
+    /// the policies whose filter is not a valid predicate.
+    pub fn with_row_policies(mut self, policies: Vec<RowPolicy>) -> Result<Self, String> {
+        let conn = self.pool.acquire()?;
+        let invalid = policies.iter().find_map(|policy| {
+            let claims = policy
+                .claims()
+                .into_iter()
+                .map(|name| (name, JsonValue::Null))
+                .collect();
+            let filter = policy.filter(&claims).ok()?;
+            subquery_wrapper(
+                &conn,
+                &format!("SELECT * FROM {FILTERED_TABLE} WHERE {filter}"),
+            )
+            .err()
+            .map(|_| format!("[{}]: invalid filter {filter}", policy.target.pattern()))
+        });
+        self.pool.release(conn);
+        match invalid {
+            Some(error) => Err(error),
+            None => {
+                self.policy.row_policies = policies.into();
+                Ok(self)
+            }
+        }
+    }
+
+    /// Mask the columns of the tables and files matching the given policies.
+    pub fn with_masking_policies(mut self, policies: Vec<MaskingPolicy>) -> Self {
+        self.policy.masking_policies = policies.into();
+        self
+    }
+}
+
+/// What statements may do, checked on the pooled connection before they run.
+#[derive(Clone)]
+struct StatementPolicy {
+    /// Kinds of the statements allowed to run, `None` allowing every statement.
+    allowed_statements: Option<Arc<[String]>>,
+    /// Lowercased names of the functions that queries may not call.
+    denied_functions: Arc<[String]>,
+    /// Filters applied to the rows read by queries.
+    row_policies: Arc<[RowPolicy]>,
+    /// Masks applied to the columns read by queries.
+    masking_policies: Arc<[MaskingPolicy]>,
+}
+
+impl StatementPolicy {
+    /// Refuse `sql` unless it is a single statement of an allowed kind, it calls no
+    /// denied function and only reads the catalogs of `scope`, and return the statement
+    /// to run in its place when row or masking policies protect what it reads. The
+    /// statement is classified by its leading keyword, and queries are checked by
+    /// DuckDB's parser: the functions and tables of other statements are not inspected,
+    /// so they are refused when the catalogs are restricted or data is protected. Runs
+    /// before `sql` is prepared, since preparing runs every statement but the last.
+    fn check(
+        &self,
+        conn: &PooledConnection,
+        sql: &str,
+        scope: &QueryScope,
+    ) -> Result<Option<String>, QueryError> {
+        if conn
+            .raw()
+            .statement_count(sql)
+            .map_err(QueryError::Execution)?
+            > 1
+        {
+            return Err(QueryError::Forbidden(
+                "only one statement may run per query".to_string(),
+            ));
+        }
+        if self.allowed_statements.is_none()
+            && self.denied_functions.is_empty()
+            && scope.catalogs.is_none()
+            && self.row_policies.is_empty()
+            && self.masking_policies.is_empty()
+        {
+            return Ok(None);
+        }
+        // the parsed statement, with its offset in `sql` past an `EXPLAIN`
+        let parsed = RefCell::new(None);
+        let kind = statement_kind(sql, &|statement| match serialize_sql(conn, statement) {
+            Some(serialized) => {
+                parsed.replace(Some((sql.len() - statement.len(), serialized)));
+                true
+            }
+            None => false,
+        });
+        if let Some(allowed) = &self.allowed_statements
+            && !is_allowed(&kind, allowed)
+        {
+            return Err(QueryError::Forbidden(format!(
+                "{kind} statements are not allowed"
+            )));
+        }
+        let parsed = parsed.into_inner();
+        let denied = parsed.as_ref().and_then(|(_, statement)| {
+            called_functions(statement)
+                .into_iter()
+                .find(|function| self.denied_functions.contains(function))
+        });
+        if let Some(function) = denied {
+            return Err(QueryError::Forbidden(format!(
+                "function {function} is not allowed"
+            )));
+        }
+        if !self.denied_functions.is_empty()
+            && let Some(function) = parsed
+                .as_ref()
+                .and_then(|(_, statement)| opaque_function(statement))
+        {
+            return Err(QueryError::Forbidden(format!(
+                "function {function} can't be checked against the denied functions"
+            )));
+        }
+        if let Some(catalogs) = &scope.catalogs {
+            let Some((_, statement)) = &parsed else {
+                return Err(QueryError::Forbidden(format!(
+                    "{kind} statements can't be checked against the allowed catalogs"
+                )));
+            };
+            for table in referenced_tables(statement) {
+                let denied = table_catalogs(conn, &table)?
+                    .into_iter()
+                    .find(|catalog| !catalogs.iter().any(|c| c.eq_ignore_ascii_case(catalog)));
+                if let Some(catalog) = denied {
+                    return Err(QueryError::Forbidden(format!(
+                        "catalog {catalog} is not allowed"
+                    )));
+                }
+            }
+        }
+        if self.row_policies.is_empty() && self.masking_policies.is_empty() {
+            return Ok(None);
+        }
+        let Some((offset, mut statement)) = parsed else {
+            return Err(QueryError::Forbidden(format!(
+                "{kind} statements can't be checked against the row and masking policies"
+            )));
+        };
+        if let Some(function) = opaque_function(&statement) {
+            return Err(QueryError::Forbidden(format!(
+                "function {function} can't be checked against the row and masking policies"
+            )));
+        }
+        let masks: Vec<&MaskingPolicy> = self
+            .masking_policies
+            .iter()
+            .filter(|policy| !policy.exempts(&scope.claims))
+            .collect();
+        if !protect_reads(
+            conn,
+            &mut statement,
+            &self.row_policies,
+            &masks,
+            &scope.claims,
+        )? {
+            return Ok(None);
+        }
+        let protected = deserialize_sql(conn, &statement)?;
+        Ok(Some(format!("{}{protected}", &sql[..offset])))
+    }
+}
+
+/// The first function of a serialized `statement` running SQL given as a string, which
+/// can't be checked.
+fn opaque_function(statement: &JsonValue) -> Option<String> {
+    called_functions(statement)
+        .into_iter()
+        .find(|function| OPAQUE_FUNCTIONS.contains(&function.as_str()))
+}
+
+/// Wrap the tables and files read by a serialized `statement` into subqueries keeping
+/// the rows that pass the filters of the row policies applying to them, then masking
+/// the columns of the masking policies applying to them, returning whether any was.
+fn protect_reads(
+    conn: &Connection,
+    statement: &mut JsonValue,
+    row_policies: &[RowPolicy],
+    masks: &[&MaskingPolicy],
+    claims: &BTreeMap<String, JsonValue>,
+) -> Result<bool, QueryError> {
+    let mut wrappers: HashMap<String, JsonValue> = HashMap::new();
+    let mut wrapper = |query: String| -> Result<JsonValue, QueryError> {
+        Ok(match wrappers.entry(query) {
+            Entry::Occupied(entry) => entry.get().clone(),
+            Entry::Vacant(entry) => {
+                let wrapper = subquery_wrapper(conn, entry.key())?;
+                entry.insert(wrapper).clone()
+            }
         })
+    };
+    let mut protected = false;
+    replace_table_refs::<QueryError, _>(statement, &mut |table_ref| {
+        let Some(read) = TableRead::of(table_ref) else {
+            return Ok(None);
+        };
+        let mut filters = Vec::new();
+        for policy in row_policies {
+            if policy
+                .target
+                .applies_to(&read)
+                .map_err(QueryError::Forbidden)?
+            {
+                let filter = policy.filter(claims).map_err(QueryError::Forbidden)?;
+                filters.push(format!("({filter})"));
+            }
+        }
+        let mut masking = Vec::new();
+        for policy in masks {
+            if policy
+                .target
+                .applies_to(&read)
+                .map_err(QueryError::Forbidden)?
+            {
+                masking.push(*policy);
+            }
+        }
+        let mut protected_ref = JsonValue::Object(table_ref.clone());
+        if !filters.is_empty() {
+            let filtered = format!(
+                "SELECT * FROM {FILTERED_TABLE} WHERE {}",
+                filters.join(" AND ")
+            );
+            protected_ref = wrap_table_ref(table_ref, wrapper(filtered)?);
+        }
+        if !masking.is_empty() {
+            if let TableRead::Files { function, .. } = &read
+                && !can_mask(&read)
+            {
+                return Err(QueryError::Forbidden(format!(
+                    "function {function} can't read masked files"
+                )));
+            }
+            let read_ref = protected_ref
+                .as_object()
+                .expect("table references are objects");
+            let select = wrapper(format!("SELECT * FROM {FILTERED_TABLE}"))?;
+            let replacements: Vec<String> = table_columns(conn, read_ref, select)?
+                .into_iter()
+                .filter_map(|(name, column_type)| {
+                    let policy = masking.iter().find(|policy| policy.masks(&name))?;
+                    Some(policy.replacement(&name, &column_type))
+                })
+                .collect();
+            if !replacements.is_empty() {
+                let masked = format!(
+                    "SELECT * REPLACE ({}) FROM {FILTERED_TABLE}",
+                    replacements.join(", ")
+                );
+                protected_ref = wrap_table_ref(read_ref, wrapper(masked)?);
+            }
+        }
+        if protected_ref.as_object() == Some(table_ref) {
+            return Ok(None);
+        }
+        protected = true;
+        Ok(Some(protected_ref))
+    })?;
+    Ok(protected)
+}
+
+/// Name of the table that [`subquery_wrapper`] reads, replaced by the protected one.
+const FILTERED_TABLE: &str = "__uquery_filtered";
+
+/// The serialized subquery reference of `query`, a `SELECT` statement reading
+/// [`FILTERED_TABLE`] in place of the table it protects.
+fn subquery_wrapper(conn: &Connection, query: &str) -> Result<JsonValue, String> {
+    let invalid = || format!("invalid policy subquery: {query}");
+    let sql = format!("SELECT * FROM ({query})");
+    let mut serialized = serialize_sql(conn, &sql).ok_or_else(invalid)?;
+    let [statement] = serialized["statements"]
+        .as_array_mut()
+        .map(Vec::as_mut_slice)
+        .unwrap_or_default()
+    else {
+        return Err(invalid());
+    };
+    let wrapper = statement["node"]["from_table"].take();
+    let node = &wrapper["subquery"]["node"];
+    // a filter ending the subquery early would leave a different statement
+    if wrapper["type"] != "SUBQUERY"
+        || node["type"] != "SELECT_NODE"
+        || node["from_table"]["table_name"] != FILTERED_TABLE
+        || statement["node"]["where_clause"] != JsonValue::Null
+    {
+        return Err(invalid());
+    }
+    Ok(wrapper)
+}
+
+/// Names and types of the columns of `table_ref`, read through `select`, the wrapper
+/// of `SELECT * FROM` [`FILTERED_TABLE`]: the column aliases of the reference are left
+/// out, as the masks apply below them.
+fn table_columns(
+    conn: &Connection,
+    table_ref: &serde_json::Map<String, JsonValue>,
+    select: JsonValue,
+) -> Result<Vec<(String, String)>, QueryError> {
+    let select = wrap_table_ref(table_ref, select);
+    let statement = serde_json::json!({"error": false, "statements": [select["subquery"]]});
+    let sql = deserialize_sql(conn, &statement)?;
+    let mut stmt = conn
+        .prepare(&format!("DESCRIBE {sql}"))
+        .map_err(|e| e.to_string())?;
+    let columns = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| e.to_string())?;
+    Ok(columns)
+}
+
+/// The SQL of statements serialized by `json_serialize_sql`.
+fn deserialize_sql(conn: &Connection, statement: &JsonValue) -> Result<String, String> {
+    conn.query_row(
+        "SELECT json_deserialize_sql(?)",
+        [statement.to_string()],
+        |row| row.get(0),
+    )
+    .map_err(|e| e.to_string())
+}
+
+/// The catalogs `table` may resolve to: its catalog when qualified, the current one
+/// otherwise, and the attached database named like its schema, as DuckDB resolves
+/// `name.table` to a table of the `name` database when there is no `name` schema.
+fn table_catalogs(conn: &Connection, table: &TableName) -> Result<Vec<String>, QueryError> {
+    if !table.catalog.is_empty() {
+        return Ok(vec![table.catalog.clone()]);
     }
+    let current: String = conn
+        .query_row("SELECT current_database()", [], |row| row.get(0))
+        .map_err(|e| e.to_string())?;
+    let mut catalogs = vec![current];
+    if !table.schema.is_empty() {
+        let database: Option<String> = conn
+            .query_row(
+                "SELECT database_name FROM duckdb_databases() \
+                 WHERE lower(database_name) = lower(?)",
+                [&table.schema],
+                |row| row.get(0),
+            )
+            .ok();
+        catalogs.extend(database);
+    }
+    Ok(catalogs)
+}
+
+/// `SELECT` statements parsed by DuckDB and serialized by `json_serialize_sql`, which
+/// reports an error for any other statement.
+fn serialize_sql(conn: &Connection, sql: &str) -> Option<JsonValue> {
+    let serialized: String = conn
+        .query_row("SELECT json_serialize_sql(?)", [sql], |row| row.get(0))
+        .ok()?;
+    let serialized: JsonValue = serde_json::from_str(&serialized).ok()?;
+    (serialized["error"] == false).then_some(serialized)
 }
 
 impl UQueryEngine for DuckDbEngine {
-    fn prepare(&self, sql: &str) -> Result<Box<dyn ExecutableQuery>, String> {
+    fn prepare(
+        &self,
+        sql: &str,
+        params: QueryParams,
+        scope: QueryScope,
+    ) -> Result<Box<dyn ExecutableQuery>, QueryError> {
+        let conn = self.pool.acquire().map_err(QueryError::Unavailable)?;
+        let interrupt = Arc::new(DuckDbInterrupt {
+            handle: conn.interrupt_handle(),
+            interrupted: AtomicBool::new(false),
+        });
         Ok(Box::new(DuckDbQuery {
-            conn: Some(self.pool.acquire()),
+            conn: Some(conn),
             pool: Arc::clone(&self.pool),
             sql: sql.to_string(),
+            params,
+            interrupt,
+            policy: self.policy.clone(),
+            scope,
         }))
     }
+
+    fn describe(&self, sql: &str, scope: &QueryScope) -> Result<QueryDescription, DescribeError> {
+        let conn = self.pool.acquire().map_err(DescribeError::Unavailable)?;
+        let description = match self.policy.check(&conn, sql, scope) {
+            Ok(filtered) => conn
+                .raw()
+                .describe(filtered.as_deref().unwrap_or(sql))
+                .map_err(|message| prepare_error(sql, &message).into()),
+            Err(QueryError::Forbidden(detail)) => Err(DescribeError::Forbidden(detail)),
+            Err(QueryError::Execution(message) | QueryError::NotFound(message)) => {
+                Err(prepare_error(sql, &message).into())
+            }
+            Err(QueryError::Unavailable(detail)) => Err(DescribeError::Unavailable(detail)),
+        };
+        self.pool.release(conn);
+        description
+    }
+
+    fn stats(&self) -> Result<EngineStats, String> {
+        let idle_connections = self.pool.state.lock().unwrap().idle.len();
+        let conn = self.monitor.lock().unwrap();
+        let mut stmt = conn.prepare(MEMORY_SQL).map_err(|e| e.to_string())?;
+        let memory = stmt
+            .query_map([], |row| {
+                Ok(MemoryUsage {
+                    tag: row.get(0)?,
+                    memory_bytes: u64::try_from(row.get::<_, i64>(1)?).unwrap_or_default(),
+                    temporary_storage_bytes: u64::try_from(row.get::<_, i64>(2)?)
+                        .unwrap_or_default(),
+                })
+            })
+            .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| e.to_string())?;
+        Ok(EngineStats {
+            pool_size: self.pool.size,
+            idle_connections,
+            memory,
+        })
+    }
+}
+
+/// Split DuckDB's error context off `message`: the line of the error, as
+/// `LINE <n>: <excerpt>`, then a `^` under the error.
+fn prepare_error(sql: &str, message: &str) -> PrepareError {
+    match message.split_once("\n\nLINE ") {
+        Some((message, context)) => PrepareError {
+            message: message.to_string(),
+            position: sql_position(sql, context),
+        },
+        None => PrepareError {
+            message: message.to_string(),
+            position: None,
+        },
+    }
+}
+
+fn sql_position(sql: &str, context: &str) -> Option<SqlPosition> {
+    let (excerpt_line, caret_line) = context.split_once('\n')?;
+    let (line, excerpt) = excerpt_line.split_once(": ")?;
+    let indicator = "LINE : ".len() + line.len();
+    let line: usize = line.parse().ok()?;
+    let mut column = caret_line.find('^')?.checked_sub(indicator)?;
+    // long lines are truncated around the error, starting with `...`
+    if let Some(excerpt) = excerpt.strip_prefix("...") {
+        let excerpt = excerpt.strip_suffix("...").unwrap_or(excerpt);
+        let sql_line = sql.lines().nth(line.checked_sub(1)?)?;
+        let start = sql_line.find(excerpt)?;
+        column = column.checked_sub("...".len())? + sql_line[..start].chars().count();
+    }
+    Some(SqlPosition {
+        line,
+        column: column + 1,
+    })
+}
+
+struct DuckDbInterrupt {
+    handle: Arc<InterruptHandle>,
+    interrupted: AtomicBool,
+}
+
+impl QueryInterrupt for DuckDbInterrupt {
+    fn interrupt(&self) {
+        self.interrupted.store(true, Ordering::SeqCst);
+        self.handle.interrupt();
+    }
 }
 
 struct DuckDbQuery {
-    conn: Option<Connection>,
+    conn: Option<PooledConnection>,
     pool: Arc<ConnectionPool>,
     sql: String,
+    params: QueryParams,
+    interrupt: Arc<DuckDbInterrupt>,
+    policy: StatementPolicy,
+    scope: QueryScope,
 }
 
 impl Drop for DuckDbQuery {
     fn drop(&mut self) {
         if let Some(conn) = self.conn.take() {
+            if self.interrupt.interrupted.load(Ordering::SeqCst) {
+                // An interrupt may land after the query completed: run a no-op
+                // statement so it can't abort the next query on this connection.
+                let _ = conn.execute_batch("SELECT 1;");
+            }
             self.pool.release(conn);
         }
     }
 }
 
 impl ExecutableQuery for DuckDbQuery {
-    fn execute(&mut self, consumer: &mut dyn RecordBatchConsumer) -> Result<(), String> {
+    fn execute(&mut self, consumer: &mut dyn RecordBatchConsumer) -> Result<(), QueryError> {
         let conn = self.conn.as_ref().expect("connection already consumed");
         let start = Instant::now();
-        let mut stmt = conn.prepare(&self.sql).map_err(|e| e.to_string())?;
-        let arrow = stmt.query_arrow([]).map_err(|e| e.to_string())?;
+        let prepare = info_span!("duckdb.prepare").entered();
+        let mut stmt = match self.policy.check(conn, &self.sql, &self.scope)? {
+            Some(filtered) => {
+                debug!("row policies: [{}] runs as [{filtered}]", self.sql);
+                conn.prepare(&filtered)
+            }
+            None => conn.prepare(&self.sql),
+        }
+        .map_err(|e| e.to_string())?;
+        let values = bind_values(&stmt, &self.params)?;
+        drop(prepare);
+        let _execute = info_span!("duckdb.execute").entered();
+        let mut arrow = stmt
+            .query_arrow(params_from_iter(values))
+            .map_err(|e| e.to_string())?;
         debug!("run: [{}] in {:?}", self.sql, start.elapsed());
         consumer.on_schema(arrow.get_schema())?;
-        for batch in arrow {
+        let mut remaining = self
+            .scope
+            .max_rows
+            .map_or(usize::MAX, |max| usize::try_from(max).unwrap_or(usize::MAX));
+        // whether the last batch sent was cut, once `max_rows` is reached
+        let mut cut = None;
+        for batch in &mut arrow {
+            if batch.num_rows() >= remaining {
+                cut = Some(batch.num_rows() > remaining);
+                consumer.on_batch(batch.slice(0, remaining))?;
+                break;
+            }
+            remaining -= batch.num_rows();
             consumer.on_batch(batch)?;
         }
-        consumer.finish()
+        // past the first rows left out, the rest of the result is never fetched
+        if cut.is_some_and(|cut| cut || arrow.any(|batch| batch.num_rows() > 0)) {
+            consumer.on_truncated();
+        }
+        Ok(consumer.finish()?)
+    }
+
+    fn interrupt_handle(&self) -> Option<Arc<dyn QueryInterrupt>> {
+        Some(Arc::clone(&self.interrupt) as Arc<dyn QueryInterrupt>)
+    }
+}
+
+/// Resolve `params` into the positional values expected by `stmt`. Named parameters
+/// are matched against the names DuckDB reports for each placeholder. Every
+/// placeholder needs a value, and every value a placeholder.
+fn bind_values(stmt: &Statement, params: &QueryParams) -> Result<Vec<Value>, String> {
+    let expected = stmt.parameter_count();
+    match params {
+        QueryParams::Positional(values) if values.len() != expected => Err(format!(
+            "the statement takes {expected} parameter(s), {} given",
+            values.len()
+        )),
+        QueryParams::Positional(values) => values.iter().map(to_duckdb_value).collect(),
+        QueryParams::Named(values) => {
+            let names = (1..=expected)
+                .map(|idx| stmt.parameter_name(idx).map_err(|e| e.to_string()))
+                .collect::<Result<Vec<_>, _>>()?;
+            let bound = names
+                .iter()
+                .map(|name| {
+                    let value = values
+                        .get(name)
+                        .ok_or_else(|| format!("missing value for parameter ${name}"))?;
+                    to_duckdb_value(value)
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            match values.keys().find(|name| !names.contains(name)) {
+                Some(name) => Err(format!("unknown parameter ${name}")),
+                None => Ok(bound),
+            }
+        }
     }
 }
+
+fn to_duckdb_value(param: &QueryParam) -> Result<Value, String> {
+    Ok(match param {
+        QueryParam::Null => Value::Null,
+        QueryParam::Bool(b) => Value::Boolean(*b),
+        QueryParam::Int(i) => Value::BigInt(*i),
+        QueryParam::Float(f) => Value::Double(*f),
+        QueryParam::Text(s) => Value::Text(s.clone()),
+        // DuckDB cannot bind LIST values through the C API: the list is bound as its
+        // text literal, which DuckDB casts to the LIST type expected by the statement.
+        QueryParam::List(_) => Value::Text(list_literal(param)?),
+        QueryParam::Typed(TypedParam::Date(s)) => {
+            Value::Date32(Date32Type::parse(s).ok_or_else(|| format!("invalid date: {s}"))?)
+        }
+        QueryParam::Typed(TypedParam::Timestamp(s)) => Value::Timestamp(
+            TimeUnit::Microsecond,
+            TimestampMicrosecondType::parse(s).ok_or_else(|| format!("invalid timestamp: {s}"))?,
+        ),
+    })
+}
+
+fn list_literal(param: &QueryParam) -> Result<String, String> {
+    Ok(match param {
+        QueryParam::Null => "NULL".to_string(),
+        QueryParam::Bool(b) => b.to_string(),
+        QueryParam::Int(i) => i.to_string(),
+        QueryParam::Float(f) => f.to_string(),
+        QueryParam::Text(s) => quote(s),
+        QueryParam::List(items) => format!(
+            "[{}]",
+            items
+                .iter()
+                .map(list_literal)
+                .collect::<Result<Vec<_>, _>>()?
+                .join(", ")
+        ),
+        QueryParam::Typed(TypedParam::Date(s) | TypedParam::Timestamp(s)) => quote(s),
+    })
+}
+
+/// A string item of a list literal. DuckDB's VARCHAR to LIST cast reads anything
+/// between quotes as a single item, `,`, `]` and `"` included, and unescapes the
+/// characters following a backslash.
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'"))
+}
@@ -1,19 +1,176 @@
-use arrow::datatypes::SchemaRef;
+use crate::core::params::QueryParams;
+use arrow::datatypes::{DataType, SchemaRef};
 use arrow::record_batch::RecordBatch;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
 
 pub trait RecordBatchConsumer: Send {
     fn on_schema(&mut self, schema: SchemaRef) -> Result<(), String>;
     fn on_batch(&mut self, batch: RecordBatch) -> Result<(), String>;
     fn finish(&mut self) -> Result<(), String>;
+
+    /// Rows past the [`QueryScope::max_rows`] of the caller were left out of the result.
+    /// Called before `finish`.
+    fn on_truncated(&mut self) {}
+}
+
+/// Aborts a running query from another thread.
+pub trait QueryInterrupt: Send + Sync {
+    fn interrupt(&self);
+}
+
+/// Why [`ExecutableQuery::execute`] failed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryError {
+    /// The statement failed, or its result could not be consumed.
+    Execution(String),
+    /// The statement is not allowed to run, and was refused before running.
+    Forbidden(String),
+    /// The engine is saturated and refused the statement, which may be retried later.
+    Unavailable(String),
+    /// What the query looks up does not exist, such as an unknown table.
+    NotFound(String),
+}
+
+impl From<String> for QueryError {
+    fn from(message: String) -> Self {
+        QueryError::Execution(message)
+    }
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryError::Execution(message)
+            | QueryError::Forbidden(message)
+            | QueryError::Unavailable(message)
+            | QueryError::NotFound(message) => f.write_str(message),
+        }
+    }
 }
 
 /// A validated, ready-to-stream query returned by [`UQueryEngine::prepare`].
 pub trait ExecutableQuery: Send {
-    fn execute(&mut self, consumer: &mut dyn RecordBatchConsumer) -> Result<(), String>;
+    fn execute(&mut self, consumer: &mut dyn RecordBatchConsumer) -> Result<(), QueryError>;
+
+    /// Handle that makes a pending or running `execute` fail, if the query can be
+    /// interrupted.
+    fn interrupt_handle(&self) -> Option<Arc<dyn QueryInterrupt>> {
+        None
+    }
+}
+
+/// Result schema and parameters of a statement, see [`UQueryEngine::describe`].
+#[derive(Debug, Clone)]
+pub struct QueryDescription {
+    /// Columns of the result, without any column for statements that return no rows.
+    pub schema: SchemaRef,
+    /// Parameters in binding order.
+    pub parameters: Vec<QueryParameter>,
+}
+
+/// A parameter of a statement, see [`QueryDescription`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryParameter {
+    /// Name of the parameter: `1`, `2`... for positional parameters.
+    pub name: String,
+    /// Type DuckDB infers for the parameter, `None` when the statement does not
+    /// constrain it, as in `? IS NULL`.
+    pub data_type: Option<DataType>,
+}
+
+/// An invalid statement, with the location of the error in the SQL when known.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrepareError {
+    pub message: String,
+    pub position: Option<SqlPosition>,
+}
+
+/// Why [`UQueryEngine::describe`] failed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DescribeError {
+    /// The statement does not parse or bind.
+    Invalid(PrepareError),
+    /// The statement may not run on behalf of the caller, see [`QueryError::Forbidden`].
+    Forbidden(String),
+    /// The engine is saturated, see [`QueryError::Unavailable`].
+    Unavailable(String),
+}
+
+impl From<PrepareError> for DescribeError {
+    fn from(error: PrepareError) -> Self {
+        DescribeError::Invalid(error)
+    }
+}
+
+/// A 1-based line and column in the SQL of a statement.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SqlPosition {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// What a query may read and return, on behalf of the caller: unrestricted by default.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct QueryScope {
+    /// Catalogs the query may read from, `None` allowing every catalog.
+    pub catalogs: Option<Vec<String>>,
+    /// Rows returned at most, the result being truncated past them.
+    pub max_rows: Option<u64>,
+    /// Longest the query may run. Requests enforce it through their timeouts, and
+    /// jobs, which outlive them, through a timeout of their own.
+    pub timeout: Option<Duration>,
+    /// Claims of the caller's token, by name, for the policies applied to the query.
+    pub claims: BTreeMap<String, Value>,
+}
+
+/// Figures about an engine, reported by `GET /metrics`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EngineStats {
+    /// Connections of the pool.
+    pub pool_size: usize,
+    /// Connections of the pool not running any query.
+    pub idle_connections: usize,
+    /// Memory used by the engine, by component.
+    pub memory: Vec<MemoryUsage>,
+}
+
+/// Memory used by a component of the engine, such as its hash tables.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryUsage {
+    pub tag: String,
+    /// Bytes held in memory.
+    pub memory_bytes: u64,
+    /// Bytes spilled to temporary storage.
+    pub temporary_storage_bytes: u64,
 }
 
 pub trait UQueryEngine: Send + Sync {
     /// Validate `sql` and return an executable handle or an error if the query
-    /// is invalid.
-    fn prepare(&self, sql: &str) -> Result<Box<dyn ExecutableQuery>, String>;
+    /// is invalid or can't run now. `params` are bound to the statement when it is
+    /// executed, which refuses or truncates what `scope` does not allow.
+    fn prepare(
+        &self,
+        sql: &str,
+        params: QueryParams,
+        scope: QueryScope,
+    ) -> Result<Box<dyn ExecutableQuery>, QueryError>;
+
+    /// Parse and bind `sql` without running it, returning its result schema and
+    /// parameters. The statement is checked as it would be by [`UQueryEngine::prepare`]
+    /// for `scope`.
+    fn describe(&self, _sql: &str, _scope: &QueryScope) -> Result<QueryDescription, DescribeError> {
+        Err(DescribeError::Invalid(PrepareError {
+            message: "describe is not supported by this engine".to_string(),
+            position: None,
+        }))
+    }
+
+    /// Current figures about the engine, without waiting for a pooled connection.
+    fn stats(&self) -> Result<EngineStats, String> {
+        Ok(EngineStats::default())
+    }
 }
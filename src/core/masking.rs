@@ -0,0 +1,249 @@
+use crate::core::claims::{claim, claim_matches};
+use crate::core::row_policy::PolicyTarget;
+use crate::core::statement::TableRead;
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// Table functions reading the rows of files, whose columns can be masked. The other
+/// functions reading files return their raw content or metadata, and are refused on
+/// masked files.
+const ROW_READERS: &[&str] = &[
+    "read_parquet",
+    "parquet_scan",
+    "read_csv",
+    "read_csv_auto",
+    "read_json",
+    "read_json_auto",
+    "read_ndjson",
+    "read_ndjson_auto",
+    "read_xlsx",
+    "delta_scan",
+    "iceberg_scan",
+];
+
+/// Characters left visible at the end of a partially masked value by default.
+const DEFAULT_VISIBLE: usize = 4;
+
+/// How a masked column is replaced.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mask {
+    /// The hex SHA-256 of the value as text, which still joins and groups like the value.
+    Hash,
+    /// The value as text, every character but the last ones replaced by `*`.
+    Partial,
+    /// `NULL`, of the type of the column.
+    Null,
+}
+
+/// A masking policy as written in the policies file.
+#[derive(Deserialize)]
+struct MaskingRule {
+    #[serde(flatten)]
+    target: PolicyTarget,
+    #[serde(default)]
+    columns: Vec<String>,
+    column_pattern: Option<String>,
+    mask: Mask,
+    visible: Option<usize>,
+    #[serde(default)]
+    exempt: BTreeMap<String, Value>,
+    #[serde(flatten)]
+    unknown: BTreeMap<String, Value>,
+}
+
+/// Columns masked in every read of the tables or files matching a pattern, unless the
+/// caller is exempt.
+#[derive(Debug, Clone)]
+pub struct MaskingPolicy {
+    pub target: PolicyTarget,
+    /// Names of the masked columns, matched case-insensitively.
+    columns: Vec<String>,
+    /// Pattern searched in the names of the columns, masking the ones it matches.
+    column_pattern: Option<Regex>,
+    mask: Mask,
+    /// Characters left visible by a partial mask.
+    visible: usize,
+    /// Claim values exempting a caller, see [`claim_matches`]: nobody when empty.
+    exempt: BTreeMap<String, Value>,
+}
+
+impl MaskingPolicy {
+    /// Policies given as a JSON array.
+    pub fn parse(json: &str) -> Result<Vec<Self>, String> {
+        let rules: Vec<MaskingRule> = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        rules
+            .into_iter()
+            .map(|rule| {
+                rule.target.validate()?;
+                let pattern = rule.target.pattern();
+                if let Some(field) = rule.unknown.keys().next() {
+                    return Err(format!("[{pattern}]: unknown field {field}"));
+                }
+                if rule.columns.is_empty() && rule.column_pattern.is_none() {
+                    return Err(format!(
+                        "[{pattern}]: columns or column_pattern is required"
+                    ));
+                }
+                if rule.visible.is_some() && rule.mask != Mask::Partial {
+                    return Err(format!(
+                        "[{pattern}]: visible only applies to partial masks"
+                    ));
+                }
+                let column_pattern = rule
+                    .column_pattern
+                    .map(|regex| Regex::new(&regex))
+                    .transpose()
+                    .map_err(|e| format!("[{pattern}]: {e}"))?;
+                Ok(Self {
+                    target: rule.target,
+                    columns: rule.columns,
+                    column_pattern,
+                    mask: rule.mask,
+                    visible: rule.visible.unwrap_or(DEFAULT_VISIBLE),
+                    exempt: rule.exempt,
+                })
+            })
+            .collect()
+    }
+
+    /// Policies read from a JSON file.
+    pub fn load(file: &Path) -> Result<Vec<Self>, String> {
+        let json = fs::read_to_string(file).map_err(|e| format!("{}: {e}", file.display()))?;
+        Self::parse(&json).map_err(|e| format!("{}: {e}", file.display()))
+    }
+
+    /// Whether a caller with `claims` sees the columns in clear.
+    pub fn exempts(&self, claims: &BTreeMap<String, Value>) -> bool {
+        !self.exempt.is_empty()
+            && self.exempt.iter().all(|(name, expected)| {
+                claim(claims, name).is_some_and(|value| claim_matches(value, expected))
+            })
+    }
+
+    /// Whether the policy masks the column `name`.
+    pub fn masks(&self, name: &str) -> bool {
+        self.columns
+            .iter()
+            .any(|column| column.eq_ignore_ascii_case(name))
+            || self
+                .column_pattern
+                .as_ref()
+                .is_some_and(|pattern| pattern.is_match(name))
+    }
+
+    /// The `SELECT * REPLACE` item masking the column `name` of type `column_type`.
+    pub fn replacement(&self, name: &str, column_type: &str) -> String {
+        let column = format!("\"{}\"", name.replace('"', "\"\""));
+        let text = format!("CAST({column} AS VARCHAR)");
+        let mask = match self.mask {
+            Mask::Hash => format!("sha256({text})"),
+            Mask::Partial => format!(
+                "CASE WHEN length({text}) > {visible} \
+                 THEN lpad(right({text}, {visible}), length({text}), '*') \
+                 ELSE repeat('*', length({text})) END",
+                visible = self.visible
+            ),
+            Mask::Null => format!("CAST(NULL AS {column_type})"),
+        };
+        format!("{mask} AS {column}")
+    }
+}
+
+/// Whether the columns of what `read` reads can be masked: tables and files read by
+/// their path or by a function returning their rows.
+pub fn can_mask(read: &TableRead) -> bool {
+    match read {
+        TableRead::Table(_) => true,
+        TableRead::Files { function, .. } => ROW_READERS.contains(&function.as_str()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn columns_are_masked_unless_exempt() {
+        let policies = MaskingPolicy::parse(
+            &json!([
+                {"file": "data/users/*.parquet", "columns": ["Email"], "mask": "hash",
+                 "exempt": {"roles": ["pii", "admin"], "tenant": "acme"}},
+                {"table": "users", "column_pattern": "(?i)^(ssn|national_id)$", "mask": "null"},
+            ])
+            .to_string(),
+        )
+        .unwrap();
+        let [email, ids] = &policies[..] else {
+            panic!("two policies expected");
+        };
+        assert!(email.masks("email"));
+        assert!(!email.masks("emails"));
+        assert!(ids.masks("NATIONAL_ID"));
+        assert!(!ids.masks("national_id_type"));
+
+        let claims = |value: Value| {
+            value
+                .as_object()
+                .unwrap()
+                .clone()
+                .into_iter()
+                .collect::<BTreeMap<_, _>>()
+        };
+        assert!(email.exempts(&claims(
+            json!({"roles": ["reader", "pii"], "tenant": "acme"})
+        )));
+        assert!(!email.exempts(&claims(json!({"roles": ["pii"], "tenant": "globex"}))));
+        assert!(!email.exempts(&claims(json!({"roles": "pii"}))));
+        assert!(!ids.exempts(&claims(json!({"roles": "admin"}))));
+    }
+
+    #[test]
+    fn masks_are_sql_expressions() {
+        let policy = |mask: &str, visible: Option<usize>| {
+            let mut rule = json!({"table": "users", "columns": ["x"], "mask": mask});
+            if let Some(visible) = visible {
+                rule["visible"] = json!(visible);
+            }
+            MaskingPolicy::parse(&json!([rule]).to_string())
+                .unwrap()
+                .remove(0)
+        };
+        assert_eq!(
+            policy("hash", None).replacement("e\"mail", "VARCHAR"),
+            "sha256(CAST(\"e\"\"mail\" AS VARCHAR)) AS \"e\"\"mail\""
+        );
+        assert_eq!(
+            policy("partial", Some(2)).replacement("phone", "VARCHAR"),
+            "CASE WHEN length(CAST(\"phone\" AS VARCHAR)) > 2 \
+             THEN lpad(right(CAST(\"phone\" AS VARCHAR), 2), length(CAST(\"phone\" AS VARCHAR)), '*') \
+             ELSE repeat('*', length(CAST(\"phone\" AS VARCHAR))) END AS \"phone\""
+        );
+        assert_eq!(
+            policy("null", None).replacement("born", "DATE"),
+            "CAST(NULL AS DATE) AS \"born\""
+        );
+    }
+
+    #[test]
+    fn invalid_policies_are_refused() {
+        for rule in [
+            json!({"table": "users", "mask": "hash"}),
+            json!({"table": "users", "columns": ["email"], "mask": "redact"}),
+            json!({"table": "users", "columns": ["email"], "mask": "hash", "visible": 2}),
+            json!({"table": "users", "column_pattern": "(", "mask": "null"}),
+            json!({"columns": ["email"], "mask": "null"}),
+            json!({"table": "users", "columns": ["email"], "mask": "null", "role": "pii"}),
+        ] {
+            assert!(
+                MaskingPolicy::parse(&json!([rule]).to_string()).is_err(),
+                "{rule}"
+            );
+        }
+    }
+}
@@ -1,3 +1,9 @@
+pub mod claims;
+pub mod database;
 pub mod duckdb;
 pub mod engine;
 pub mod error;
+pub mod masking;
+pub mod params;
+pub mod row_policy;
+pub mod statement;
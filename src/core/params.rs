@@ -0,0 +1,49 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// A single value bound to a prepared statement parameter.
+///
+/// Plain JSON values map to their natural SQL type. Dates and timestamps have no
+/// JSON representation and are passed as `{"type": "date", "value": "2024-01-31"}`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum QueryParam {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Text(String),
+    List(Vec<QueryParam>),
+    Typed(TypedParam),
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "type", content = "value", rename_all = "lowercase")]
+pub enum TypedParam {
+    Date(String),
+    Timestamp(String),
+}
+
+/// Parameters of a query: a JSON array binds positional parameters (`?`, `$1`),
+/// a JSON object binds named parameters (`$name`).
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum QueryParams {
+    Positional(Vec<QueryParam>),
+    Named(BTreeMap<String, QueryParam>),
+}
+
+impl Default for QueryParams {
+    fn default() -> Self {
+        QueryParams::Positional(Vec::new())
+    }
+}
+
+impl QueryParams {
+    pub fn is_empty(&self) -> bool {
+        match self {
+            QueryParams::Positional(values) => values.is_empty(),
+            QueryParams::Named(values) => values.is_empty(),
+        }
+    }
+}
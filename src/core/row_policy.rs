@@ -0,0 +1,398 @@
+use crate::core::claims::claim;
+use crate::core::statement::{TableName, TableRead};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// Table functions running SQL, or reading a table, given as a string: the functions
+/// they call and the tables they read can't be checked, so they are refused when
+/// functions are denied or row or masking policies are configured.
+pub const OPAQUE_FUNCTIONS: &[&str] = &["query", "query_table", "json_execute_serialized_sql"];
+
+/// The tables or the files a policy applies to.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PolicyTarget {
+    /// Tables the policy applies to, as `table`, `schema.table` or
+    /// `catalog.schema.table`, where `*` matches any characters.
+    pub table: Option<String>,
+    /// Files the policy applies to, as a path or URL where `*` matches any characters,
+    /// `/` included.
+    pub file: Option<String>,
+}
+
+impl PolicyTarget {
+    /// Check that the target is either a table, of at most three parts, or a file.
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        match (&self.table, &self.file) {
+            (Some(table), None) => {
+                if table.split('.').count() > 3 || table.split('.').any(str::is_empty) {
+                    return Err(format!(
+                        "[{table}]: tables are written table, schema.table or catalog.schema.table"
+                    ));
+                }
+                Ok(())
+            }
+            (None, Some(_)) => Ok(()),
+            _ => Err("every policy needs either a table or a file".to_string()),
+        }
+    }
+
+    /// The table or file pattern.
+    pub fn pattern(&self) -> &str {
+        self.table
+            .as_deref()
+            .or(self.file.as_deref())
+            .unwrap_or_default()
+    }
+
+    /// Whether the target covers what `read` reads. A file pattern can't tell which
+    /// files a table function reads when they are not constant, which is an error.
+    pub fn applies_to(&self, read: &TableRead) -> Result<bool, String> {
+        match (read, &self.table, &self.file) {
+            (TableRead::Table(table), Some(pattern), _) => Ok(table_matches(pattern, table)),
+            // an unqualified name may be the path of a file
+            (TableRead::Table(table), _, Some(pattern)) => Ok(table.catalog.is_empty()
+                && table.schema.is_empty()
+                && file_matches(pattern, &table.table)),
+            (TableRead::Files { function, paths }, _, Some(pattern)) => match paths {
+                Some(paths) => Ok(paths.iter().any(|path| file_matches(pattern, path))),
+                None => Err(format!(
+                    "the files read by {function} must be constant strings to be checked against the policies of {pattern}"
+                )),
+            },
+            _ => Ok(false),
+        }
+    }
+}
+
+/// A filter applied to every read of the tables or files matching a pattern.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct RowPolicy {
+    #[serde(flatten)]
+    pub target: PolicyTarget,
+    /// SQL predicate the rows must satisfy, where `:name` stands for the `name` claim of
+    /// the caller.
+    pub filter: String,
+    #[serde(flatten)]
+    unknown: BTreeMap<String, Value>,
+}
+
+impl RowPolicy {
+    /// Policies given as a JSON array.
+    pub fn parse(json: &str) -> Result<Vec<Self>, String> {
+        let policies: Vec<Self> = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        for policy in &policies {
+            policy.target.validate()?;
+            let pattern = policy.target.pattern();
+            if let Some(field) = policy.unknown.keys().next() {
+                return Err(format!("[{pattern}]: unknown field {field}"));
+            }
+            if policy.filter.trim().is_empty() {
+                return Err(format!("[{pattern}]: the filter is empty"));
+            }
+        }
+        Ok(policies)
+    }
+
+    /// Policies read from a JSON file.
+    pub fn load(file: &Path) -> Result<Vec<Self>, String> {
+        let json = fs::read_to_string(file).map_err(|e| format!("{}: {e}", file.display()))?;
+        Self::parse(&json).map_err(|e| format!("{}: {e}", file.display()))
+    }
+
+    /// Names of the claims used by the filter.
+    pub fn claims(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        template(&self.filter, &mut |name| {
+            names.push(name.to_string());
+            Ok(String::new())
+        })
+        .expect("placeholders are never refused");
+        names
+    }
+
+    /// The filter, its placeholders replaced by the SQL literals of `claims`. A missing
+    /// claim, or an object that has no literal, is an error.
+    pub fn filter(&self, claims: &BTreeMap<String, Value>) -> Result<String, String> {
+        template(&self.filter, &mut |name| {
+            let value = claim(claims, name).ok_or_else(|| {
+                format!(
+                    "the {name} claim required by the row policy of {} is missing",
+                    self.target.pattern()
+                )
+            })?;
+            literal(value).ok_or_else(|| {
+                format!(
+                    "the {name} claim can't be used by the row policy of {}",
+                    self.target.pattern()
+                )
+            })
+        })
+    }
+}
+
+/// Replace the `:name` placeholders of `filter` with `value(name)`, leaving alone the
+/// quoted strings and identifiers and the `::` casts.
+fn template(
+    filter: &str,
+    value: &mut dyn FnMut(&str) -> Result<String, String>,
+) -> Result<String, String> {
+    let mut result = String::with_capacity(filter.len());
+    let mut chars = filter.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\'' | '"' => {
+                result.push(c);
+                for (_, next) in chars.by_ref() {
+                    result.push(next);
+                    // a doubled quote is read as two quoted strings
+                    if next == c {
+                        break;
+                    }
+                }
+            }
+            ':' if chars.peek().is_some_and(|(_, next)| *next == ':') => {
+                result.push_str("::");
+                chars.next();
+            }
+            ':' if chars
+                .peek()
+                .is_some_and(|(_, next)| next.is_ascii_alphabetic() || *next == '_') =>
+            {
+                let start = i + 1;
+                let mut end = start;
+                while let Some((j, next)) = chars.peek().copied() {
+                    if !(next.is_ascii_alphanumeric() || next == '_' || next == '.') {
+                        break;
+                    }
+                    end = j + 1;
+                    chars.next();
+                }
+                let name = &filter[start..end];
+                // a trailing dot ends the sentence, not the name
+                let trimmed = name.trim_end_matches('.');
+                result.push_str(&value(trimmed)?);
+                result.push_str(&name[trimmed.len()..]);
+            }
+            c => result.push(c),
+        }
+    }
+    Ok(result)
+}
+
+/// The SQL literal of a JSON value, lists becoming DuckDB lists.
+fn literal(value: &Value) -> Option<String> {
+    Some(match value {
+        Value::Null => "NULL".to_string(),
+        Value::Bool(b) => b.to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::String(s) => format!("'{}'", s.replace('\'', "''")),
+        Value::Array(items) => format!(
+            "[{}]",
+            items
+                .iter()
+                .map(literal)
+                .collect::<Option<Vec<_>>>()?
+                .join(", ")
+        ),
+        Value::Object(_) => return None,
+    })
+}
+
+/// Whether `pattern` matches `table`, case-insensitively. The parts missing from the
+/// table name match any pattern, and a `name.table` table, which DuckDB reads from the
+/// `name` schema or from the `name` database, matches both.
+fn table_matches(pattern: &str, table: &TableName) -> bool {
+    let pattern = pattern.to_lowercase();
+    let (catalog, schema, name) = match pattern.split('.').collect::<Vec<_>>()[..] {
+        [name] => ("*", "*", name),
+        [schema, name] => ("*", schema, name),
+        [catalog, schema, name] => (catalog, schema, name),
+        _ => return false,
+    };
+    let part =
+        |pattern: &str, part: &str| part.is_empty() || glob_matches(pattern, &part.to_lowercase());
+    let in_schema = if table.catalog.is_empty() && !table.schema.is_empty() {
+        part(schema, &table.schema) || part(catalog, &table.schema)
+    } else {
+        part(catalog, &table.catalog) && part(schema, &table.schema)
+    };
+    in_schema && glob_matches(name, &table.table.to_lowercase())
+}
+
+/// Whether `pattern` matches the file at `path`, both made absolute. A path that is
+/// itself a glob matches when it may read a file of the pattern, that is when the
+/// parts of the pattern and of the path before their first wildcard overlap.
+fn file_matches(pattern: &str, path: &str) -> bool {
+    let (pattern, path) = (absolute(pattern), absolute(path));
+    match path.find(['*', '?', '[', '{']) {
+        Some(wildcard) => {
+            let fixed = &pattern[..pattern.find(['*', '?']).unwrap_or(pattern.len())];
+            fixed.starts_with(&path[..wildcard]) || path[..wildcard].starts_with(fixed)
+        }
+        None => glob_matches(&pattern, &path),
+    }
+}
+
+/// `path` made absolute, without its `.` and `..` components, URLs being left as is.
+fn absolute(path: &str) -> String {
+    if path.contains("://") {
+        return path.to_string();
+    }
+    let mut absolute = match path.strip_prefix("~/") {
+        Some(path) => std::env::var("HOME")
+            .map_or_else(|_| PathBuf::from(path), |home| Path::new(&home).join(path)),
+        None => std::env::current_dir().unwrap_or_default().join(path),
+    };
+    let mut normalized = PathBuf::new();
+    for component in absolute.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    absolute = normalized;
+    absolute.to_string_lossy().into_owned()
+}
+
+/// Whether `text` matches `pattern`, where `*` matches any characters and `?` a single
+/// one.
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let (pattern, text): (Vec<char>, Vec<char>) =
+        (pattern.chars().collect(), text.chars().collect());
+    let (mut p, mut t) = (0, 0);
+    // the position after the last star, and the text it matches up to
+    let mut star = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            p += 1;
+            star = Some((p, t));
+        } else if let Some((after, matched)) = star {
+            p = after;
+            t = matched + 1;
+            star = Some((after, t));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn table(catalog: &str, schema: &str, name: &str) -> TableRead {
+        TableRead::Table(TableName {
+            catalog: catalog.to_string(),
+            schema: schema.to_string(),
+            table: name.to_string(),
+        })
+    }
+
+    fn row_policy(json: Value) -> RowPolicy {
+        RowPolicy::parse(&json!([json]).to_string())
+            .unwrap()
+            .remove(0)
+    }
+
+    #[test]
+    fn tables_are_matched_on_their_known_parts() {
+        let policy = row_policy(json!({"table": "lake.main.Orders", "filter": "true"}));
+        for (read, applies) in [
+            (table("", "", "orders"), true),
+            (table("", "main", "ORDERS"), true),
+            // read from the lake database
+            (table("", "lake", "orders"), true),
+            (table("lake", "main", "orders"), true),
+            (table("memory", "main", "orders"), false),
+            (table("", "other", "orders"), false),
+            (table("", "", "customers"), false),
+        ] {
+            assert_eq!(policy.target.applies_to(&read), Ok(applies), "{read:?}");
+        }
+        let policy = row_policy(json!({"table": "*.orders_*", "filter": "true"}));
+        assert_eq!(
+            policy.target.applies_to(&table("", "x", "orders_2026")),
+            Ok(true)
+        );
+        assert_eq!(
+            policy.target.applies_to(&table("", "x", "orders")),
+            Ok(false)
+        );
+    }
+
+    #[test]
+    fn files_are_matched_on_their_absolute_path() {
+        let policy = row_policy(json!({"file": "data/tenants/*.parquet", "filter": "true"}));
+        let files = |paths: Option<&[&str]>| TableRead::Files {
+            function: "read_parquet".to_string(),
+            paths: paths.map(|paths| paths.iter().map(|path| path.to_string()).collect()),
+        };
+        let cwd = std::env::current_dir().unwrap();
+        let absolute = format!("{}/data/tenants/acme.parquet", cwd.display());
+        for (read, applies) in [
+            (table("", "", "data/tenants/acme.parquet"), true),
+            (table("", "", "./data/other/../tenants/acme.parquet"), true),
+            (table("", "", "data/public/zones.parquet"), false),
+            (table("", "data", "tenants"), false),
+            (files(Some(&[&absolute])), true),
+            (
+                files(Some(&["data/public/zones.parquet", "data/*/*.parquet"])),
+                true,
+            ),
+            (files(Some(&["data/**"])), true),
+            (files(Some(&["data/public/*.parquet"])), false),
+        ] {
+            assert_eq!(policy.target.applies_to(&read), Ok(applies), "{read:?}");
+        }
+        assert!(policy.target.applies_to(&files(None)).is_err());
+        let url = row_policy(json!({"file": "s3://bucket/tenants/*", "filter": "true"}));
+        assert_eq!(
+            url.target
+                .applies_to(&table("", "", "s3://bucket/tenants/acme/2026.parquet")),
+            Ok(true)
+        );
+    }
+
+    #[test]
+    fn filters_are_templated_with_claims() {
+        let policy = row_policy(json!({"table": "orders",
+            "filter": "tenant_id = :tenant AND region IN (SELECT unnest(:realm.regions::VARCHAR[])) AND note <> ':tenant'. -- :sub."}));
+        assert_eq!(policy.claims(), ["tenant", "realm.regions", "sub"]);
+        let claims = BTreeMap::from([
+            ("tenant".to_string(), json!("o'hara")),
+            ("realm".to_string(), json!({"regions": ["eu", 1]})),
+            ("sub".to_string(), json!(null)),
+        ]);
+        assert_eq!(
+            policy.filter(&claims).unwrap(),
+            "tenant_id = 'o''hara' AND region IN (SELECT unnest(['eu', 1]::VARCHAR[])) AND note <> ':tenant'. -- NULL."
+        );
+        let claims = BTreeMap::from([("tenant".to_string(), json!({"id": 1}))]);
+        assert!(policy.filter(&claims).is_err());
+        assert!(policy.filter(&BTreeMap::new()).is_err());
+    }
+
+    #[test]
+    fn invalid_policies_are_refused() {
+        for json in [
+            json!([{"filter": "true"}]),
+            json!([{"table": "t", "file": "f", "filter": "true"}]),
+            json!([{"table": "a.b.c.d", "filter": "true"}]),
+            json!([{"table": "t", "filter": " "}]),
+            json!([{"table": "t", "filter": "true", "tenant": "acme"}]),
+        ] {
+            assert!(RowPolicy::parse(&json.to_string()).is_err(), "{json}");
+        }
+    }
+}
@@ -0,0 +1,637 @@
+use serde_json::{Map, Value};
+use std::collections::BTreeSet;
+
+/// Statement kinds allowed by default: read-only queries.
+pub const DEFAULT_ALLOWED_STATEMENTS: &[&str] = &["SELECT", "WITH", "DESCRIBE", "SUMMARIZE"];
+
+/// Kind of a statement the parser could not confirm, never allowed unless every kind is.
+pub const UNKNOWN_STATEMENT: &str = "UNKNOWN";
+
+/// Kinds whose leading keyword must be confirmed by DuckDB's parser as a `SELECT`
+/// statement: `WITH` also starts `INSERT`, `UPDATE` and `DELETE` statements.
+const QUERY_KINDS: &[&str] = &["SELECT", "WITH", "DESCRIBE", "SUMMARIZE", "SHOW"];
+
+/// Statements that can follow the common table expressions of a `WITH` clause.
+const WITH_STATEMENTS: &[&str] = &["SELECT", "INSERT", "UPDATE", "DELETE", "MERGE"];
+
+/// Kind of `sql`, named after its leading keyword: `SELECT`, `INSERT`, `ATTACH`,
+/// `COPY`... Queries that don't start with `SELECT` (`FROM`, `VALUES`, `TABLE`,
+/// `PIVOT`...) are `SELECT` statements, `DESC` is `DESCRIBE`, and `EXPLAIN` takes the
+/// kind of the statement it explains, which `EXPLAIN ANALYZE` runs.
+///
+/// `is_select` asks DuckDB's parser whether a statement is a `SELECT` statement: query
+/// kinds are only reported when the parser agrees, [`UNKNOWN_STATEMENT`] otherwise.
+pub fn statement_kind(sql: &str, is_select: &dyn Fn(&str) -> bool) -> String {
+    let words = top_level_words(sql);
+    let Some((_, keyword)) = words.first() else {
+        return UNKNOWN_STATEMENT.to_string();
+    };
+    let kind = match keyword.as_str() {
+        "EXPLAIN" => {
+            return words
+                .iter()
+                .skip(1)
+                .find(|(_, word)| word != "ANALYZE")
+                .map(|(offset, _)| statement_kind(&sql[*offset..], is_select))
+                .unwrap_or_else(|| UNKNOWN_STATEMENT.to_string());
+        }
+        // a parenthesized query: `(SELECT 1) UNION (SELECT 2)`
+        "(" | "FROM" | "VALUES" | "TABLE" | "PIVOT" | "UNPIVOT" => "SELECT",
+        "DESC" => "DESCRIBE",
+        "FORCE" => words
+            .get(1)
+            .map_or(UNKNOWN_STATEMENT, |(_, word)| word.as_str()),
+        keyword => keyword,
+    };
+    if !QUERY_KINDS.contains(&kind) || is_select(sql) {
+        return kind.to_string();
+    }
+    let statement = (kind == "WITH")
+        .then(|| {
+            words
+                .iter()
+                .map(|(_, word)| word.as_str())
+                .find(|word| WITH_STATEMENTS.contains(word) && *word != "SELECT")
+        })
+        .flatten();
+    statement.unwrap_or(UNKNOWN_STATEMENT).to_string()
+}
+
+/// Whether a statement of `kind` may run: `*` allows every statement.
+pub fn is_allowed(kind: &str, allowed: &[String]) -> bool {
+    allowed
+        .iter()
+        .any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(kind))
+}
+
+/// Lowercased names of the functions called by a statement serialized by DuckDB's
+/// `json_serialize_sql`: scalar, aggregate, window and table functions, and the
+/// functions reading the files read by their path (`FROM 'trips.csv'`).
+pub fn called_functions(statement: &Value) -> BTreeSet<String> {
+    let mut functions = BTreeSet::new();
+    collect_functions(statement, &mut functions);
+    functions
+}
+
+fn collect_functions(value: &Value, functions: &mut BTreeSet<String>) {
+    match value {
+        Value::Object(object) => {
+            if let Some(Value::String(name)) = object.get("function_name") {
+                functions.insert(name.to_lowercase());
+            }
+            if object.get("type").and_then(Value::as_str) == Some("BASE_TABLE") {
+                let table = table_name(object);
+                if table.catalog.is_empty() && table.schema.is_empty() {
+                    let readers = file_readers(&table.table);
+                    functions.extend(readers.iter().map(|reader| reader.to_string()));
+                }
+            }
+            object
+                .values()
+                .for_each(|value| collect_functions(value, functions));
+        }
+        Value::Array(values) => values
+            .iter()
+            .for_each(|value| collect_functions(value, functions)),
+        _ => {}
+    }
+}
+
+/// The functions DuckDB reads the file `path` with when a query reads it by its path,
+/// under every name they can be called by, or none when the path has no extension
+/// DuckDB reads by itself.
+fn file_readers(path: &str) -> &'static [&'static str] {
+    let path = path.to_lowercase();
+    let path = [".gz", ".zst"]
+        .iter()
+        .find_map(|compression| path.strip_suffix(compression))
+        .unwrap_or(&path);
+    match path.rsplit_once('.').map(|(_, extension)| extension) {
+        Some("csv" | "tsv" | "tbl" | "txt") => &["read_csv", "read_csv_auto"],
+        Some("parquet") => &["read_parquet", "parquet_scan"],
+        Some("json" | "jsonl" | "ndjson") => &["read_json", "read_json_auto", "read_ndjson"],
+        Some("xlsx") => &["read_xlsx"],
+        _ => &[],
+    }
+}
+
+/// A table or view read by a query, as written: `catalog` and `schema` are empty when
+/// the name is not qualified with them.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TableName {
+    pub catalog: String,
+    pub schema: String,
+    pub table: String,
+}
+
+/// The tables and views read by a statement serialized by DuckDB's
+/// `json_serialize_sql`, without the references to its common table expressions.
+pub fn referenced_tables(statement: &Value) -> BTreeSet<TableName> {
+    let mut tables = BTreeSet::new();
+    collect_tables(statement, &mut Vec::new(), &mut tables);
+    tables
+}
+
+/// Collect the base table references under `value`, `ctes` holding the names of the
+/// common table expressions in scope. A common table expression sees the ones declared
+/// before it, and itself when it is recursive.
+fn collect_tables(value: &Value, ctes: &mut Vec<String>, tables: &mut BTreeSet<TableName>) {
+    match value {
+        Value::Object(object) => {
+            let in_scope = ctes.len();
+            if let Some(Value::Array(map)) = object.get("cte_map").map(|ctes| &ctes["map"]) {
+                for cte in map {
+                    let name = cte["key"].as_str().unwrap_or_default().to_lowercase();
+                    let recursive = cte["value"]["query"]["node"]["type"] == "RECURSIVE_CTE_NODE";
+                    if recursive {
+                        ctes.push(name.clone());
+                    }
+                    collect_tables(&cte["value"], ctes, tables);
+                    if !recursive {
+                        ctes.push(name);
+                    }
+                }
+            }
+            if object.get("type").and_then(Value::as_str) == Some("BASE_TABLE") {
+                let table = table_name(object);
+                let is_cte = table.catalog.is_empty()
+                    && table.schema.is_empty()
+                    && ctes.contains(&table.table.to_lowercase());
+                if !is_cte {
+                    tables.insert(table);
+                }
+            }
+            object
+                .iter()
+                .filter(|(key, _)| *key != "cte_map")
+                .for_each(|(_, value)| collect_tables(value, ctes, tables));
+            ctes.truncate(in_scope);
+        }
+        Value::Array(values) => values
+            .iter()
+            .for_each(|value| collect_tables(value, ctes, tables)),
+        _ => {}
+    }
+}
+
+/// The name of a base table reference.
+fn table_name(table_ref: &Map<String, Value>) -> TableName {
+    let name = |key: &str| {
+        table_ref
+            .get(key)
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string()
+    };
+    TableName {
+        catalog: name("catalog_name"),
+        schema: name("schema_name"),
+        table: name("table_name"),
+    }
+}
+
+/// Table functions reading the files given as their first argument, besides the
+/// `read_*` functions.
+const FILE_FUNCTIONS: &[&str] = &[
+    "parquet_scan",
+    "parquet_metadata",
+    "parquet_file_metadata",
+    "parquet_kv_metadata",
+    "parquet_schema",
+    "sniff_csv",
+    "delta_scan",
+    "iceberg_scan",
+    "iceberg_metadata",
+    "iceberg_snapshots",
+];
+
+/// What a table reference of a statement serialized by `json_serialize_sql` reads.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TableRead {
+    /// A table or view, or a file read by its path: `FROM 'data.csv'`.
+    Table(TableName),
+    /// The files read by a table function such as `read_parquet`, `None` when they are
+    /// not given as constant strings.
+    Files {
+        function: String,
+        paths: Option<Vec<String>>,
+    },
+}
+
+impl TableRead {
+    /// What `table_ref` reads, `None` for the references reading no table or file by
+    /// themselves: joins, subqueries, other table functions...
+    pub fn of(table_ref: &Map<String, Value>) -> Option<Self> {
+        match table_ref.get("type").and_then(Value::as_str)? {
+            "BASE_TABLE" if table_ref.contains_key("table_name") => {
+                Some(TableRead::Table(table_name(table_ref)))
+            }
+            "TABLE_FUNCTION" => {
+                let function = table_ref.get("function")?;
+                let name = function["function_name"].as_str()?.to_lowercase();
+                if !name.starts_with("read_") && !FILE_FUNCTIONS.contains(&name.as_str()) {
+                    return None;
+                }
+                let paths = function["children"].get(0).and_then(constant_strings);
+                Some(TableRead::Files {
+                    function: name,
+                    paths,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The strings of a constant string or list of strings expression.
+fn constant_strings(expression: &Value) -> Option<Vec<String>> {
+    match expression["class"].as_str()? {
+        "CONSTANT" => value_strings(&expression["value"]),
+        "FUNCTION"
+            if matches!(
+                expression["function_name"].as_str(),
+                Some("list_value" | "list_pack")
+            ) =>
+        {
+            let children = expression["children"].as_array()?;
+            let strings = children.iter().map(constant_strings);
+            strings
+                .collect::<Option<Vec<_>>>()
+                .map(|paths| paths.concat())
+        }
+        _ => None,
+    }
+}
+
+/// The strings of a serialized string or list of strings value.
+fn value_strings(value: &Value) -> Option<Vec<String>> {
+    match &value["value"] {
+        Value::String(string) => Some(vec![string.clone()]),
+        Value::Object(list) => {
+            let children = list.get("children")?.as_array()?;
+            let strings = children.iter().map(value_strings);
+            strings
+                .collect::<Option<Vec<_>>>()
+                .map(|paths| paths.concat())
+        }
+        _ => None,
+    }
+}
+
+/// Replace, bottom up, the base table and table function references under `value` for
+/// which `replace` returns a new reference.
+pub fn replace_table_refs<E, F>(value: &mut Value, replace: &mut F) -> Result<(), E>
+where
+    F: FnMut(&Map<String, Value>) -> Result<Option<Value>, E>,
+{
+    match value {
+        Value::Object(object) => {
+            for child in object.values_mut() {
+                replace_table_refs(child, replace)?;
+            }
+            let is_table_ref = matches!(
+                object.get("type").and_then(Value::as_str),
+                Some("BASE_TABLE" | "TABLE_FUNCTION")
+            ) && (object.contains_key("table_name")
+                || object.contains_key("function"));
+            if is_table_ref && let Some(replacement) = replace(object)? {
+                *value = replacement;
+            }
+        }
+        Value::Array(values) => {
+            for value in values {
+                replace_table_refs(value, replace)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Put `table_ref` in place of the table read by `wrapper`, a serialized
+/// `(SELECT * FROM placeholder WHERE ...)` subquery reference, which takes the alias and
+/// column aliases of `table_ref` so that the query around it is unchanged.
+pub fn wrap_table_ref(table_ref: &Map<String, Value>, mut wrapper: Value) -> Value {
+    let mut inner = table_ref.clone();
+    let alias = match inner.insert("alias".to_string(), Value::from("")) {
+        Some(Value::String(alias)) if !alias.is_empty() => alias,
+        _ => default_alias(table_ref),
+    };
+    let column_aliases = inner.insert("column_name_alias".to_string(), Value::Array(Vec::new()));
+    wrapper["alias"] = Value::from(alias);
+    wrapper["column_name_alias"] = column_aliases.unwrap_or_else(|| Value::Array(Vec::new()));
+    wrapper["subquery"]["node"]["from_table"] = Value::Object(inner);
+    wrapper
+}
+
+/// The name DuckDB gives to an unaliased table reference: the table name, the file name
+/// without its extensions for a file read by its path, and the function name for a
+/// table function.
+fn default_alias(table_ref: &Map<String, Value>) -> String {
+    if let Some(function) = table_ref.get("function") {
+        return function["function_name"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+    }
+    let table = table_ref
+        .get("table_name")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+    if !table.contains(['/', '.']) {
+        return table.to_string();
+    }
+    if table.contains(['*', '?', '[']) {
+        return String::new();
+    }
+    let file = table.rsplit('/').next().unwrap_or(table);
+    file.split('.').next().unwrap_or(file).to_string()
+}
+
+/// The keywords and identifiers of `sql` outside parentheses, uppercased, with their
+/// offset, preceded by `(` when the statement starts with a parenthesis. Comments and
+/// quoted strings or identifiers are skipped.
+fn top_level_words(sql: &str) -> Vec<(usize, String)> {
+    let bytes = sql.as_bytes();
+    let mut words = Vec::new();
+    let mut depth = 0usize;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'-' if bytes.get(i + 1) == Some(&b'-') => {
+                i = sql[i..].find('\n').map_or(bytes.len(), |end| i + end);
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                i = sql[i + 2..]
+                    .find("*/")
+                    .map_or(bytes.len(), |end| i + end + 4);
+            }
+            quote @ (b'\'' | b'"') => {
+                i += 1;
+                while i < bytes.len() {
+                    if bytes[i] == quote {
+                        // a doubled quote is an escaped quote
+                        if bytes.get(i + 1) != Some(&quote) {
+                            break;
+                        }
+                        i += 1;
+                    }
+                    i += 1;
+                }
+                i += 1;
+            }
+            b'$' if bytes.get(i + 1) == Some(&b'$') => {
+                i = sql[i + 2..]
+                    .find("$$")
+                    .map_or(bytes.len(), |end| i + end + 4);
+            }
+            b'(' => {
+                if depth == 0 && words.is_empty() {
+                    words.push((i, "(".to_string()));
+                }
+                depth += 1;
+                i += 1;
+            }
+            b')' => {
+                depth = depth.saturating_sub(1);
+                i += 1;
+            }
+            c if c.is_ascii_alphabetic() || c == b'_' => {
+                let start = i;
+                while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                    i += 1;
+                }
+                // `$name` parameters are not keywords
+                if depth == 0 && (start == 0 || bytes[start - 1] != b'$') {
+                    words.push((start, sql[start..i].to_ascii_uppercase()));
+                }
+            }
+            _ => i += 1,
+        }
+    }
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kind(sql: &str, select: bool) -> String {
+        statement_kind(sql, &|_| select)
+    }
+
+    #[test]
+    fn queries_are_confirmed_by_the_parser() {
+        assert_eq!(kind("select 1", true), "SELECT");
+        assert_eq!(
+            kind(
+                "  -- comment\n/* block */ WITH t AS (SELECT 1) SELECT * FROM t",
+                true
+            ),
+            "WITH"
+        );
+        assert_eq!(kind("FROM range(3)", true), "SELECT");
+        assert_eq!(kind("(SELECT 1) UNION (SELECT 2)", true), "SELECT");
+        assert_eq!(kind("desc tbl", true), "DESCRIBE");
+        assert_eq!(kind("SELECT 1", false), UNKNOWN_STATEMENT);
+    }
+
+    #[test]
+    fn statements_are_named_after_their_keyword() {
+        assert_eq!(kind("insert into t values (1)", false), "INSERT");
+        assert_eq!(kind("ATTACH 'other.db'", false), "ATTACH");
+        assert_eq!(kind("COPY t TO 'out.csv'", false), "COPY");
+        assert_eq!(kind("force install httpfs", false), "INSTALL");
+        assert_eq!(
+            kind(
+                "WITH t AS (SELECT 'select' AS s) INSERT INTO x SELECT * FROM t",
+                false
+            ),
+            "INSERT"
+        );
+        assert_eq!(kind("EXPLAIN ANALYZE DELETE FROM t", false), "DELETE");
+        assert_eq!(kind("EXPLAIN (FORMAT json) SELECT 1", true), "SELECT");
+    }
+
+    #[test]
+    fn quoted_text_is_skipped() {
+        let words = top_level_words("SELECT 'it''s (' AS \"from\", $name /* INSERT */ FROM t");
+        let words: Vec<_> = words.into_iter().map(|(_, word)| word).collect();
+        assert_eq!(words, ["SELECT", "AS", "FROM", "T"]);
+    }
+
+    #[test]
+    fn functions_are_collected() {
+        let statement = serde_json::json!({"node": {
+            "select_list": [
+                {"class": "FUNCTION", "function_name": "GETENV", "children": [
+                    {"class": "FUNCTION", "function_name": "lower", "children": []}
+                ]},
+                {"class": "WINDOW", "function_name": "row_number"}
+            ],
+            "from_table": {"type": "TABLE_FUNCTION", "function": {
+                "class": "FUNCTION", "function_name": "read_text", "children": []
+            }}
+        }});
+        let functions: Vec<_> = called_functions(&statement).into_iter().collect();
+        assert_eq!(functions, ["getenv", "lower", "read_text", "row_number"]);
+
+        let statement = serde_json::json!({"node": {
+            "from_table": {"type": "JOIN",
+                "left": {"type": "BASE_TABLE", "catalog_name": "", "schema_name": "",
+                    "table_name": "data/Trips.CSV.gz"},
+                "right": {"type": "BASE_TABLE", "catalog_name": "", "schema_name": "",
+                    "table_name": "s3://lake/*.parquet"}}
+        }});
+        let functions: Vec<_> = called_functions(&statement).into_iter().collect();
+        assert_eq!(
+            functions,
+            ["parquet_scan", "read_csv", "read_csv_auto", "read_parquet"]
+        );
+    }
+
+    #[test]
+    fn tables_are_collected_without_ctes() {
+        let statement = serde_json::json!({"node": {
+            "cte_map": {"map": [{"key": "recent", "value": {"query": {"node": {
+                "from_table": {"type": "BASE_TABLE", "catalog_name": "",
+                    "schema_name": "", "table_name": "orders"}
+            }}}}]},
+            "from_table": {"type": "JOIN",
+                "left": {"type": "BASE_TABLE", "catalog_name": "",
+                    "schema_name": "", "table_name": "RECENT"},
+                "right": {"type": "BASE_TABLE", "catalog_name": "lake",
+                    "schema_name": "main", "table_name": "customers"}
+            },
+            "where_clause": {"class": "SUBQUERY", "subquery": {"node": {
+                "from_table": {"type": "BASE_TABLE", "catalog_name": "",
+                    "schema_name": "", "table_name": "recent"}
+            }}}
+        }});
+        let tables: Vec<_> = referenced_tables(&statement)
+            .into_iter()
+            .map(|table| format!("{}.{}.{}", table.catalog, table.schema, table.table))
+            .collect();
+        assert_eq!(tables, ["..orders", "lake.main.customers"]);
+
+        // a table named after a CTE of another query is not a CTE reference
+        let statement = serde_json::json!([
+            {"cte_map": {"map": [{"key": "t"}]}},
+            {"from_table": {"type": "BASE_TABLE", "catalog_name": "",
+                "schema_name": "", "table_name": "t"}}
+        ]);
+        assert_eq!(referenced_tables(&statement).len(), 1);
+    }
+
+    #[test]
+    fn ctes_only_see_the_previous_ones() {
+        let table = |name: &str| {
+            serde_json::json!({"type": "BASE_TABLE", "catalog_name": "",
+                "schema_name": "", "table_name": name})
+        };
+        // WITH orders AS (FROM orders), recent AS (FROM orders, later) ...
+        let statement = serde_json::json!({"node": {
+            "cte_map": {"map": [
+                {"key": "orders", "value": {"query": {"node": {"type": "SELECT_NODE",
+                    "from_table": table("orders")}}}},
+                {"key": "recent", "value": {"query": {"node": {"type": "SELECT_NODE",
+                    "from_table": {"type": "JOIN", "left": table("orders"),
+                        "right": table("later")}}}}},
+                {"key": "later", "value": {"query": {"node": {"type": "RECURSIVE_CTE_NODE",
+                    "left": {"from_table": table("later")}}}}}
+            ]},
+            "from_table": table("recent")
+        }});
+        let tables: Vec<_> = referenced_tables(&statement)
+            .into_iter()
+            .map(|table| table.table)
+            .collect();
+        assert_eq!(tables, ["later", "orders"]);
+    }
+
+    #[test]
+    fn table_reads() {
+        let read = |table_ref: serde_json::Value| TableRead::of(table_ref.as_object().unwrap());
+        let constant = |path: &str| {
+            serde_json::json!({"class": "CONSTANT",
+                "value": {"type": {"id": "VARCHAR"}, "is_null": false, "value": path}})
+        };
+        assert_eq!(
+            read(
+                serde_json::json!({"type": "BASE_TABLE", "catalog_name": "lake",
+                "schema_name": "", "table_name": "orders"})
+            ),
+            Some(TableRead::Table(TableName {
+                catalog: "lake".to_string(),
+                schema: String::new(),
+                table: "orders".to_string(),
+            }))
+        );
+        assert_eq!(
+            read(serde_json::json!({"type": "TABLE_FUNCTION", "function": {
+            "class": "FUNCTION", "function_name": "READ_PARQUET", "children": [
+                {"class": "FUNCTION", "function_name": "list_value",
+                    "children": [constant("a.parquet"), constant("b.parquet")]},
+                {"class": "COMPARISON", "left": {}, "right": {}}
+            ]}})),
+            Some(TableRead::Files {
+                function: "read_parquet".to_string(),
+                paths: Some(vec!["a.parquet".to_string(), "b.parquet".to_string()]),
+            })
+        );
+        assert_eq!(
+            read(serde_json::json!({"type": "TABLE_FUNCTION", "function": {
+            "class": "FUNCTION", "function_name": "read_csv", "children": [
+                {"class": "FUNCTION", "function_name": "concat", "children": []}
+            ]}})),
+            Some(TableRead::Files {
+                function: "read_csv".to_string(),
+                paths: None,
+            })
+        );
+        assert_eq!(
+            read(serde_json::json!({"type": "TABLE_FUNCTION", "function": {
+                "class": "FUNCTION", "function_name": "range", "children": []}})),
+            None
+        );
+    }
+
+    #[test]
+    fn table_refs_are_wrapped_in_place() {
+        let wrapper = serde_json::json!({"type": "SUBQUERY", "alias": "",
+            "column_name_alias": [], "subquery": {"node": {"type": "SELECT_NODE",
+                "from_table": {"type": "BASE_TABLE", "table_name": "placeholder"},
+                "where_clause": {"class": "CONSTANT"}}}});
+        let mut statement = serde_json::json!({"node": {"from_table": {"type": "JOIN",
+            "left": {"type": "BASE_TABLE", "alias": "o", "column_name_alias": ["a"],
+                "catalog_name": "", "schema_name": "", "table_name": "orders"},
+            "right": {"type": "BASE_TABLE", "alias": "", "column_name_alias": [],
+                "catalog_name": "", "schema_name": "", "table_name": "data/2026.csv"}
+        }}});
+        replace_table_refs::<(), _>(&mut statement, &mut |table_ref| {
+            Ok(Some(wrap_table_ref(table_ref, wrapper.clone())))
+        })
+        .unwrap();
+        let join = &statement["node"]["from_table"];
+        assert_eq!(join["left"]["type"], "SUBQUERY");
+        assert_eq!(join["left"]["alias"], "o");
+        assert_eq!(join["left"]["column_name_alias"], serde_json::json!(["a"]));
+        let inner = &join["left"]["subquery"]["node"]["from_table"];
+        assert_eq!(inner["table_name"], "orders");
+        assert_eq!(inner["alias"], "");
+        assert_eq!(inner["column_name_alias"], serde_json::json!([]));
+        assert_eq!(join["right"]["alias"], "2026");
+        assert_eq!(
+            join["right"]["subquery"]["node"]["from_table"]["table_name"],
+            "data/2026.csv"
+        );
+    }
+
+    #[test]
+    fn allowed_kinds() {
+        let allowed = vec!["select".to_string(), "WITH".to_string()];
+        assert!(is_allowed("SELECT", &allowed));
+        assert!(!is_allowed("INSERT", &allowed));
+        assert!(is_allowed(UNKNOWN_STATEMENT, &["*".to_string()]));
+    }
+}
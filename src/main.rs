@@ -1,11 +1,19 @@
 use crate::cli::options::Options;
-use crate::core::duckdb::DuckDbEngine;
+use crate::cli::otlp;
+use crate::core::database::Database;
+use crate::core::duckdb::{DuckDbEngine, PoolQueue};
 use crate::core::engine::UQueryEngine;
-use duckdb::Connection;
+use crate::core::masking::MaskingPolicy;
+use crate::core::row_policy::RowPolicy;
 use pingora::prelude::{Server, http_proxy_service};
 
+use crate::web::jwt::JwtConfig;
+use crate::web::limits::Limits;
 use crate::web::proxy::UIProxyService;
+use crate::web::routers::RouterConfig;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::signal;
 use tokio::time::Instant;
 use tracing::{debug, info};
@@ -18,7 +26,8 @@ fn main() {
     let cli_options = cli::options::parse();
     let start = Instant::now();
     let addr = format!("{}:{}", cli_options.addr, cli_options.port);
-    let conn = Connection::open_in_memory().unwrap();
+    let database = Database::open_in_memory().unwrap();
+    let conn = database.connect().unwrap();
 
     if cli_options.install_extensions {
         for sql in Options::all_extensions_script() {
@@ -34,14 +43,35 @@ fn main() {
     for init_query in cli_options.init_script() {
         conn.execute(init_query.as_str(), []).unwrap();
     }
-    let engine: Arc<dyn UQueryEngine> = Arc::new(
-        DuckDbEngine::new(conn, cli_options.db_file.is_some(), cli_options.pool_size).unwrap(),
-    );
+    let mut duckdb_engine = DuckDbEngine::new(
+        database,
+        cli_options.db_file.is_some(),
+        cli_options.pool_size,
+    )
+    .unwrap()
+    .with_allowed_statements(cli_options.allowed_statements)
+    .with_denied_functions(cli_options.denied_functions)
+    .with_pool_queue(PoolQueue {
+        max_waiting: Some(cli_options.pool_max_waiting),
+        timeout: timeout_from_secs(cli_options.pool_acquire_timeout_secs),
+    });
+    if let Some(row_policies) = &cli_options.row_policies {
+        duckdb_engine = duckdb_engine
+            .with_row_policies(RowPolicy::load(row_policies).unwrap())
+            .unwrap();
+    }
+    if let Some(masking_policies) = &cli_options.masking_policies {
+        duckdb_engine =
+            duckdb_engine.with_masking_policies(MaskingPolicy::load(masking_policies).unwrap());
+    }
+    let engine: Arc<dyn UQueryEngine> = Arc::new(duckdb_engine);
 
     let tk_runtime = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .worker_threads(2)
-        .max_blocking_threads(cli_options.pool_size * 2)
+        // room for the running queries, the queries waiting for a connection and the
+        // other blocking work
+        .max_blocking_threads(cli_options.pool_size * 2 + cli_options.pool_max_waiting)
         .build()
         .unwrap();
 
@@ -55,16 +85,61 @@ fn main() {
         let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
         info!("uQuery server started in {:?}", start.elapsed());
         debug!("listening on {}", addr);
-        let query_timeout = match cli_options.query_timeout_secs {
-            0 => None,
-            secs => Some(std::time::Duration::from_secs(secs)),
+        let mut router_config = RouterConfig {
+            cors_enabled: cli_options.cors_enabled,
+            query_timeout: timeout_from_secs(cli_options.query_timeout_secs),
+            execution_timeout: timeout_from_secs(cli_options.execution_timeout_secs),
+            jobs_retention: Duration::from_secs(cli_options.jobs_retention_secs),
+            jobs_max_pending: cli_options.jobs_max_pending,
+            queries_dir: cli_options.queries_dir,
+            saved_queries_only: cli_options.saved_queries_only,
+            api_keys: cli_options.api_keys,
+            api_keys_file: cli_options.api_keys_file,
+            jwt: cli_options.jwt_jwks.map(|jwks| JwtConfig {
+                jwks_cache: Duration::from_secs(cli_options.jwt_jwks_cache_secs),
+                issuer: cli_options.jwt_issuer,
+                audience: cli_options.jwt_audience,
+                claims: cli_options.jwt_claims,
+                policies: cli_options.jwt_policies,
+                ..JwtConfig::new(jwks)
+            }),
+            limits: Limits {
+                rate: cli_options.rate_limit,
+                burst: cli_options.rate_limit_burst,
+                max_concurrent: cli_options.max_concurrent_queries,
+                global_rate: cli_options.global_rate_limit,
+                global_max_concurrent: cli_options.global_max_concurrent_queries,
+            },
+            metrics: cli_options.metrics_port.is_none(),
+            ..RouterConfig::default()
         };
-        let router = web::routers::create_router(engine, cli_options.cors_enabled, query_timeout);
-        axum::serve(listener, router)
+        if let Some(jobs_dir) = cli_options.jobs_dir {
+            router_config.jobs_dir = jobs_dir;
+        }
+        if let Some(metrics_port) = cli_options.metrics_port {
+            let metrics_addr = format!("{}:{}", cli_options.addr, metrics_port);
+            let metrics_listener = tokio::net::TcpListener::bind(&metrics_addr).await.unwrap();
+            debug!("serving metrics on {}", metrics_addr);
+            let admin_router = web::metrics::create_admin_router(Arc::clone(&engine));
+            tokio::spawn(async move {
+                axum::serve(metrics_listener, admin_router).await.unwrap();
+            });
+        }
+        let router = web::routers::create_router(engine, router_config);
+        let service = router.into_make_service_with_connect_info::<SocketAddr>();
+        axum::serve(listener, service)
             .with_graceful_shutdown(shutdown_signal())
             .await
             .unwrap();
     });
+    otlp::shutdown();
+}
+
+fn timeout_from_secs(secs: u64) -> Option<Duration> {
+    match secs {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    }
 }
 
 fn start_duckdb_ui_proxy(ui_port: u16) {
@@ -107,26 +182,47 @@ async fn shutdown_signal() {
 #[cfg(test)]
 mod tests {
     use crate::cli::options::UQ_ATTACHED_DB_NAME;
-    use crate::core::duckdb::DuckDbEngine;
-    use crate::core::engine::{ExecutableQuery, RecordBatchConsumer, UQueryEngine};
+    use crate::core::database::Database;
+    use crate::core::duckdb::{DuckDbEngine, PoolQueue};
+    use crate::core::engine::{
+        ExecutableQuery, QueryError, QueryScope, RecordBatchConsumer, UQueryEngine,
+    };
+    use crate::core::masking::MaskingPolicy;
+    use crate::core::params::QueryParams;
+    use crate::core::row_policy::RowPolicy;
+    use crate::web::auth::key_hash;
+    use crate::web::consumers::{CsvOptions, EXCEL_MAX_ROWS, JsonOptions};
+    use crate::web::jwt::JwtConfig;
+    use crate::web::jwt::tests::{TestSigner, expires_in};
+    use crate::web::limits::Limits;
+    use crate::web::queries::QUERY_ID_HEADER;
     use crate::web::request::QueryRequest;
-    use crate::web::response::QueryResponseFormat;
-    use crate::web::routers::create_router;
-    use axum::body::Body;
+    use crate::web::response::{ERROR_TRAILER, QueryResponseFormat, TRUNCATED_TRAILER};
+    use crate::web::routers::{RouterConfig, create_router};
+    use crate::web::timeouts::EXECUTION_TIMEOUT_HEADER;
+    use crate::web::{CONTENT_TYPE_ENVELOPE, CONTENT_TYPE_JSONLINES, CONTENT_TYPE_XLSX};
+    use arrow::array::{AsArray, Int32Array};
+    use arrow::datatypes::{DataType, Field, Int64Type, Schema};
+    use arrow::ipc::reader::StreamReader;
+    use arrow::record_batch::RecordBatch;
+    use axum::Router;
+    use axum::body::{Body, Bytes, HttpBody};
     use axum::http;
     use axum::http::header::{
         ACCEPT, ACCEPT_ENCODING, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
-        CONTENT_ENCODING, CONTENT_TYPE, ORIGIN,
+        CONTENT_ENCODING, CONTENT_TYPE, LOCATION, ORIGIN, TE, TRAILER,
     };
     use axum::http::{Request, StatusCode};
     use axum::response::Response;
-    use duckdb::Connection;
     use futures_util::TryStreamExt;
+    use http_body::Frame;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
     use polars::error::PolarsError;
     use polars_io::SerReader;
     use polars_io::ipc::IpcStreamReader;
     use serde_json::Value;
     use std::io::Cursor;
+    use std::pin::Pin;
     use std::str::from_utf8;
     use std::sync::Arc;
     use std::time::Duration;
@@ -135,7 +231,12 @@ mod tests {
     struct SlowEngine(Duration);
 
     impl UQueryEngine for SlowEngine {
-        fn prepare(&self, _sql: &str) -> Result<Box<dyn ExecutableQuery>, String> {
+        fn prepare(
+            &self,
+            _sql: &str,
+            _params: QueryParams,
+            _scope: QueryScope,
+        ) -> Result<Box<dyn ExecutableQuery>, QueryError> {
             std::thread::sleep(self.0);
             Ok(Box::new(SlowQuery))
         }
@@ -144,8 +245,62 @@ mod tests {
     struct SlowQuery;
 
     impl ExecutableQuery for SlowQuery {
-        fn execute(&mut self, consumer: &mut dyn RecordBatchConsumer) -> Result<(), String> {
-            consumer.finish()
+        fn execute(&mut self, consumer: &mut dyn RecordBatchConsumer) -> Result<(), QueryError> {
+            Ok(consumer.finish()?)
+        }
+    }
+
+    /// Sends a first batch, then fails: an error once the response has started.
+    struct FailingEngine;
+
+    impl UQueryEngine for FailingEngine {
+        fn prepare(
+            &self,
+            _sql: &str,
+            _params: QueryParams,
+            _scope: QueryScope,
+        ) -> Result<Box<dyn ExecutableQuery>, QueryError> {
+            Ok(Box::new(FailingQuery))
+        }
+    }
+
+    struct FailingQuery;
+
+    impl ExecutableQuery for FailingQuery {
+        fn execute(&mut self, consumer: &mut dyn RecordBatchConsumer) -> Result<(), QueryError> {
+            let schema = Arc::new(Schema::new(vec![Field::new("n", DataType::Int32, false)]));
+            consumer.on_schema(Arc::clone(&schema))?;
+            let batch =
+                RecordBatch::try_new(schema, vec![Arc::new(Int32Array::from(vec![1, 2, 3]))])
+                    .unwrap();
+            consumer.on_batch(batch)?;
+            Err(QueryError::Execution("boom".to_string()))
+        }
+    }
+
+    /// Returns a single batch of `.0` rows.
+    struct RowsEngine(usize);
+
+    impl UQueryEngine for RowsEngine {
+        fn prepare(
+            &self,
+            _sql: &str,
+            _params: QueryParams,
+            _scope: QueryScope,
+        ) -> Result<Box<dyn ExecutableQuery>, QueryError> {
+            Ok(Box::new(RowsQuery(self.0)))
+        }
+    }
+
+    struct RowsQuery(usize);
+
+    impl ExecutableQuery for RowsQuery {
+        fn execute(&mut self, consumer: &mut dyn RecordBatchConsumer) -> Result<(), QueryError> {
+            let schema = Arc::new(Schema::new(vec![Field::new("n", DataType::Int32, false)]));
+            consumer.on_schema(Arc::clone(&schema))?;
+            let values = Int32Array::from_iter_values(0..self.0 as i32);
+            consumer.on_batch(RecordBatch::try_new(schema, vec![Arc::new(values)]).unwrap())?;
+            Ok(consumer.finish()?)
         }
     }
 
@@ -155,7 +310,7 @@ mod tests {
     async fn query_json_test() {
         let response = perform_json_request(
             QueryRequest::new(TEST_QUERY.to_string()),
-            QueryResponseFormat::Json,
+            QueryResponseFormat::Json(JsonOptions::default()),
         )
         .await;
         assert_eq!(response.status(), StatusCode::OK);
@@ -168,8 +323,11 @@ mod tests {
 
     #[tokio::test]
     async fn query_text_plain_json_test() {
-        let response =
-            perform_plain_text_request(TEST_QUERY.to_string(), QueryResponseFormat::Json).await;
+        let response = perform_plain_text_request(
+            TEST_QUERY.to_string(),
+            QueryResponseFormat::Json(JsonOptions::default()),
+        )
+        .await;
         assert_eq!(response.status(), StatusCode::OK);
         let result = read_response(response).await;
         assert_eq!(
@@ -182,7 +340,7 @@ mod tests {
     async fn query_csv_test() {
         let response = perform_json_request(
             QueryRequest::new(TEST_QUERY.to_string()),
-            QueryResponseFormat::Csv,
+            QueryResponseFormat::Csv(CsvOptions::default()),
         )
         .await;
         assert_eq!(response.status(), StatusCode::OK);
@@ -212,11 +370,333 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn query_csv_dialect_test() {
+        let response = create_router(make_engine(false), RouterConfig::default())
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/?bom=true&date_format=%25d%2F%25m%2F%25Y")
+                    .header(CONTENT_TYPE, "text/plain")
+                    .header(ACCEPT, "text/tab-separated-values;header=absent;null=NULL")
+                    .body(Body::from(
+                        "SELECT 1 AS id, 'a;b' AS s, NULL::INTEGER AS n, DATE '2024-01-31' AS day",
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(CONTENT_TYPE).unwrap(),
+            "text/tab-separated-values"
+        );
+        let result = read_response(response).await;
+        assert_eq!(
+            from_utf8(&result).unwrap(),
+            "\u{FEFF}1\ta;b\tNULL\t31/01/2024\n"
+        );
+    }
+
+    async fn envelope_request(uri: &str, sql: &'static str) -> Value {
+        let response = create_router(make_engine(false), RouterConfig::default())
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri(uri)
+                    .header(CONTENT_TYPE, "text/plain")
+                    .header(ACCEPT, CONTENT_TYPE_ENVELOPE)
+                    .body(Body::from(sql))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(CONTENT_TYPE).unwrap(),
+            CONTENT_TYPE_ENVELOPE
+        );
+        serde_json::from_slice(&read_response(response).await).unwrap()
+    }
+
+    #[tokio::test]
+    async fn query_envelope_test() {
+        let envelope = envelope_request("/", TEST_QUERY).await;
+        assert_eq!(envelope["schema"][0]["name"], "Id");
+        assert_eq!(envelope["schema"][0]["type"], "Int32");
+        assert_eq!(envelope["schema"][1]["name"], "Name");
+        assert_eq!(envelope["data"][0]["Name"], "Rust");
+        assert_eq!(envelope["stats"]["rows"], 1);
+        assert_eq!(envelope["stats"]["truncated"], false);
+        assert!(envelope["stats"]["elapsed_ms"].is_u64());
+    }
+
+    #[tokio::test]
+    async fn query_envelope_empty_test() {
+        let envelope = envelope_request("/", "SELECT 1 AS id, 'a' AS name WHERE false").await;
+        let names: Vec<&str> = envelope["schema"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|field| field["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, vec!["id", "name"]);
+        assert_eq!(envelope["data"], serde_json::json!([]));
+        assert_eq!(envelope["stats"]["rows"], 0);
+    }
+
+    #[tokio::test]
+    async fn query_envelope_truncated_test() {
+        let envelope = envelope_request("/?max_rows=3", "SELECT * FROM range(10)").await;
+        assert_eq!(envelope["data"].as_array().unwrap().len(), 3);
+        assert_eq!(envelope["stats"]["rows"], 3);
+        assert_eq!(envelope["stats"]["truncated"], true);
+    }
+
+    #[tokio::test]
+    async fn query_json_options_test() {
+        let response = create_router(make_engine(false), RouterConfig::default())
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/?nulls=explicit")
+                    .header(CONTENT_TYPE, "text/plain")
+                    .header(
+                        ACCEPT,
+                        "application/json;int64=string;decimal=string;timestamp=epoch_ms;binary=base64",
+                    )
+                    .body(Body::from(
+                        "SELECT 9007199254740993::BIGINT AS big, 1.50::DECIMAL(4,2) AS dec, \
+                         TIMESTAMP '2024-01-31 10:00:00' AS ts, 'abc'::BLOB AS bin, \
+                         NULL::INTEGER AS missing",
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let rows: Value = serde_json::from_slice(&read_response(response).await).unwrap();
+        assert_eq!(
+            rows,
+            serde_json::json!([{
+                "big": "9007199254740993",
+                "dec": "1.50",
+                "ts": 1706695200000i64,
+                "bin": "YWJj",
+                "missing": null,
+            }])
+        );
+    }
+
+    #[tokio::test]
+    async fn query_json_options_header_test() {
+        let response = create_router(make_engine(false), RouterConfig::default())
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/")
+                    .header(CONTENT_TYPE, "text/plain")
+                    .header(ACCEPT, CONTENT_TYPE_JSONLINES)
+                    .header("x-uquery-json-options", "timestamp=iso; nulls=omit")
+                    .body(Body::from(
+                        "SELECT TIMESTAMP '2024-01-31 10:00:00' AS ts, NULL::INTEGER AS missing",
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = read_response(response).await;
+        assert_eq!(
+            String::from_utf8_lossy(&body).trim(),
+            r#"{"ts":"2024-01-31T10:00:00Z"}"#
+        );
+
+        let envelope = envelope_request("/?int64=string", "SELECT 42::BIGINT AS n").await;
+        assert_eq!(envelope["data"][0]["n"], "42");
+    }
+
+    #[tokio::test]
+    async fn query_parquet_test() {
+        let response = create_router(make_engine(false), RouterConfig::default())
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/?compression=zstd")
+                    .header(CONTENT_TYPE, "text/plain")
+                    .header(ACCEPT, "application/vnd.apache.parquet;row_group_size=4")
+                    .body(Body::from("SELECT range AS n FROM range(10)"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(CONTENT_TYPE).unwrap(),
+            "application/vnd.apache.parquet"
+        );
+        let result = Bytes::from(read_response(response).await);
+        let builder = ParquetRecordBatchReaderBuilder::try_new(result).unwrap();
+        let metadata = builder.metadata().clone();
+        assert_eq!(metadata.num_row_groups(), 3);
+        assert!(matches!(
+            metadata.row_group(0).column(0).compression(),
+            parquet::basic::Compression::ZSTD(_)
+        ));
+        let values: Vec<i64> = builder
+            .build()
+            .unwrap()
+            .flat_map(|batch| {
+                batch
+                    .unwrap()
+                    .column(0)
+                    .as_primitive::<Int64Type>()
+                    .values()
+                    .to_vec()
+            })
+            .collect();
+        assert_eq!(values, (0..10).collect::<Vec<i64>>());
+    }
+
+    #[tokio::test]
+    async fn query_parquet_invalid_option_test() {
+        let response = create_router(make_engine(false), RouterConfig::default())
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/?row_group_size=0")
+                    .header(CONTENT_TYPE, "text/plain")
+                    .header(ACCEPT, "application/vnd.apache.parquet")
+                    .body(Body::from("SELECT 1"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let result = read_response(response).await;
+        let error: Value = serde_json::from_str(from_utf8(&result).unwrap()).unwrap();
+        assert_eq!(error["title"], "Invalid Format Option");
+    }
+
+    #[tokio::test]
+    async fn query_without_accept_test() {
+        let response = create_router(make_engine(false), RouterConfig::default())
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/")
+                    .header(CONTENT_TYPE, "text/plain")
+                    .body(Body::from("SELECT 1 AS one"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let result = read_response(response).await;
+        assert_eq!(from_utf8(&result).unwrap(), r#"[{"one":1}]"#);
+
+        let response = create_router(make_engine(false), RouterConfig::default())
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/")
+                    .header(CONTENT_TYPE, "text/plain")
+                    .header(
+                        ACCEPT,
+                        http::HeaderValue::from_bytes(b"text/csv\xe9").unwrap(),
+                    )
+                    .body(Body::from("SELECT 1 AS one"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn query_xlsx_test() {
+        let response = create_router(make_engine(false), RouterConfig::default())
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/")
+                    .header(CONTENT_TYPE, "text/plain")
+                    .header(ACCEPT, CONTENT_TYPE_XLSX)
+                    .body(Body::from(
+                        "SELECT 1 AS i, 1.5::DECIMAL(4,2) AS d, DATE '2024-01-31' AS day, TIMESTAMP '2024-01-31 10:00:00' AS ts, true AS b, 'Rust' AS s, [1, 2] AS l, NULL AS n",
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(CONTENT_TYPE).unwrap(),
+            CONTENT_TYPE_XLSX
+        );
+        let result = read_response(response).await;
+        // an xlsx file is a zip archive
+        assert!(result.starts_with(b"PK\x03\x04"));
+    }
+
+    #[tokio::test]
+    async fn query_xlsx_large_result_test() {
+        let response = create_router(Arc::new(RowsEngine(500_000)), RouterConfig::default())
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/")
+                    .header(CONTENT_TYPE, "text/plain")
+                    .header(ACCEPT, CONTENT_TYPE_XLSX)
+                    .body(Body::from("SELECT 1"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        // the workbook, larger than the response buffer, is copied whole from its file
+        let result = read_response(response).await;
+        assert!(result.len() > 1024 * 1024, "{} bytes", result.len());
+        assert!(result.starts_with(b"PK\x03\x04"));
+        // the zip archive ends with its central directory
+        let end = &result[result.len() - 22..];
+        assert!(end.starts_with(b"PK\x05\x06"));
+    }
+
+    #[tokio::test]
+    async fn query_xlsx_too_many_rows_test() {
+        let response = create_router(
+            Arc::new(RowsEngine(EXCEL_MAX_ROWS)),
+            RouterConfig::default(),
+        )
+        .oneshot(
+            Request::builder()
+                .method(http::Method::POST)
+                .uri("/")
+                .header(CONTENT_TYPE, "text/plain")
+                .header(ACCEPT, CONTENT_TYPE_XLSX)
+                .body(Body::from("SELECT 1"))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+        // the workbook is only sent once complete: the error comes with a proper status
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let result = read_response(response).await;
+        let error: Value = serde_json::from_str(from_utf8(&result).unwrap()).unwrap();
+        assert!(
+            error["detail"]
+                .as_str()
+                .unwrap()
+                .contains("rows of an Excel sheet")
+        );
+    }
+
     #[tokio::test]
     async fn query_json_gzip_test() {
         let response = perform_json_request_compress(
             QueryRequest::new(TEST_QUERY.to_string()),
-            QueryResponseFormat::Json,
+            QueryResponseFormat::Json(JsonOptions::default()),
             true,
         )
         .await;
@@ -236,16 +716,20 @@ mod tests {
             .method(http::Method::POST)
             .uri("/")
             .header(CONTENT_TYPE, "application/json")
-            .header(ACCEPT, QueryResponseFormat::Json.to_string());
+            .header(
+                ACCEPT,
+                QueryResponseFormat::Json(JsonOptions::default()).to_string(),
+            );
 
-        let conn = Connection::open_in_memory().unwrap();
+        let database = Database::open_in_memory().unwrap();
+        let conn = database.connect().unwrap();
         conn.execute(
             format!("ATTACH 'tests/test.db' as {UQ_ATTACHED_DB_NAME};").as_str(),
             [],
         )
         .unwrap();
-        let engine: Arc<dyn UQueryEngine> = Arc::new(DuckDbEngine::new(conn, true, 2).unwrap());
-        let response = create_router(engine, false, None)
+        let engine: Arc<dyn UQueryEngine> = Arc::new(DuckDbEngine::new(database, true, 2).unwrap());
+        let response = create_router(engine, RouterConfig::default())
             .oneshot(builder.body(Body::from(json)).unwrap())
             .await
             .unwrap();
@@ -258,160 +742,1193 @@ mod tests {
         assert_eq!(json_array[0].get("name").unwrap().as_str().unwrap(), "Rust");
     }
 
-    #[tokio::test]
-    async fn cors_enabled_test() {
-        let builder = Request::builder()
-            .method(http::Method::OPTIONS)
-            .uri("/")
-            .header(ACCESS_CONTROL_ALLOW_METHODS, "POST")
-            .header(ORIGIN, "https://origin.com");
-
-        let conn = Connection::open_in_memory().unwrap();
-        let engine: Arc<dyn UQueryEngine> = Arc::new(DuckDbEngine::new(conn, false, 2).unwrap());
-        let response = create_router(engine, true, None)
-            .oneshot(builder.body(Body::empty()).unwrap())
+    async fn describe_request(sql: &str, accept: &str) -> Response {
+        create_router(make_engine(false), RouterConfig::default())
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/describe")
+                    .header(CONTENT_TYPE, "text/plain")
+                    .header(ACCEPT, accept)
+                    .body(Body::from(sql.to_string()))
+                    .unwrap(),
+            )
             .await
-            .unwrap();
-        assert_eq!(response.status(), StatusCode::OK);
-        assert_eq!(
-            response.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(),
-            "*"
-        );
-        assert_eq!(
-            response
-                .headers()
-                .get(ACCESS_CONTROL_ALLOW_METHODS)
-                .unwrap(),
-            "*"
-        );
+            .unwrap()
     }
 
     #[tokio::test]
-    async fn query_sql_error_test() {
-        let response = perform_json_request(
-            QueryRequest::new("bad command".to_string()),
-            QueryResponseFormat::Json,
+    async fn describe_test() {
+        let response = describe_request(
+            "SELECT n, n::VARCHAR AS label FROM range(10) r(n) WHERE n > $min AND n < $max",
+            "application/json",
         )
         .await;
-        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
-        let result = read_response(response).await;
-        let error: Value = serde_json::from_str(from_utf8(&*result).unwrap()).unwrap();
-        assert_eq!(error["status"].as_u64().unwrap(), 400);
-        assert_eq!(error["title"], "SQL Error");
-        assert!(!error["detail"].to_string().is_empty());
+        assert_eq!(response.status(), StatusCode::OK);
+        let description: Value = serde_json::from_slice(&read_response(response).await).unwrap();
+        assert_eq!(description["schema"][0]["name"], "n");
+        assert_eq!(description["schema"][0]["type"], "Int64");
+        assert_eq!(description["schema"][1]["name"], "label");
+        assert_eq!(description["schema"][1]["type"], "Utf8");
+        let mut parameters: Vec<(&str, &str)> = description["parameters"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|parameter| {
+                (
+                    parameter["name"].as_str().unwrap(),
+                    parameter["type"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        parameters.sort();
+        assert_eq!(parameters, vec![("max", "Int64"), ("min", "Int64")]);
     }
 
     #[tokio::test]
-    async fn read_csv_test() {
-        let response = perform_json_request(
-            QueryRequest::new("select * from read_csv('tests/test.csv')".to_string()),
-            QueryResponseFormat::Json,
+    async fn describe_statements_test() {
+        for (sql, columns) in [
+            ("SELECT 1 AS n -- the answer", vec!["n"]),
+            ("DESCRIBE SELECT 1 AS n", vec!["column_name", "column_type"]),
+            (
+                "SUMMARIZE SELECT 1 AS n",
+                vec!["column_name", "column_type"],
+            ),
+        ] {
+            let response = describe_request(sql, "application/json").await;
+            assert_eq!(response.status(), StatusCode::OK, "{sql}");
+            let description: Value =
+                serde_json::from_slice(&read_response(response).await).unwrap();
+            let names: Vec<&str> = description["schema"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|field| field["name"].as_str().unwrap())
+                .collect();
+            assert!(names.starts_with(&columns), "{sql}: {names:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn describe_arrow_test() {
+        let response = describe_request(
+            "SELECT 1 AS id, 'a' AS name;",
+            QueryResponseFormat::Arrow.to_string().as_str(),
         )
         .await;
         assert_eq!(response.status(), StatusCode::OK);
-        let result = read_response(response).await;
-        let response_string = from_utf8(&*result).unwrap();
-        let json_array: Vec<Value> = serde_json::from_str(response_string).unwrap();
-        assert_eq!(json_array.len(), 2);
-        assert_eq!(json_array[0].get("f_str").unwrap().as_str().unwrap(), "abc");
-        assert_eq!(json_array[0].get("f_int").unwrap().as_i64().unwrap(), 123);
-        assert_eq!(
-            json_array[0].get("f_float").unwrap().as_f64().unwrap(),
-            4.56
-        );
+        let reader =
+            StreamReader::try_new(Cursor::new(read_response(response).await), None).unwrap();
+        let names: Vec<String> = reader
+            .schema()
+            .fields()
+            .iter()
+            .map(|f| f.name().clone())
+            .collect();
+        assert_eq!(names, vec!["id", "name"]);
+        assert_eq!(reader.count(), 0);
     }
 
     #[tokio::test]
-    async fn read_parquet_test() {
-        let response = perform_json_request(
-            QueryRequest::new("select * from 'tests/test.zstd.parquet'".to_string()),
-            QueryResponseFormat::Json,
+    async fn describe_binder_error_test() {
+        let response = describe_request(
+            "SELECT 1 AS id,\n  missing FROM range(3)",
+            "application/json",
         )
         .await;
-
-        assert_eq!(response.status(), StatusCode::OK);
-
-        let result = read_response(response).await;
-        let response_string = from_utf8(&*result).unwrap();
-        let json_array: Vec<Value> = serde_json::from_str(response_string).unwrap();
-
-        assert_eq!(json_array.len(), 2);
-        assert_eq!(json_array[0].get("f_str").unwrap().as_str().unwrap(), "abc");
-        assert_eq!(json_array[0].get("f_int").unwrap().as_i64().unwrap(), 123);
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
         assert_eq!(
-            json_array[0].get("f_float").unwrap().as_f64().unwrap(),
-            4.56
+            response.headers().get(CONTENT_TYPE).unwrap(),
+            "application/problem+json"
         );
+        let problem: Value = serde_json::from_slice(&read_response(response).await).unwrap();
+        assert!(problem["detail"].as_str().unwrap().contains("missing"));
+        assert_eq!(problem["position"]["line"], 2);
+        assert_eq!(problem["position"]["column"], 3);
+
+        let response = describe_request("SELECT 1", CONTENT_TYPE_XLSX).await;
+        assert_eq!(response.status(), StatusCode::NOT_ACCEPTABLE);
     }
 
     #[tokio::test]
-    async fn read_json_test() {
-        let response = perform_json_request(
-            QueryRequest::new("select * from 'tests/test.jsonl'".to_string()),
-            QueryResponseFormat::Json,
-        )
-        .await;
+    async fn describe_refused_statement_test() {
+        let response =
+            describe_request("CREATE TABLE described (n INTEGER)", "application/json").await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let problem: Value = serde_json::from_slice(&read_response(response).await).unwrap();
+        assert_eq!(problem["title"], "Statement Not Allowed");
 
-        assert_eq!(response.status(), StatusCode::OK);
+        let database = Database::open_in_memory().unwrap();
+        let conn = database.connect().unwrap();
+        let engine: Arc<dyn UQueryEngine> = Arc::new(
+            DuckDbEngine::new(database, false, 1)
+                .unwrap()
+                .with_allowed_statements(vec!["*".to_string()]),
+        );
+        let response = create_router(engine, RouterConfig::default())
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/describe")
+                    .header(CONTENT_TYPE, "text/plain")
+                    .body(Body::from("CREATE TABLE described (n INTEGER); SELECT 1"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        // the leading statement was refused before it could run
+        let tables: i64 = conn
+            .query_row(
+                "SELECT count(*) FROM duckdb_tables() WHERE table_name = 'described'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(tables, 0);
+    }
 
-        let result = read_response(response).await;
-        let response_string = from_utf8(&*result).unwrap();
-        let json_array: Vec<Value> = serde_json::from_str(response_string).unwrap();
+    async fn explain_request(uri: &str, sql: &str) -> Response {
+        create_router(make_engine(false), RouterConfig::default())
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri(uri)
+                    .header(CONTENT_TYPE, "text/plain")
+                    .body(Body::from(sql.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap()
+    }
 
-        assert_eq!(json_array.len(), 2);
-        assert_eq!(json_array[0].get("f_str").unwrap().as_str().unwrap(), "abc");
-        assert_eq!(json_array[0].get("f_int").unwrap().as_i64().unwrap(), 123);
-        assert_eq!(
-            json_array[0].get("f_float").unwrap().as_f64().unwrap(),
-            4.56
-        );
+    /// All the nodes of an explain plan, depth first.
+    fn plan_nodes(nodes: &Value) -> Vec<&Value> {
+        nodes
+            .as_array()
+            .unwrap()
+            .iter()
+            .flat_map(|node| {
+                let mut nodes = vec![node];
+                nodes.extend(plan_nodes(&node["children"]));
+                nodes
+            })
+            .collect()
     }
 
     #[tokio::test]
-    async fn text_plain_request_test() {
-        let response = perform_plain_text_request(
-            "select * from 'tests/test.jsonl'".to_string(),
-            QueryResponseFormat::Json,
+    async fn explain_test() {
+        let response = explain_request(
+            "/explain",
+            "SELECT count(*) AS n FROM range(100) r(i) WHERE i > 50",
         )
         .await;
-
         assert_eq!(response.status(), StatusCode::OK);
-
-        let result = read_response(response).await;
-        let response_string = from_utf8(&*result).unwrap();
-        let json_array: Vec<Value> = serde_json::from_str(response_string).unwrap();
-
-        assert_eq!(json_array.len(), 2);
-        assert_eq!(json_array[0].get("f_str").unwrap().as_str().unwrap(), "abc");
-        assert_eq!(json_array[0].get("f_int").unwrap().as_i64().unwrap(), 123);
-        assert_eq!(
-            json_array[0].get("f_float").unwrap().as_f64().unwrap(),
-            4.56
+        let explain: Value = serde_json::from_slice(&read_response(response).await).unwrap();
+        assert_eq!(explain["analyze"], false);
+        let nodes = plan_nodes(&explain["plan"]);
+        assert!(!nodes.is_empty());
+        assert!(
+            nodes
+                .iter()
+                .all(|node| !node["name"].as_str().unwrap().is_empty())
+        );
+        assert!(
+            nodes
+                .iter()
+                .any(|node| node["estimated_cardinality"].is_u64())
         );
+        assert!(nodes.iter().all(|node| node.get("cardinality").is_none()));
     }
 
     #[tokio::test]
-    async fn read_jsonlines_test() {
-        let response = perform_json_request(
-            QueryRequest::new("select * from 'tests/test.jsonl'".to_string()),
-            QueryResponseFormat::JsonLINES,
+    async fn explain_analyze_test() {
+        let response = explain_request(
+            "/explain?analyze=true",
+            "SELECT count(*) AS n FROM range(100) r(i) WHERE i > 50;",
         )
         .await;
-
         assert_eq!(response.status(), StatusCode::OK);
+        let explain: Value = serde_json::from_slice(&read_response(response).await).unwrap();
+        assert_eq!(explain["analyze"], true);
+        assert!(explain["metrics"]["latency"].is_number());
+        let nodes = plan_nodes(&explain["plan"]);
+        assert!(
+            nodes
+                .iter()
+                .any(|node| node["cardinality"] == 49 && node["timing"].is_number())
+        );
 
-        let result = read_response(response).await;
-        let response_string = from_utf8(&*result).unwrap().lines().collect::<Vec<&str>>();
-        let json_first: Value = serde_json::from_str(response_string.get(0).unwrap()).unwrap();
-
-        assert_eq!(response_string.len(), 2);
-        assert_eq!(json_first.get("f_str").unwrap().as_str().unwrap(), "abc");
-        assert_eq!(json_first.get("f_int").unwrap().as_i64().unwrap(), 123);
-        assert_eq!(json_first.get("f_float").unwrap().as_f64().unwrap(), 4.56);
+        let response = explain_request("/explain", "SELECT missing FROM range(3)").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
     }
 
-    #[tokio::test]
-    /*
+    const RANGE_QUERY: &str = "---
+description: Numbers up to a bound
+params:
+  bound: integer
+  step: integer = 1
+---
+SELECT range AS n FROM range(0, $bound, $step)
+";
+
+    /// A queries directory holding `files`, reloaded every 50ms.
+    fn saved_queries_config(files: &[(&str, &str)]) -> RouterConfig {
+        let dir = std::env::temp_dir().join(format!("uquery-saved-{}", crate::web::new_id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, content) in files {
+            std::fs::write(dir.join(name), content).unwrap();
+        }
+        RouterConfig {
+            queries_dir: Some(dir),
+            queries_reload_interval: Duration::from_millis(50),
+            ..RouterConfig::default()
+        }
+    }
+
+    async fn saved_query_request(
+        router: &Router,
+        method: http::Method,
+        uri: &str,
+        body: &str,
+    ) -> Response {
+        router
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method(method)
+                    .uri(uri)
+                    .header(ACCEPT, "application/json")
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(body.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn saved_query_test() {
+        let router = create_router(
+            make_engine(false),
+            saved_queries_config(&[("range.sql", RANGE_QUERY), ("broken.sql", "---\nSELECT 1")]),
+        );
+        let response = saved_query_request(&router, http::Method::GET, "/q", "").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let listing: Value = serde_json::from_slice(&read_response(response).await).unwrap();
+        assert_eq!(listing.as_array().unwrap().len(), 1);
+        assert_eq!(listing[0]["name"], "range");
+        assert_eq!(listing[0]["description"], "Numbers up to a bound");
+        assert_eq!(listing[0]["params"][0]["name"], "bound");
+        assert_eq!(listing[0]["params"][0]["type"], "integer");
+        assert_eq!(listing[0]["params"][0]["required"], true);
+        assert_eq!(listing[0]["params"][1]["default"], 1);
+
+        let response = saved_query_request(
+            &router,
+            http::Method::GET,
+            "/q/range?bound=3&int64=string",
+            "",
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let rows: Value = serde_json::from_slice(&read_response(response).await).unwrap();
+        assert_eq!(
+            rows,
+            serde_json::json!([{"n": "0"}, {"n": "1"}, {"n": "2"}])
+        );
+
+        let response = saved_query_request(
+            &router,
+            http::Method::POST,
+            "/q/range",
+            r#"{"bound": 10, "step": 5}"#,
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let rows: Value = serde_json::from_slice(&read_response(response).await).unwrap();
+        assert_eq!(rows, serde_json::json!([{"n": 0}, {"n": 5}]));
+
+        for (uri, body, status) in [
+            ("/q/range", "", StatusCode::BAD_REQUEST),
+            ("/q/range?bound=ten", "", StatusCode::BAD_REQUEST),
+            (
+                "/q/range",
+                r#"{"bound": 3, "sql": "drop"}"#,
+                StatusCode::BAD_REQUEST,
+            ),
+            ("/q/broken", "", StatusCode::NOT_FOUND),
+        ] {
+            let response = saved_query_request(&router, http::Method::POST, uri, body).await;
+            assert_eq!(response.status(), status, "{uri} {body}");
+        }
+    }
+
+    #[tokio::test]
+    async fn saved_query_reload_test() {
+        let config = saved_queries_config(&[("range.sql", RANGE_QUERY)]);
+        let dir = config.queries_dir.clone().unwrap();
+        let router = create_router(make_engine(false), config);
+        let response = saved_query_request(&router, http::Method::GET, "/q/answer", "").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        std::fs::write(dir.join("answer.sql"), "SELECT 42 AS answer").unwrap();
+        std::fs::remove_file(dir.join("range.sql")).unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        let response = saved_query_request(&router, http::Method::GET, "/q/answer", "").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let rows: Value = serde_json::from_slice(&read_response(response).await).unwrap();
+        assert_eq!(rows, serde_json::json!([{"answer": 42}]));
+        let response =
+            saved_query_request(&router, http::Method::GET, "/q/range?bound=1", "").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn saved_queries_only_test() {
+        let router = create_router(
+            make_engine(false),
+            RouterConfig {
+                saved_queries_only: true,
+                ..saved_queries_config(&[("range.sql", RANGE_QUERY)])
+            },
+        );
+        for uri in ["/", "/describe", "/explain", "/jobs"] {
+            let response = saved_query_request(&router, http::Method::POST, uri, TEST_QUERY).await;
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{uri}");
+            assert_eq!(
+                response.headers().get(CONTENT_TYPE).unwrap(),
+                "application/problem+json"
+            );
+            let problem: Value = serde_json::from_slice(&read_response(response).await).unwrap();
+            assert_eq!(problem["title"], "Ad-hoc SQL Disabled");
+        }
+
+        let response =
+            saved_query_request(&router, http::Method::GET, "/q/range?bound=2", "").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let rows: Value = serde_json::from_slice(&read_response(response).await).unwrap();
+        assert_eq!(rows, serde_json::json!([{"n": 0}, {"n": 1}]));
+    }
+
+    #[tokio::test]
+    async fn statement_allowlist_test() {
+        for sql in [
+            "CREATE TABLE forbidden AS SELECT 1 AS n",
+            "WITH t AS (SELECT 1) INSERT INTO forbidden SELECT * FROM t",
+            "ATTACH ':memory:' AS other",
+        ] {
+            let response = perform_json_request(
+                QueryRequest::new(sql.to_string()),
+                QueryResponseFormat::Json(JsonOptions::default()),
+            )
+            .await;
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{sql}");
+            let problem: Value = serde_json::from_slice(&read_response(response).await).unwrap();
+            assert_eq!(problem["title"], "Statement Not Allowed");
+        }
+        let response = explain_request("/explain?analyze=true", "CREATE TABLE t (n INTEGER)").await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        for sql in [
+            "WITH t AS (SELECT 1 AS n) SELECT * FROM t",
+            "DESCRIBE SELECT 1 AS n",
+            "SUMMARIZE SELECT 1 AS n",
+        ] {
+            let response = perform_json_request(
+                QueryRequest::new(sql.to_string()),
+                QueryResponseFormat::Json(JsonOptions::default()),
+            )
+            .await;
+            assert_eq!(response.status(), StatusCode::OK, "{sql}");
+        }
+
+        let engine: Arc<dyn UQueryEngine> = Arc::new(
+            DuckDbEngine::new(Database::open_in_memory().unwrap(), false, 1)
+                .unwrap()
+                .with_allowed_statements(vec!["*".to_string()]),
+        );
+        let response = create_router(engine, RouterConfig::default())
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/")
+                    .header(CONTENT_TYPE, "text/plain")
+                    .body(Body::from("CREATE TABLE allowed (n INTEGER)"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn multiple_statements_test() {
+        let database = Database::open_in_memory().unwrap();
+        let conn = database.connect().unwrap();
+        conn.execute_batch("CREATE TABLE t (n INTEGER);").unwrap();
+        let engine: Arc<dyn UQueryEngine> = Arc::new(
+            DuckDbEngine::new(database, false, 1)
+                .unwrap()
+                .with_allowed_statements(vec!["*".to_string()]),
+        );
+        let router = create_router(engine, RouterConfig::default());
+        for (uri, sql, status) in [
+            (
+                "/",
+                "INSERT INTO t VALUES (1); SELECT 1",
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                "/",
+                "ATTACH ':memory:' AS other; SELECT 1",
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                "/explain?analyze=true",
+                "INSERT INTO t VALUES (2); SELECT 1",
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                "/",
+                "INSERT INTO t VALUES (3); INSERT INTO t VALUES (4)",
+                StatusCode::FORBIDDEN,
+            ),
+            ("/", "DROP TABLE t; SELECT 1", StatusCode::FORBIDDEN),
+            ("/describe", "DROP TABLE t; SELECT 1", StatusCode::FORBIDDEN),
+            ("/", "SELECT count(*) AS n FROM t;", StatusCode::OK),
+        ] {
+            let response = router
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .method(http::Method::POST)
+                        .uri(uri)
+                        .header(CONTENT_TYPE, "text/plain")
+                        .body(Body::from(sql))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), status, "{sql}");
+        }
+        // none of the refused statements ran: the table is still there, and empty
+        let rows: i64 = conn
+            .query_row("SELECT count(*) FROM t", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(rows, 0);
+        let attached: i64 = conn
+            .query_row(
+                "SELECT count(*) FROM duckdb_databases() WHERE database_name = 'other'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(attached, 0);
+    }
+
+    #[tokio::test]
+    async fn function_denylist_test() {
+        let engine: Arc<dyn UQueryEngine> = Arc::new(
+            DuckDbEngine::new(Database::open_in_memory().unwrap(), false, 1)
+                .unwrap()
+                .with_denied_functions(vec![
+                    "getenv".to_string(),
+                    "READ_TEXT".to_string(),
+                    "read_csv".to_string(),
+                ]),
+        );
+        let router = create_router(engine, RouterConfig::default());
+        for (uri, sql, status) in [
+            ("/", "SELECT upper('rust') AS name", StatusCode::OK),
+            ("/", "SELECT GetEnv('HOME') AS home", StatusCode::FORBIDDEN),
+            (
+                "/",
+                "WITH t AS (SELECT * FROM read_text('Cargo.toml')) SELECT count(*) FROM t",
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                "/explain?analyze=true",
+                "SELECT length(getenv('HOME'))",
+                StatusCode::FORBIDDEN,
+            ),
+            // the SQL run by query() can't be checked
+            (
+                "/",
+                "SELECT * FROM query('SELECT getenv(''HOME'')')",
+                StatusCode::FORBIDDEN,
+            ),
+            // a file read by its path is read by read_csv
+            (
+                "/",
+                "SELECT count(*) FROM 'data/trips.csv'",
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                "/",
+                "SELECT count(*) FROM 'data/trips.tsv.gz' AS t",
+                StatusCode::FORBIDDEN,
+            ),
+        ] {
+            let response = router
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .method(http::Method::POST)
+                        .uri(uri)
+                        .header(CONTENT_TYPE, "text/plain")
+                        .body(Body::from(sql))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), status, "{sql}");
+            if status == StatusCode::FORBIDDEN {
+                let problem: Value =
+                    serde_json::from_slice(&read_response(response).await).unwrap();
+                assert!(problem["detail"].as_str().unwrap().starts_with("function "));
+            }
+        }
+    }
+
+    async fn auth_request(
+        router: &Router,
+        method: http::Method,
+        uri: &str,
+        key: Option<&str>,
+        sql: &str,
+    ) -> Response {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(ACCEPT, "application/json")
+            .header(CONTENT_TYPE, "text/plain");
+        if let Some(key) = key {
+            request = request.header(http::header::AUTHORIZATION, format!("Bearer {key}"));
+        }
+        router
+            .clone()
+            .oneshot(request.body(Body::from(sql.to_string())).unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn api_key_test() {
+        let database = Database::open_in_memory().unwrap();
+        let conn = database.connect().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE langs AS SELECT * FROM (VALUES (1, 'Rust'), (2, 'Go')) t(id, name);
+             ATTACH ':memory:' AS other;
+             CREATE TABLE other.secrets AS SELECT 42 AS secret;",
+        )
+        .unwrap();
+        let engine: Arc<dyn UQueryEngine> =
+            Arc::new(DuckDbEngine::new(database, false, 2).unwrap());
+        let keys = serde_json::json!([
+            {"name": "analyst", "sha256": key_hash("analyst-key"), "ad_hoc_sql": true,
+             "catalogs": ["memory"], "max_rows": 1},
+            {"name": "reports", "sha256": key_hash("reports-key"), "queries": ["range"]},
+        ]);
+        let router = create_router(
+            engine,
+            RouterConfig {
+                api_keys: Some(keys.to_string()),
+                ..saved_queries_config(&[("range.sql", RANGE_QUERY), ("answer.sql", "SELECT 42")])
+            },
+        );
+        let get = http::Method::GET;
+        let post = http::Method::POST;
+
+        let response = auth_request(&router, get.clone(), "/health", None, "").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        for key in [None, Some("wrong-key")] {
+            let response = auth_request(&router, post.clone(), "/", key, "SELECT 1").await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+            assert_eq!(response.headers()[http::header::WWW_AUTHENTICATE], "Bearer");
+        }
+
+        let analyst = Some("analyst-key");
+        let response = auth_request(&router, post.clone(), "/", analyst, "FROM langs").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let rows: Value = serde_json::from_slice(&read_response(response).await).unwrap();
+        assert_eq!(rows, serde_json::json!([{"id": 1, "name": "Rust"}]));
+        for sql in ["FROM other.secrets", "FROM other.main.secrets"] {
+            for uri in ["/", "/describe"] {
+                let response = auth_request(&router, post.clone(), uri, analyst, sql).await;
+                assert_eq!(response.status(), StatusCode::FORBIDDEN, "{uri} {sql}");
+            }
+        }
+        let response = auth_request(&router, get.clone(), "/catalogs", analyst, "").await;
+        let catalogs: Value = serde_json::from_slice(&read_response(response).await).unwrap();
+        assert_eq!(catalogs.as_array().unwrap().len(), 1);
+        assert_eq!(catalogs[0]["name"], "memory");
+
+        let reports = Some("reports-key");
+        let response = auth_request(&router, post.clone(), "/", reports, "SELECT 1").await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = auth_request(&router, get.clone(), "/q", reports, "").await;
+        let listing: Value = serde_json::from_slice(&read_response(response).await).unwrap();
+        assert_eq!(listing.as_array().unwrap().len(), 1);
+        assert_eq!(listing[0]["name"], "range");
+        let response = auth_request(&router, get.clone(), "/q/range?bound=2", reports, "").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = auth_request(&router, get, "/q/answer", reports, "").await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn api_keys_file_reload_test() {
+        let file = std::env::temp_dir().join(format!("uquery-keys-{}.json", crate::web::new_id()));
+        let key = |name: &str| serde_json::json!({"name": name, "sha256": key_hash(name), "ad_hoc_sql": true});
+        std::fs::write(&file, serde_json::json!([key("first")]).to_string()).unwrap();
+        let router = create_router(
+            make_engine(false),
+            RouterConfig {
+                api_keys_file: Some(file.clone()),
+                api_keys_reload_interval: Duration::from_millis(50),
+                ..RouterConfig::default()
+            },
+        );
+        let query = |key| auth_request(&router, http::Method::POST, "/", Some(key), "SELECT 1");
+        assert_eq!(query("first").await.status(), StatusCode::OK);
+        assert_eq!(query("second").await.status(), StatusCode::UNAUTHORIZED);
+
+        std::fs::write(
+            &file,
+            serde_json::json!([key("second"), key("third")]).to_string(),
+        )
+        .unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(query("first").await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(query("second").await.status(), StatusCode::OK);
+
+        // an invalid file keeps the current keys
+        std::fs::write(&file, "[{\"name\": \"broken\"}]").unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(query("third").await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn rate_limit_test() {
+        let keys = serde_json::json!([
+            {"name": "dashboard", "sha256": key_hash("dashboard-key"), "ad_hoc_sql": true},
+            {"name": "batch", "sha256": key_hash("batch-key"), "ad_hoc_sql": true,
+             "rate_limit": 100, "max_concurrent_queries": 2},
+        ]);
+        let router = create_router(
+            make_engine(false),
+            RouterConfig {
+                api_keys: Some(keys.to_string()),
+                limits: Limits {
+                    rate: Some(0.1),
+                    burst: Some(2),
+                    max_concurrent: Some(1),
+                    ..Limits::default()
+                },
+                ..RouterConfig::default()
+            },
+        );
+        let query = |key| auth_request(&router, http::Method::POST, "/", Some(key), "SELECT 1");
+
+        // the response holds its query slot until its body is read or dropped
+        let streaming = query("dashboard-key").await;
+        assert_eq!(streaming.status(), StatusCode::OK);
+        let response = query("dashboard-key").await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[http::header::RETRY_AFTER], "1");
+        read_response(streaming).await;
+        assert_eq!(query("dashboard-key").await.status(), StatusCode::OK);
+
+        let response = query("dashboard-key").await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[http::header::RETRY_AFTER], "10");
+        let problem: Value = serde_json::from_slice(&read_response(response).await).unwrap();
+        assert_eq!(problem["title"], "Too Many Requests");
+        let response = auth_request(&router, http::Method::GET, "/health", None, "").await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let first = query("batch-key").await;
+        let second = query("batch-key").await;
+        assert_eq!(first.status(), StatusCode::OK);
+        assert_eq!(second.status(), StatusCode::OK);
+        assert_eq!(
+            query("batch-key").await.status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+    }
+
+    #[tokio::test]
+    async fn row_policy_test() {
+        let database = Database::open_in_memory().unwrap();
+        let conn = database.connect().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE orders AS SELECT * FROM
+             (VALUES (1, 'acme', 10), (2, 'acme', 20), (3, 'globex', 30)) t(id, tenant, amount);",
+        )
+        .unwrap();
+        let policies = serde_json::json!([
+            {"table": "main.orders", "filter": "tenant = :tenant"},
+            {"file": "tests/*.csv", "filter": "f_int = :csv.min"},
+        ]);
+        let engine: Arc<dyn UQueryEngine> = Arc::new(
+            DuckDbEngine::new(database, false, 2)
+                .unwrap()
+                .with_row_policies(RowPolicy::parse(&policies.to_string()).unwrap())
+                .unwrap(),
+        );
+        let keys = serde_json::json!([
+            {"name": "acme", "sha256": key_hash("acme-key"), "ad_hoc_sql": true,
+             "claims": {"tenant": "acme", "csv": {"min": 123}}},
+            {"name": "anonymous", "sha256": key_hash("anonymous-key"), "ad_hoc_sql": true},
+        ]);
+        let router = create_router(
+            engine,
+            RouterConfig {
+                api_keys: Some(keys.to_string()),
+                ..RouterConfig::default()
+            },
+        );
+        let post = http::Method::POST;
+        for (sql, count) in [
+            ("SELECT count(*) AS n FROM orders", 2),
+            (
+                "SELECT count(*) AS n FROM orders AS o WHERE o.amount > 10",
+                1,
+            ),
+            (
+                "SELECT count(*) AS n FROM orders x(a, b, c) WHERE x.b = 'globex'",
+                0,
+            ),
+            ("SELECT count(orders.id) AS n FROM memory.main.orders", 2),
+            (
+                "WITH t AS (SELECT * FROM orders) SELECT count(*) AS n FROM t",
+                2,
+            ),
+            ("SELECT count(*) AS n FROM (SELECT tenant FROM orders) s", 2),
+            ("SELECT (SELECT count(*) FROM orders) AS n", 2),
+            (
+                "SELECT count(*) AS n FROM range(5) r WHERE r.range IN (SELECT id FROM orders)",
+                2,
+            ),
+            ("SELECT count(*) AS n FROM 'tests/test.csv'", 1),
+            ("SELECT count(*) AS n FROM read_csv(['tests/test.csv'])", 1),
+        ] {
+            let response = auth_request(&router, post.clone(), "/", Some("acme-key"), sql).await;
+            assert_eq!(response.status(), StatusCode::OK, "{sql}");
+            let rows: Value = serde_json::from_slice(&read_response(response).await).unwrap();
+            assert_eq!(rows, serde_json::json!([{"n": count}]), "{sql}");
+        }
+        for (key, sql) in [
+            ("acme-key", "SELECT * FROM query_table('orders')"),
+            (
+                "acme-key",
+                "SELECT * FROM read_csv(concat('tests/', 'test.csv'))",
+            ),
+            ("anonymous-key", "SELECT * FROM orders"),
+        ] {
+            let response = auth_request(&router, post.clone(), "/", Some(key), sql).await;
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{sql}");
+        }
+        let response = auth_request(&router, post, "/", Some("anonymous-key"), "SELECT 1").await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn masking_policy_test() {
+        let database = Database::open_in_memory().unwrap();
+        let conn = database.connect().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE users AS SELECT * FROM
+             (VALUES (1, 'ada@example.com', '0612345678', 'acme')) t(id, email, phone, tenant);",
+        )
+        .unwrap();
+        let masks = serde_json::json!([
+            {"table": "users", "columns": ["email"], "mask": "hash", "exempt": {"roles": "pii"}},
+            {"table": "users", "column_pattern": "^ph", "mask": "partial", "visible": 2},
+            {"file": "tests/test.csv", "columns": ["f_str"], "mask": "null"},
+        ]);
+        let policies = serde_json::json!([{"table": "users", "filter": "tenant = :tenant"}]);
+        let engine: Arc<dyn UQueryEngine> = Arc::new(
+            DuckDbEngine::new(database, false, 2)
+                .unwrap()
+                .with_row_policies(RowPolicy::parse(&policies.to_string()).unwrap())
+                .unwrap()
+                .with_masking_policies(MaskingPolicy::parse(&masks.to_string()).unwrap()),
+        );
+        let keys = serde_json::json!([
+            {"name": "support", "sha256": key_hash("support-key"), "ad_hoc_sql": true,
+             "claims": {"tenant": "acme", "roles": ["support"]}},
+            {"name": "dpo", "sha256": key_hash("dpo-key"), "ad_hoc_sql": true,
+             "claims": {"tenant": "acme", "roles": ["support", "pii"]}},
+        ]);
+        let router = create_router(
+            engine,
+            RouterConfig {
+                api_keys: Some(keys.to_string()),
+                ..RouterConfig::default()
+            },
+        );
+        let post = http::Method::POST;
+        // the hex SHA-256 of the address, as for the API keys
+        let hash = key_hash("ada@example.com");
+        for (key, sql, expected) in [
+            (
+                "support-key",
+                "SELECT u.m AS e, u.p AS phone FROM users u(id, m, p)",
+                serde_json::json!([{"e": hash, "phone": "********78"}]),
+            ),
+            (
+                "support-key",
+                "SELECT count(*) AS n FROM users WHERE email = 'ada@example.com'",
+                serde_json::json!([{"n": 0}]),
+            ),
+            (
+                "dpo-key",
+                "WITH t AS (FROM users) SELECT email, phone FROM t",
+                serde_json::json!([{"email": "ada@example.com", "phone": "********78"}]),
+            ),
+            (
+                "support-key",
+                "SELECT count(f_str) AS n FROM read_csv('tests/test.csv')",
+                serde_json::json!([{"n": 0}]),
+            ),
+        ] {
+            let response = auth_request(&router, post.clone(), "/", Some(key), sql).await;
+            assert_eq!(response.status(), StatusCode::OK, "{sql}");
+            let rows: Value = serde_json::from_slice(&read_response(response).await).unwrap();
+            assert_eq!(rows, expected, "{sql}");
+        }
+        let sql = "SELECT * FROM read_text('tests/test.csv')";
+        let response = auth_request(&router, post, "/", Some("support-key"), sql).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn jwt_test() {
+        let signer = TestSigner::new("k1");
+        let dir = std::env::temp_dir().join(format!("uquery-jwt-{}", crate::web::new_id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("jwks.json"), signer.jwks().to_string()).unwrap();
+        std::fs::write(
+            dir.join("policies.json"),
+            serde_json::json!([
+                {"name": "analysts", "claims": {"roles": "analyst"}, "ad_hoc_sql": true},
+                {"name": "viewers", "claims": {"roles": "viewer"}},
+            ])
+            .to_string(),
+        )
+        .unwrap();
+        let router = create_router(
+            make_engine(false),
+            RouterConfig {
+                api_keys: Some(
+                    serde_json::json!([{"name": "ci", "sha256": key_hash("ci-key"), "ad_hoc_sql": true}])
+                        .to_string(),
+                ),
+                jwt: Some(JwtConfig {
+                    issuer: Some("https://idp.example.com".to_string()),
+                    audience: Some("uquery".to_string()),
+                    claims: vec!["tenant".to_string()],
+                    policies: Some(dir.join("policies.json")),
+                    ..JwtConfig::new(dir.join("jwks.json").display().to_string())
+                }),
+                ..RouterConfig::default()
+            },
+        );
+        let token = |roles: &str, exp: i64| {
+            signer.sign(serde_json::json!({
+                "iss": "https://idp.example.com", "aud": "uquery", "sub": "alice",
+                "tenant": "acme", "roles": [roles], "exp": expires_in(exp),
+            }))
+        };
+        for (credentials, status) in [
+            (token("analyst", 600), StatusCode::OK),
+            ("ci-key".to_string(), StatusCode::OK),
+            (token("analyst", -3600), StatusCode::UNAUTHORIZED),
+            (token("viewer", 600), StatusCode::FORBIDDEN),
+            (token("guest", 600), StatusCode::FORBIDDEN),
+        ] {
+            let response = auth_request(
+                &router,
+                http::Method::POST,
+                "/",
+                Some(&credentials),
+                "SELECT 1",
+            )
+            .await;
+            assert_eq!(response.status(), status, "{credentials}");
+        }
+
+        let response =
+            auth_request(&router, http::Method::POST, "/", Some("a.b.c"), "SELECT 1").await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()[http::header::WWW_AUTHENTICATE], "Bearer");
+        let problem: Value = serde_json::from_slice(&read_response(response).await).unwrap();
+        assert_eq!(problem["title"], "Unauthorized");
+    }
+
+    fn catalog_engine() -> Arc<dyn UQueryEngine> {
+        let database = Database::open_in_memory().unwrap();
+        let conn = database.connect().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE langs (id INTEGER NOT NULL, name VARCHAR);
+             COMMENT ON TABLE langs IS 'Languages';
+             COMMENT ON COLUMN langs.name IS 'Display name';
+             INSERT INTO langs VALUES (1, 'Rust'), (2, 'Go');
+             CREATE VIEW rust AS SELECT * FROM langs WHERE id = 1;",
+        )
+        .unwrap();
+        Arc::new(DuckDbEngine::new(database, false, 2).unwrap())
+    }
+
+    async fn catalog_request(engine: &Arc<dyn UQueryEngine>, uri: &str, accept: &str) -> Response {
+        create_router(Arc::clone(engine), RouterConfig::default())
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::GET)
+                    .uri(uri)
+                    .header(ACCEPT, accept)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap()
+    }
+
+    async fn catalog_json(engine: &Arc<dyn UQueryEngine>, uri: &str) -> Vec<Value> {
+        let response = catalog_request(engine, uri, "application/json").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        serde_json::from_slice(&read_response(response).await).unwrap()
+    }
+
+    #[tokio::test]
+    async fn catalog_tables_test() {
+        let engine = catalog_engine();
+        let catalogs = catalog_json(&engine, "/catalogs").await;
+        assert_eq!(catalogs.len(), 1);
+        assert_eq!(catalogs[0]["name"], "memory");
+
+        let schemas = catalog_json(&engine, "/schemas?catalog=memory").await;
+        assert!(schemas.iter().any(|schema| schema["name"] == "main"));
+        assert!(
+            catalog_json(&engine, "/schemas?catalog=other")
+                .await
+                .is_empty()
+        );
+
+        let tables = catalog_json(&engine, "/tables?schema=main").await;
+        assert_eq!(tables.len(), 2);
+        assert_eq!(tables[0]["name"], "langs");
+        assert_eq!(tables[0]["type"], "table");
+        assert_eq!(tables[0]["comment"], "Languages");
+        assert_eq!(tables[0]["estimated_rows"], 2);
+        assert_eq!(tables[1]["name"], "rust");
+        assert_eq!(tables[1]["type"], "view");
+    }
+
+    #[tokio::test]
+    async fn catalog_describe_table_test() {
+        let engine = catalog_engine();
+        let columns = catalog_json(&engine, "/tables/langs").await;
+        assert_eq!(columns.len(), 2);
+        assert_eq!(columns[0]["name"], "id");
+        assert_eq!(columns[0]["type"], "INTEGER");
+        assert_eq!(columns[0]["nullable"], false);
+        assert_eq!(columns[1]["name"], "name");
+        assert_eq!(columns[1]["nullable"], true);
+        assert_eq!(columns[1]["comment"], "Display name");
+        assert_eq!(catalog_json(&engine, "/tables/rust").await.len(), 2);
+
+        let response = catalog_request(&engine, "/tables/missing", "application/json").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = catalog_request(&engine, "/tables/missing", "text/csv").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let error: Value = serde_json::from_slice(&read_response(response).await).unwrap();
+        assert_eq!(error["detail"], "table [missing] does not exist");
+        let response =
+            catalog_request(&engine, "/tables/langs?schema=other", "application/json").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn catalog_arrow_test() -> Result<(), PolarsError> {
+        let engine = catalog_engine();
+        let response = catalog_request(
+            &engine,
+            "/tables",
+            QueryResponseFormat::Arrow.to_string().as_str(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let df = IpcStreamReader::new(Cursor::new(read_response(response).await)).finish()?;
+        assert_eq!(df.height(), 2);
+        assert_eq!(df.column("name")?.str()?.get(0).unwrap(), "langs");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cors_enabled_test() {
+        let builder = Request::builder()
+            .method(http::Method::OPTIONS)
+            .uri("/")
+            .header(ACCESS_CONTROL_ALLOW_METHODS, "POST")
+            .header(ORIGIN, "https://origin.com");
+
+        let database = Database::open_in_memory().unwrap();
+        let engine: Arc<dyn UQueryEngine> =
+            Arc::new(DuckDbEngine::new(database, false, 2).unwrap());
+        let response = create_router(
+            engine,
+            RouterConfig {
+                cors_enabled: true,
+                ..RouterConfig::default()
+            },
+        )
+        .oneshot(builder.body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(),
+            "*"
+        );
+        assert_eq!(
+            response
+                .headers()
+                .get(ACCESS_CONTROL_ALLOW_METHODS)
+                .unwrap(),
+            "*"
+        );
+    }
+
+    #[tokio::test]
+    async fn query_sql_error_test() {
+        let response = perform_json_request(
+            QueryRequest::new("bad command".to_string()),
+            QueryResponseFormat::Json(JsonOptions::default()),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let result = read_response(response).await;
+        let error: Value = serde_json::from_str(from_utf8(&*result).unwrap()).unwrap();
+        assert_eq!(error["status"].as_u64().unwrap(), 400);
+        assert_eq!(error["title"], "SQL Error");
+        assert!(!error["detail"].to_string().is_empty());
+    }
+
+    #[tokio::test]
+    async fn read_csv_test() {
+        let response = perform_json_request(
+            QueryRequest::new("select * from read_csv('tests/test.csv')".to_string()),
+            QueryResponseFormat::Json(JsonOptions::default()),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let result = read_response(response).await;
+        let response_string = from_utf8(&*result).unwrap();
+        let json_array: Vec<Value> = serde_json::from_str(response_string).unwrap();
+        assert_eq!(json_array.len(), 2);
+        assert_eq!(json_array[0].get("f_str").unwrap().as_str().unwrap(), "abc");
+        assert_eq!(json_array[0].get("f_int").unwrap().as_i64().unwrap(), 123);
+        assert_eq!(
+            json_array[0].get("f_float").unwrap().as_f64().unwrap(),
+            4.56
+        );
+    }
+
+    #[tokio::test]
+    async fn read_parquet_test() {
+        let response = perform_json_request(
+            QueryRequest::new("select * from 'tests/test.zstd.parquet'".to_string()),
+            QueryResponseFormat::Json(JsonOptions::default()),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let result = read_response(response).await;
+        let response_string = from_utf8(&*result).unwrap();
+        let json_array: Vec<Value> = serde_json::from_str(response_string).unwrap();
+
+        assert_eq!(json_array.len(), 2);
+        assert_eq!(json_array[0].get("f_str").unwrap().as_str().unwrap(), "abc");
+        assert_eq!(json_array[0].get("f_int").unwrap().as_i64().unwrap(), 123);
+        assert_eq!(
+            json_array[0].get("f_float").unwrap().as_f64().unwrap(),
+            4.56
+        );
+    }
+
+    #[tokio::test]
+    async fn read_json_test() {
+        let response = perform_json_request(
+            QueryRequest::new("select * from 'tests/test.jsonl'".to_string()),
+            QueryResponseFormat::Json(JsonOptions::default()),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let result = read_response(response).await;
+        let response_string = from_utf8(&*result).unwrap();
+        let json_array: Vec<Value> = serde_json::from_str(response_string).unwrap();
+
+        assert_eq!(json_array.len(), 2);
+        assert_eq!(json_array[0].get("f_str").unwrap().as_str().unwrap(), "abc");
+        assert_eq!(json_array[0].get("f_int").unwrap().as_i64().unwrap(), 123);
+        assert_eq!(
+            json_array[0].get("f_float").unwrap().as_f64().unwrap(),
+            4.56
+        );
+    }
+
+    #[tokio::test]
+    async fn text_plain_request_test() {
+        let response = perform_plain_text_request(
+            "select * from 'tests/test.jsonl'".to_string(),
+            QueryResponseFormat::Json(JsonOptions::default()),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let result = read_response(response).await;
+        let response_string = from_utf8(&*result).unwrap();
+        let json_array: Vec<Value> = serde_json::from_str(response_string).unwrap();
+
+        assert_eq!(json_array.len(), 2);
+        assert_eq!(json_array[0].get("f_str").unwrap().as_str().unwrap(), "abc");
+        assert_eq!(json_array[0].get("f_int").unwrap().as_i64().unwrap(), 123);
+        assert_eq!(
+            json_array[0].get("f_float").unwrap().as_f64().unwrap(),
+            4.56
+        );
+    }
+
+    #[tokio::test]
+    async fn read_jsonlines_test() {
+        let response = perform_json_request(
+            QueryRequest::new("select * from 'tests/test.jsonl'".to_string()),
+            QueryResponseFormat::JsonLINES(JsonOptions::default()),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let result = read_response(response).await;
+        let response_string = from_utf8(&*result).unwrap().lines().collect::<Vec<&str>>();
+        let json_first: Value = serde_json::from_str(response_string.get(0).unwrap()).unwrap();
+
+        assert_eq!(response_string.len(), 2);
+        assert_eq!(json_first.get("f_str").unwrap().as_str().unwrap(), "abc");
+        assert_eq!(json_first.get("f_int").unwrap().as_i64().unwrap(), 123);
+        assert_eq!(json_first.get("f_float").unwrap().as_f64().unwrap(), 4.56);
+    }
+
+    #[tokio::test]
+    /*
        The following macro table has been created in the tests/test.db DuckDB database file
        create macro table test() as select * from 'tests/test.zstd.parquet'
     */
@@ -423,16 +1940,20 @@ mod tests {
             .method(http::Method::POST)
             .uri("/")
             .header(CONTENT_TYPE, "application/json")
-            .header(ACCEPT, QueryResponseFormat::Json.to_string());
+            .header(
+                ACCEPT,
+                QueryResponseFormat::Json(JsonOptions::default()).to_string(),
+            );
 
-        let conn = Connection::open_in_memory().unwrap();
+        let database = Database::open_in_memory().unwrap();
+        let conn = database.connect().unwrap();
         conn.execute(
             format!("ATTACH 'tests/test.db' as {UQ_ATTACHED_DB_NAME};").as_str(),
             [],
         )
         .unwrap();
-        let engine: Arc<dyn UQueryEngine> = Arc::new(DuckDbEngine::new(conn, true, 2).unwrap());
-        let response = create_router(engine, false, None)
+        let engine: Arc<dyn UQueryEngine> = Arc::new(DuckDbEngine::new(database, true, 2).unwrap());
+        let response = create_router(engine, RouterConfig::default())
             .oneshot(builder.body(Body::from(json)).unwrap())
             .await
             .unwrap();
@@ -449,6 +1970,114 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn query_positional_params_test() {
+        let request: QueryRequest = serde_json::from_str(
+            r#"{"query": "SELECT $1 + 1 AS n, $2 AS s, $3 AS b, $4 IS NULL AS z", "params": [41, "it's", true, null]}"#,
+        )
+        .unwrap();
+        let response =
+            perform_json_request(request, QueryResponseFormat::Json(JsonOptions::default())).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let result = read_response(response).await;
+        assert_eq!(
+            from_utf8(&*result).unwrap(),
+            "[{\"n\":42,\"s\":\"it's\",\"b\":true,\"z\":true}]"
+        );
+    }
+
+    #[tokio::test]
+    async fn query_named_params_test() {
+        let request: QueryRequest = serde_json::from_value(serde_json::json!({
+            "query": "SELECT f_int, $day AS day, list_contains($ids::INTEGER[], 2) AS found FROM read_csv('tests/test.csv') WHERE f_str = $name",
+            "params": {"name": "abc", "day": {"type": "date", "value": "2024-01-31"}, "ids": [1, 2, 3]}
+        }))
+        .unwrap();
+        let response =
+            perform_json_request(request, QueryResponseFormat::Json(JsonOptions::default())).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let result = read_response(response).await;
+        let json_array: Vec<Value> = serde_json::from_str(from_utf8(&*result).unwrap()).unwrap();
+        assert_eq!(json_array.len(), 1);
+        assert_eq!(json_array[0]["f_int"].as_i64().unwrap(), 123);
+        assert_eq!(json_array[0]["day"].as_str().unwrap(), "2024-01-31");
+        assert!(json_array[0]["found"].as_bool().unwrap());
+    }
+
+    #[tokio::test]
+    async fn query_list_params_test() {
+        let items = serde_json::json!([
+            "it's",
+            "back\\slash\\",
+            "a, b",
+            "[x]",
+            "say \"hi\"",
+            "'quoted'",
+            "NULL",
+            " padded ",
+            null,
+        ]);
+        let request: QueryRequest = serde_json::from_value(serde_json::json!({
+            "query": "SELECT $items::VARCHAR[] AS items, $nested::VARCHAR[][] AS nested",
+            "params": {"items": items.clone(), "nested": [["a]", "b,c"], [], ["\\"]]}
+        }))
+        .unwrap();
+        let response =
+            perform_json_request(request, QueryResponseFormat::Json(JsonOptions::default())).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let rows: Value = serde_json::from_slice(&read_response(response).await).unwrap();
+        assert_eq!(rows[0]["items"], items);
+        assert_eq!(
+            rows[0]["nested"],
+            serde_json::json!([["a]", "b,c"], [], ["\\"]])
+        );
+    }
+
+    #[tokio::test]
+    async fn query_missing_named_param_test() {
+        let request: QueryRequest =
+            serde_json::from_str(r#"{"query": "SELECT $name AS name", "params": {"other": 1}}"#)
+                .unwrap();
+        let response =
+            perform_json_request(request, QueryResponseFormat::Json(JsonOptions::default())).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let result = read_response(response).await;
+        let error: Value = serde_json::from_str(from_utf8(&*result).unwrap()).unwrap();
+        assert_eq!(error["title"], "SQL Error");
+        assert!(error["detail"].as_str().unwrap().contains("$name"));
+    }
+
+    #[tokio::test]
+    async fn query_unexpected_params_test() {
+        for (request, detail) in [
+            (
+                serde_json::json!({"query": "SELECT $name", "params": {"name": "a", "other": 1}}),
+                "unknown parameter $other",
+            ),
+            (
+                serde_json::json!({"query": "SELECT $1", "params": [1, 2]}),
+                "the statement takes 1 parameter(s), 2 given",
+            ),
+            (
+                serde_json::json!({"query": "SELECT $1, $2", "params": [1]}),
+                "the statement takes 2 parameter(s), 1 given",
+            ),
+            (
+                serde_json::json!({"query": "SELECT $1"}),
+                "the statement takes 1 parameter(s), 0 given",
+            ),
+        ] {
+            let request: QueryRequest = serde_json::from_value(request).unwrap();
+            let response =
+                perform_json_request(request, QueryResponseFormat::Json(JsonOptions::default()))
+                    .await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{detail}");
+            let error: Value = serde_json::from_slice(&read_response(response).await).unwrap();
+            assert_eq!(error["title"], "SQL Error");
+            assert_eq!(error["detail"], detail);
+        }
+    }
+
     #[tokio::test]
     async fn query_timeout_test() {
         let engine: Arc<dyn UQueryEngine> = Arc::new(SlowEngine(Duration::from_millis(500)));
@@ -456,20 +2085,711 @@ mod tests {
             .method(http::Method::POST)
             .uri("/")
             .header(CONTENT_TYPE, "application/json")
-            .header(ACCEPT, QueryResponseFormat::Json.to_string())
+            .header(
+                ACCEPT,
+                QueryResponseFormat::Json(JsonOptions::default()).to_string(),
+            )
             .body(Body::from(
                 serde_json::to_string(&QueryRequest::new("SELECT 1".to_string())).unwrap(),
             ))
             .unwrap();
-        let response = create_router(engine, false, Some(Duration::from_millis(50)))
-            .oneshot(request)
+        let response = create_router(
+            engine,
+            RouterConfig {
+                query_timeout: Some(Duration::from_millis(50)),
+                ..RouterConfig::default()
+            },
+        )
+        .oneshot(request)
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::REQUEST_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn execution_timeout_before_first_batch_test() {
+        let response = create_router(
+            make_engine(false),
+            RouterConfig {
+                execution_timeout: Some(Duration::from_millis(200)),
+                ..RouterConfig::default()
+            },
+        )
+        .oneshot(
+            Request::builder()
+                .method(http::Method::POST)
+                .uri("/")
+                .header(CONTENT_TYPE, "text/plain")
+                .header(
+                    ACCEPT,
+                    QueryResponseFormat::Json(JsonOptions::default()).to_string(),
+                )
+                .body(Body::from(
+                    "SELECT count(*) FROM range(1000000000) a, range(1000000) b",
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::REQUEST_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn execution_timeout_mid_stream_test() {
+        let response = create_router(make_engine(false), RouterConfig::default())
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/")
+                    .header(CONTENT_TYPE, "text/plain")
+                    .header(
+                        ACCEPT,
+                        QueryResponseFormat::Csv(CsvOptions::default()).to_string(),
+                    )
+                    .header(EXECUTION_TIMEOUT_HEADER, "1")
+                    .header(TE, "trailers")
+                    .body(Body::from("SELECT * FROM range(5000000)"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(TRAILER).unwrap(),
+            &format!("{ERROR_TRAILER}, {TRUNCATED_TRAILER}")
+        );
+
+        // a slow client: the result can't be fully sent before the deadline
+        let mut body = response.into_body();
+        let first = next_frame(&mut body).await.unwrap();
+        assert!(first.is_data());
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        let mut trailers = None;
+        while let Some(frame) = next_frame(&mut body).await {
+            if let Ok(t) = frame.into_trailers() {
+                trailers = Some(t);
+            }
+        }
+        let trailers = trailers.expect("no error trailer");
+        let error: Value =
+            serde_json::from_str(trailers.get(ERROR_TRAILER).unwrap().to_str().unwrap()).unwrap();
+        assert_eq!(error["status"].as_u64().unwrap(), 408);
+        assert_eq!(error["title"], "Query Timeout");
+    }
+
+    fn failing_request(format: &QueryResponseFormat, trailers: bool) -> Request<Body> {
+        let mut request = Request::builder()
+            .method(http::Method::POST)
+            .uri("/")
+            .header(CONTENT_TYPE, "text/plain")
+            .header(ACCEPT, format.to_string());
+        if trailers {
+            request = request.header(TE, "trailers");
+        }
+        request.body(Body::from("SELECT 1")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn mid_stream_error_aborts_response_test() {
+        for format in [
+            QueryResponseFormat::Csv(CsvOptions::default()),
+            QueryResponseFormat::Json(JsonOptions::default()),
+            QueryResponseFormat::Arrow,
+        ] {
+            let response = create_router(Arc::new(FailingEngine), RouterConfig::default())
+                .oneshot(failing_request(&format, false))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert!(response.headers().get(TRAILER).is_none());
+
+            let mut body = response.into_body();
+            let mut aborted = false;
+            while let Some(frame) =
+                std::future::poll_fn(|cx| Pin::new(&mut body).poll_frame(cx)).await
+            {
+                match frame {
+                    Ok(frame) => assert!(frame.is_data(), "{format}: unexpected trailers"),
+                    Err(_) => {
+                        aborted = true;
+                        break;
+                    }
+                }
+            }
+            assert!(aborted, "{format}: truncated response ended cleanly");
+        }
+    }
+
+    #[tokio::test]
+    async fn mid_stream_error_jsonlines_record_test() {
+        let response = create_router(Arc::new(FailingEngine), RouterConfig::default())
+            .oneshot(failing_request(
+                &QueryResponseFormat::JsonLINES(JsonOptions::default()),
+                false,
+            ))
             .await
             .unwrap();
-        assert_eq!(response.status(), StatusCode::REQUEST_TIMEOUT);
+        assert_eq!(response.status(), StatusCode::OK);
+        let result = read_response(response).await;
+        let lines: Vec<Value> = from_utf8(&result)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[2]["n"].as_i64().unwrap(), 3);
+        assert_eq!(lines[3]["error"]["status"].as_u64().unwrap(), 400);
+        assert_eq!(lines[3]["error"]["title"], "SQL Error");
+        assert_eq!(lines[3]["error"]["detail"], "boom");
+    }
+
+    #[tokio::test]
+    async fn mid_stream_error_trailer_test() {
+        let response = create_router(Arc::new(FailingEngine), RouterConfig::default())
+            .oneshot(failing_request(
+                &QueryResponseFormat::Csv(CsvOptions::default()),
+                true,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(TRAILER).unwrap(),
+            &format!("{ERROR_TRAILER}, {TRUNCATED_TRAILER}")
+        );
+
+        let mut body = response.into_body();
+        let mut data = Vec::new();
+        let mut trailers = None;
+        while let Some(frame) = next_frame(&mut body).await {
+            match frame.into_data() {
+                Ok(chunk) => data.extend_from_slice(&chunk),
+                Err(frame) => trailers = frame.into_trailers().ok(),
+            }
+        }
+        assert_eq!(from_utf8(&data).unwrap(), "n\n1\n2\n3\n");
+        let trailers = trailers.expect("no error trailer");
+        let error: Value =
+            serde_json::from_str(trailers.get(ERROR_TRAILER).unwrap().to_str().unwrap()).unwrap();
+        assert_eq!(error["status"].as_u64().unwrap(), 400);
+        assert_eq!(error["detail"], "boom");
+    }
+
+    #[tokio::test]
+    async fn max_rows_truncation_test() {
+        let keys = serde_json::json!([
+            {"name": "analyst", "sha256": key_hash("analyst-key"), "ad_hoc_sql": true,
+             "max_rows": 2},
+        ]);
+        let router = create_router(
+            make_engine(false),
+            RouterConfig {
+                api_keys: Some(keys.to_string()),
+                ..RouterConfig::default()
+            },
+        );
+        let request = |accept: &str, sql: &str| {
+            Request::builder()
+                .method(http::Method::POST)
+                .uri("/")
+                .header(CONTENT_TYPE, "text/plain")
+                .header(ACCEPT, accept)
+                .header(http::header::AUTHORIZATION, "Bearer analyst-key")
+                .header(TE, "trailers")
+                .body(Body::from(sql.to_string()))
+                .unwrap()
+        };
+        for (sql, truncated) in [
+            ("SELECT * FROM range(10)", true),
+            ("SELECT * FROM range(2)", false),
+        ] {
+            let response = router
+                .clone()
+                .oneshot(request("text/csv", sql))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let mut body = response.into_body();
+            let mut data = Vec::new();
+            let mut trailers = None;
+            while let Some(frame) = next_frame(&mut body).await {
+                match frame.into_data() {
+                    Ok(chunk) => data.extend_from_slice(&chunk),
+                    Err(frame) => trailers = frame.into_trailers().ok(),
+                }
+            }
+            assert_eq!(from_utf8(&data).unwrap(), "range\n0\n1\n");
+            let flagged = trailers
+                .as_ref()
+                .and_then(|trailers| trailers.get(TRUNCATED_TRAILER))
+                .is_some_and(|value| value == "true");
+            assert_eq!(flagged, truncated, "{sql}");
+
+            let response = router
+                .clone()
+                .oneshot(request(CONTENT_TYPE_ENVELOPE, sql))
+                .await
+                .unwrap();
+            let envelope: Value = serde_json::from_slice(&read_response(response).await).unwrap();
+            assert_eq!(envelope["stats"]["rows"], 2);
+            assert_eq!(envelope["stats"]["truncated"], truncated, "{sql}");
+        }
+    }
+
+    async fn next_frame(body: &mut Body) -> Option<Frame<Bytes>> {
+        std::future::poll_fn(|cx| Pin::new(&mut *body).poll_frame(cx))
+            .await
+            .map(|frame| frame.unwrap())
+    }
+
+    #[tokio::test]
+    async fn job_lifecycle_test() {
+        let router = create_router(make_engine(false), RouterConfig::default());
+        let job = submit_job(&router, TEST_QUERY).await;
+        assert_eq!(job["status"], "succeeded");
+        assert_eq!(job["rows"].as_u64().unwrap(), 1);
+        let id = job["id"].as_str().unwrap();
+
+        // the stored result can be fetched several times, in any format
+        for (format, expected) in [
+            (
+                QueryResponseFormat::Csv(CsvOptions::default()),
+                "Id,Name,Description\n1,Rust,\"Safe, concurrent, performant systems language\"\n",
+            ),
+            (
+                QueryResponseFormat::Json(JsonOptions::default()),
+                "[{\"Id\":1,\"Name\":\"Rust\",\"Description\":\"Safe, concurrent, performant systems language\"}]",
+            ),
+        ] {
+            let response = router
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .uri(format!("/jobs/{id}/result"))
+                        .header(ACCEPT, format.to_string())
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let result = read_response(response).await;
+            assert_eq!(from_utf8(&*result).unwrap(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn job_failed_test() {
+        let router = create_router(make_engine(false), RouterConfig::default());
+        let job = submit_job(&router, "bad command").await;
+        assert_eq!(job["status"], "failed");
+        assert!(!job["error"].as_str().unwrap().is_empty());
+
+        let response = router
+            .oneshot(
+                Request::builder()
+                    .uri(format!("/jobs/{}/result", job["id"].as_str().unwrap()))
+                    .header(
+                        ACCEPT,
+                        QueryResponseFormat::Json(JsonOptions::default()).to_string(),
+                    )
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn job_not_found_test() {
+        let response = create_router(make_engine(false), RouterConfig::default())
+            .oneshot(
+                Request::builder()
+                    .uri("/jobs/unknown")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    /// Submit `sql` as an asynchronous job and poll its status until it is finished.
+    async fn submit_job(router: &Router, sql: &str) -> Value {
+        let response = router
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/jobs")
+                    .header(CONTENT_TYPE, "text/plain")
+                    .body(Body::from(sql.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let location = response
+            .headers()
+            .get(LOCATION)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+        for _ in 0..100 {
+            let response = router
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .uri(&location)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let job: Value = serde_json::from_slice(&read_response(response).await).unwrap();
+            if job["status"] == "succeeded" || job["status"] == "failed" {
+                return job;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("job {location} did not finish");
+    }
+
+    #[tokio::test]
+    async fn job_owner_test() {
+        let keys = serde_json::json!([
+            {"name": "alice", "sha256": key_hash("alice-key"), "ad_hoc_sql": true},
+            {"name": "bob", "sha256": key_hash("bob-key"), "ad_hoc_sql": true},
+        ]);
+        let router = create_router(
+            make_engine(false),
+            RouterConfig {
+                api_keys: Some(keys.to_string()),
+                ..RouterConfig::default()
+            },
+        );
+        let (alice, bob) = (Some("alice-key"), Some("bob-key"));
+        let get = http::Method::GET;
+        let response = auth_request(
+            &router,
+            http::Method::POST,
+            "/jobs",
+            alice,
+            "SELECT 42 AS answer",
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let job: Value = serde_json::from_slice(&read_response(response).await).unwrap();
+        let id = job["id"].as_str().unwrap().to_string();
+
+        // the job of another caller does not exist
+        for uri in [format!("/jobs/{id}"), format!("/jobs/{id}/result")] {
+            let response = auth_request(&router, get.clone(), &uri, bob, "").await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{uri}");
+        }
+        let uri = format!("/queries/{id}");
+        let response = auth_request(&router, http::Method::DELETE, &uri, bob, "").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let job = finished_job(&router, &id, alice).await;
+        assert_eq!(job["status"], "succeeded");
+        let uri = format!("/jobs/{id}/result");
+        let response = auth_request(&router, get, &uri, alice, "").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let rows: Value = serde_json::from_slice(&read_response(response).await).unwrap();
+        assert_eq!(rows, serde_json::json!([{"answer": 42}]));
+    }
+
+    #[tokio::test]
+    async fn job_timeout_test() {
+        let keys = serde_json::json!([
+            {"name": "hasty", "sha256": key_hash("hasty-key"), "ad_hoc_sql": true, "timeout": 0.2},
+        ]);
+        let router = create_router(
+            make_engine(false),
+            RouterConfig {
+                api_keys: Some(keys.to_string()),
+                ..RouterConfig::default()
+            },
+        );
+        let key = Some("hasty-key");
+        let response = auth_request(
+            &router,
+            http::Method::POST,
+            "/jobs",
+            key,
+            "SELECT count(*) FROM range(1000000000) a, range(1000000) b",
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let job: Value = serde_json::from_slice(&read_response(response).await).unwrap();
+        let job = finished_job(&router, job["id"].as_str().unwrap(), key).await;
+        assert_eq!(job["status"], "failed");
+        assert!(
+            job["error"].as_str().unwrap().contains("timeout"),
+            "{}",
+            job["error"]
+        );
+    }
+
+    /// Poll job `id` with `key` until it ends.
+    async fn finished_job(router: &Router, id: &str, key: Option<&str>) -> Value {
+        for _ in 0..100 {
+            let uri = format!("/jobs/{id}");
+            let response = auth_request(router, http::Method::GET, &uri, key, "").await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let job: Value = serde_json::from_slice(&read_response(response).await).unwrap();
+            if job["status"] == "succeeded" || job["status"] == "failed" {
+                return job;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("job {id} did not finish");
+    }
+
+    #[tokio::test]
+    async fn cancel_query_test() {
+        // a single connection: the follow-up query only succeeds if it was released clean
+        let engine: Arc<dyn UQueryEngine> =
+            Arc::new(DuckDbEngine::new(Database::open_in_memory().unwrap(), false, 1).unwrap());
+        let router = create_router(engine, RouterConfig::default());
+        let long_query = tokio::spawn(
+            router.clone().oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/")
+                    .header(CONTENT_TYPE, "text/plain")
+                    .header(
+                        ACCEPT,
+                        QueryResponseFormat::Json(JsonOptions::default()).to_string(),
+                    )
+                    .header(QUERY_ID_HEADER, "long-query")
+                    .body(Body::from(
+                        "SELECT count(*) FROM range(1000000000) a, range(1000000) b",
+                    ))
+                    .unwrap(),
+            ),
+        );
+
+        let mut status = StatusCode::NOT_FOUND;
+        for _ in 0..100 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let response = router
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .method(http::Method::DELETE)
+                        .uri("/queries/long-query")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            status = response.status();
+            if status == StatusCode::NO_CONTENT {
+                break;
+            }
+        }
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let response = tokio::time::timeout(Duration::from_secs(10), long_query)
+            .await
+            .expect("query was not interrupted")
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let json = serde_json::to_string(&QueryRequest::new(TEST_QUERY.to_string())).unwrap();
+        let response = router
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/")
+                    .header(CONTENT_TYPE, "application/json")
+                    .header(
+                        ACCEPT,
+                        QueryResponseFormat::Csv(CsvOptions::default()).to_string(),
+                    )
+                    .body(Body::from(json))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().contains_key(QUERY_ID_HEADER));
+        let result = read_response(response).await;
+        assert_eq!(
+            from_utf8(&*result).unwrap(),
+            "Id,Name,Description\n1,Rust,\"Safe, concurrent, performant systems language\"\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn pool_queue_test() {
+        // a single connection, for which a single query may wait up to a second
+        let engine = DuckDbEngine::new(Database::open_in_memory().unwrap(), false, 1)
+            .unwrap()
+            .with_pool_queue(PoolQueue {
+                max_waiting: Some(1),
+                timeout: Some(Duration::from_secs(1)),
+            });
+        let router = create_router(Arc::new(engine), RouterConfig::default());
+        let query = |sql: &'static str, id: &str| {
+            router.clone().oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/")
+                    .header(CONTENT_TYPE, "text/plain")
+                    .header(QUERY_ID_HEADER, id)
+                    .body(Body::from(sql))
+                    .unwrap(),
+            )
+        };
+        let long_query = tokio::spawn(query(
+            "SELECT count(*) FROM range(1000000000) a, range(1000000) b",
+            "long-query",
+        ));
+
+        // wait for the long query to hold the connection, then saturate the queue
+        let mut status = StatusCode::OK;
+        for attempt in 0..100 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            status = query("SELECT 42", &format!("probe-{attempt}"))
+                .await
+                .unwrap()
+                .status();
+            if status != StatusCode::OK {
+                break;
+            }
+        }
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let (first, second) =
+            tokio::join!(query("SELECT 42", "first"), query("SELECT 42", "second"));
+        let mut details = Vec::new();
+        for response in [first.unwrap(), second.unwrap()] {
+            assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+            assert_eq!(response.headers()[http::header::RETRY_AFTER], "1");
+            let problem: Value = serde_json::from_slice(&read_response(response).await).unwrap();
+            details.push(problem["detail"].as_str().unwrap().to_string());
+        }
+        details.sort();
+        assert_eq!(
+            details,
+            [
+                "all 1 connections are busy and 1 queries are already waiting",
+                "no connection became available within 1s",
+            ]
+        );
+
+        let cancel = Request::builder()
+            .method(http::Method::DELETE)
+            .uri("/queries/long-query")
+            .body(Body::empty())
+            .unwrap();
+        let response = router.clone().oneshot(cancel).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        long_query.await.unwrap().unwrap();
+        let response = query("SELECT 42", "after").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn cancel_unknown_query_test() {
+        let response = create_router(make_engine(false), RouterConfig::default())
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::DELETE)
+                    .uri("/queries/unknown")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn metrics_test() {
+        let router = create_router(make_engine(false), RouterConfig::default());
+        let request = |method: http::Method, uri: &str, sql: &'static str| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(CONTENT_TYPE, "text/plain")
+                .header(ACCEPT, "text/csv")
+                .body(Body::from(sql))
+                .unwrap()
+        };
+        let response = router
+            .clone()
+            .oneshot(request(http::Method::POST, "/", "SELECT * FROM range(3)"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        read_response(response).await;
+        let response = router
+            .clone()
+            .oneshot(request(
+                http::Method::POST,
+                "/",
+                "SELECT missing FROM range(3)",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = router
+            .clone()
+            .oneshot(request(http::Method::GET, "/metrics", ""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[CONTENT_TYPE],
+            "text/plain; version=0.0.4"
+        );
+        let metrics = String::from_utf8(read_response(response).await).unwrap();
+        for expected in [
+            "uquery_http_requests_total{route=\"/\",format=\"text/csv\",status=\"200\"}",
+            "uquery_http_request_duration_seconds_bucket{route=\"/\",format=\"application/problem+json\",status=\"400\",le=",
+            "uquery_query_first_batch_seconds_bucket{format=\"text/csv\",le=",
+            "uquery_query_duration_seconds_count{format=\"text/csv\"}",
+            "uquery_rows_streamed_total{format=\"text/csv\"}",
+            "uquery_bytes_streamed_total{format=\"text/csv\"}",
+            "uquery_query_errors_total{class=\"Binder Error\"}",
+            "uquery_pool_connections ",
+            "uquery_pool_idle_connections ",
+            "uquery_duckdb_memory_bytes{tag=\"BASE_TABLE\"}",
+        ] {
+            assert!(
+                metrics.contains(expected),
+                "{expected} missing from\n{metrics}"
+            );
+        }
+
+        // with a port of their own, the metrics are not served with the queries
+        let config = RouterConfig {
+            metrics: false,
+            ..RouterConfig::default()
+        };
+        let response = create_router(make_engine(false), config)
+            .oneshot(request(http::Method::GET, "/metrics", ""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = crate::web::metrics::create_admin_router(make_engine(false))
+            .oneshot(request(http::Method::GET, "/metrics", ""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
     }
 
     fn make_engine(attached: bool) -> Arc<dyn UQueryEngine> {
-        Arc::new(DuckDbEngine::new(Connection::open_in_memory().unwrap(), attached, 2).unwrap())
+        Arc::new(DuckDbEngine::new(Database::open_in_memory().unwrap(), attached, 2).unwrap())
     }
 
     async fn perform_json_request(request: QueryRequest, format: QueryResponseFormat) -> Response {
@@ -491,7 +2811,7 @@ mod tests {
         if compress {
             builder = builder.header(ACCEPT_ENCODING, "gzip");
         }
-        create_router(make_engine(false), false, None)
+        create_router(make_engine(false), RouterConfig::default())
             .oneshot(builder.body(Body::from(json)).unwrap())
             .await
             .unwrap()
@@ -503,7 +2823,7 @@ mod tests {
             .uri("/")
             .header(CONTENT_TYPE, "text/plain")
             .header(ACCEPT, format.to_string());
-        create_router(make_engine(false), false, None)
+        create_router(make_engine(false), RouterConfig::default())
             .oneshot(builder.body(Body::from(sql)).unwrap())
             .await
             .unwrap()
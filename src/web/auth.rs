@@ -0,0 +1,429 @@
+use crate::core::engine::QueryScope;
+use crate::core::error::UQueryError;
+use crate::web::jwt::JwtValidator;
+use crate::web::reload::{Fingerprint, Loaded, Reloadable, fingerprint};
+use axum::extract::{FromRequestParts, Request, State};
+use axum::http::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::{debug, info};
+
+/// Request header carrying an API key, as an alternative to `Authorization: Bearer`.
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+/// What a caller may do, granted by its API key or by the policy matching its token.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct Grants {
+    /// Whether the caller may run its own SQL, or only the saved queries.
+    #[serde(default)]
+    pub ad_hoc_sql: bool,
+    /// Saved queries the caller may run, every query when absent.
+    pub queries: Option<Vec<String>>,
+    /// Catalogs the caller may read and list, every catalog when absent.
+    pub catalogs: Option<Vec<String>>,
+    /// Rows returned at most by a query, the result being truncated past them.
+    pub max_rows: Option<u64>,
+    /// Longest a query may run, in seconds, lowering the server timeouts.
+    pub timeout: Option<f64>,
+    /// Requests per second allowed, replacing the server rate limit.
+    pub rate_limit: Option<f64>,
+    /// Queries run at the same time at most, replacing the server limit.
+    pub max_concurrent_queries: Option<usize>,
+}
+
+impl Grants {
+    /// Every permission, without any limit.
+    pub fn all() -> Self {
+        Self {
+            ad_hoc_sql: true,
+            ..Self::default()
+        }
+    }
+
+    /// Check the grants of an entry named `owner` in a configuration file, refusing
+    /// the `unknown` fields left over by its deserialization.
+    pub(crate) fn validate(
+        &self,
+        owner: &str,
+        unknown: &BTreeMap<String, Value>,
+    ) -> Result<(), String> {
+        if let Some(field) = unknown.keys().next() {
+            return Err(format!("[{owner}]: unknown field {field}"));
+        }
+        if self
+            .timeout
+            .is_some_and(|secs| Duration::try_from_secs_f64(secs).is_err() || secs <= 0.0)
+        {
+            return Err(format!(
+                "[{owner}]: timeout must be a positive number of seconds"
+            ));
+        }
+        if self
+            .rate_limit
+            .is_some_and(|rate| !rate.is_finite() || rate <= 0.0)
+        {
+            return Err(format!("[{owner}]: rate_limit must be a positive number"));
+        }
+        if self.max_concurrent_queries == Some(0) {
+            return Err(format!(
+                "[{owner}]: max_concurrent_queries must be at least 1"
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The authenticated caller of a request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Principal {
+    /// Name of the API key or subject of the token, logged with the requests.
+    pub name: String,
+    /// The name prefixed by the kind of credentials, `key:<name>` or `jwt:<sub>`, so
+    /// that a token can't pass for an API key. It owns the queries and jobs of the
+    /// caller, and its limits.
+    pub id: String,
+    pub grants: Grants,
+    /// Claims of the caller's token exposed to the query pipeline, by name.
+    pub claims: BTreeMap<String, Value>,
+}
+
+/// An API key of the keys file. Only the SHA-256 of the key is stored.
+#[derive(Deserialize)]
+struct ApiKey {
+    name: String,
+    /// Hex SHA-256 of the key.
+    sha256: String,
+    /// Attributes of the key for the row policies, like the claims of a token.
+    #[serde(default)]
+    claims: BTreeMap<String, Value>,
+    #[serde(flatten)]
+    grants: Grants,
+    #[serde(flatten)]
+    unknown: BTreeMap<String, Value>,
+}
+
+/// Hex SHA-256 of `key`, as stored in the keys file.
+pub fn key_hash(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Parse a JSON array of API keys into their principals, by hash.
+fn parse_keys(json: &str) -> Result<HashMap<String, Arc<Principal>>, String> {
+    let keys: Vec<ApiKey> = serde_json::from_str(json).map_err(|e| e.to_string())?;
+    let mut by_hash = HashMap::with_capacity(keys.len());
+    for key in keys {
+        let hash = key.sha256.trim().to_ascii_lowercase();
+        if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("[{}]: sha256 must be 64 hex digits", key.name));
+        }
+        key.grants.validate(&key.name, &key.unknown)?;
+        let principal = Principal {
+            id: format!("key:{}", key.name),
+            name: key.name,
+            grants: key.grants,
+            claims: key.claims,
+        };
+        if let Some(duplicate) = by_hash.insert(hash, Arc::new(principal)) {
+            return Err(format!(
+                "[{}]: the same key is declared twice",
+                duplicate.name
+            ));
+        }
+    }
+    Ok(by_hash)
+}
+
+/// The API keys allowed to query the server, reloaded when their file changes.
+pub struct ApiKeys {
+    file: Option<PathBuf>,
+    keys: RwLock<HashMap<String, Arc<Principal>>>,
+    loaded: Loaded<Fingerprint>,
+}
+
+impl ApiKeys {
+    /// Keys given as the JSON array of a keys file, never reloaded.
+    pub fn parse(json: &str) -> Result<Self, String> {
+        Ok(Self {
+            file: None,
+            keys: RwLock::new(parse_keys(json)?),
+            loaded: Loaded::default(),
+        })
+    }
+
+    /// Keys read from a JSON file, reloaded by [`watch`](crate::web::reload::watch) when
+    /// it changes.
+    pub fn load(file: PathBuf) -> Result<Self, String> {
+        let keys = Self {
+            file: Some(file),
+            keys: RwLock::new(HashMap::new()),
+            loaded: Loaded::default(),
+        };
+        keys.reload_if_changed()?;
+        Ok(keys)
+    }
+
+    fn authenticate(&self, key: &str) -> Option<Arc<Principal>> {
+        self.keys.read().unwrap().get(&key_hash(key)).cloned()
+    }
+}
+
+impl Reloadable for ApiKeys {
+    const NAME: &'static str = "API keys";
+
+    /// Reload the keys if their file changed. An invalid file leaves the current keys
+    /// in place.
+    fn reload_if_changed(&self) -> Result<(), String> {
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+        let metadata = fs::metadata(file).map_err(|e| format!("{}: {e}", file.display()))?;
+        self.loaded.load_if_changed(fingerprint(&metadata), |_| {
+            let json = fs::read_to_string(file).map_err(|e| format!("{}: {e}", file.display()))?;
+            let keys = parse_keys(&json).map_err(|e| format!("{}: {e}", file.display()))?;
+            info!("loaded {} API keys from {}", keys.len(), file.display());
+            *self.keys.write().unwrap() = keys;
+            Ok(())
+        })
+    }
+}
+
+/// The ways callers authenticate: API keys, JSON Web Tokens, or both.
+pub struct Authenticator {
+    pub api_keys: Option<Arc<ApiKeys>>,
+    pub jwt: Option<Arc<JwtValidator>>,
+}
+
+impl Authenticator {
+    /// The principal of the credentials of `headers`. Bearer tokens shaped like a JWT
+    /// are validated as such when JWTs are accepted, as API keys otherwise.
+    async fn authenticate(&self, headers: &HeaderMap) -> Result<Arc<Principal>, UQueryError> {
+        let credentials = credentials(headers).ok_or_else(|| {
+            unauthorized("credentials are required, as Authorization: Bearer or X-API-Key")
+        })?;
+        if let Credentials::Bearer(token) = credentials
+            && let Some(jwt) = &self.jwt
+            && token.split('.').count() == 3
+        {
+            return jwt.validate(token).await.map(Arc::new);
+        }
+        let key = match credentials {
+            Credentials::Bearer(key) | Credentials::ApiKey(key) => key,
+        };
+        self.api_keys
+            .as_ref()
+            .and_then(|keys| keys.authenticate(key))
+            .ok_or_else(|| unauthorized("invalid API key"))
+    }
+}
+
+enum Credentials<'a> {
+    Bearer(&'a str),
+    ApiKey(&'a str),
+}
+
+/// The token of `Authorization: Bearer <token>`, or the key of the `x-api-key` header.
+fn credentials(headers: &HeaderMap) -> Option<Credentials<'_>> {
+    let bearer = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+        .map(|(_, token)| token.trim())
+        .filter(|token| !token.is_empty());
+    match bearer {
+        Some(token) => Some(Credentials::Bearer(token)),
+        None => headers
+            .get(API_KEY_HEADER)?
+            .to_str()
+            .ok()
+            .map(str::trim)
+            .filter(|key| !key.is_empty())
+            .map(Credentials::ApiKey),
+    }
+}
+
+/// `401 Unauthorized` for missing or invalid credentials.
+pub(crate) fn unauthorized(detail: &str) -> UQueryError {
+    UQueryError {
+        status_code: StatusCode::UNAUTHORIZED.as_u16(),
+        title: "Unauthorized".to_string(),
+        detail: detail.to_string(),
+    }
+}
+
+/// Middleware refusing the requests without valid credentials with `401 Unauthorized`,
+/// or `403 Forbidden` when no policy grants access to a valid token. The principal is
+/// made available to the handlers as a [`Caller`].
+pub(crate) async fn authenticate(
+    State(authenticator): State<Arc<Authenticator>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let principal = match authenticator.authenticate(request.headers()).await {
+        Ok(principal) => principal,
+        Err(error) if error.status_code == StatusCode::UNAUTHORIZED.as_u16() => {
+            return ([(WWW_AUTHENTICATE, "Bearer")], error).into_response();
+        }
+        Err(error) => return error.into_response(),
+    };
+    debug!(
+        "{} {} as [{}]",
+        request.method(),
+        request.uri(),
+        principal.name
+    );
+    request.extensions_mut().insert(principal);
+    next.run(request).await
+}
+
+/// Middleware of the endpoints taking SQL from the client: `403 Forbidden` for the
+/// keys only allowed to run saved queries.
+pub(crate) async fn require_ad_hoc_sql(caller: Caller, request: Request, next: Next) -> Response {
+    match caller.0 {
+        Some(principal) if !principal.grants.ad_hoc_sql => UQueryError {
+            status_code: StatusCode::FORBIDDEN.as_u16(),
+            title: "Ad-hoc SQL Not Allowed".to_string(),
+            detail: format!(
+                "[{}] may only run saved queries, listed by GET /q",
+                principal.name
+            ),
+        }
+        .into_response(),
+        _ => next.run(request).await,
+    }
+}
+
+/// The principal of the request, `None` when authentication is disabled.
+pub(crate) struct Caller(Option<Arc<Principal>>);
+
+impl<S: Send + Sync> FromRequestParts<S> for Caller {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Infallible> {
+        Ok(Caller(parts.extensions.get::<Arc<Principal>>().cloned()))
+    }
+}
+
+impl Caller {
+    /// What the queries of the caller may read and return.
+    pub fn scope(&self) -> QueryScope {
+        match &self.0 {
+            Some(principal) => QueryScope {
+                catalogs: principal.grants.catalogs.clone(),
+                max_rows: principal.grants.max_rows,
+                timeout: principal.grants.timeout.map(Duration::from_secs_f64),
+                claims: principal.claims.clone(),
+            },
+            None => QueryScope::default(),
+        }
+    }
+
+    /// Identifier of the caller, owning the queries and jobs it starts. `None` when
+    /// authentication is disabled.
+    pub fn owner(&self) -> Option<String> {
+        self.0.as_ref().map(|principal| principal.id.clone())
+    }
+
+    /// Catalogs the caller may list, every catalog when `None`.
+    pub fn catalogs(&self) -> Option<&[String]> {
+        self.0.as_ref()?.grants.catalogs.as_deref()
+    }
+
+    /// Whether the caller may run the saved query `name`.
+    pub fn may_run(&self, name: &str) -> bool {
+        self.0
+            .as_ref()
+            .and_then(|principal| principal.grants.queries.as_ref())
+            .is_none_or(|queries| queries.iter().any(|query| query == name))
+    }
+
+    /// `403 Forbidden` for a saved query the caller may not run.
+    pub fn forbidden_query(&self, name: &str) -> UQueryError {
+        let principal = self
+            .0
+            .as_ref()
+            .map_or("", |principal| principal.name.as_str());
+        UQueryError {
+            status_code: StatusCode::FORBIDDEN.as_u16(),
+            title: "Query Not Allowed".to_string(),
+            detail: format!("[{principal}] may not run query [{name}]"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    const KEY_HASH: &str = "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b";
+
+    #[test]
+    fn keys_are_found_by_hash() {
+        assert_eq!(key_hash("secret"), KEY_HASH);
+        let keys = ApiKeys::parse(&format!(
+            r#"[{{"name": "reports", "sha256": "{}", "queries": ["daily"], "timeout": 5,
+                "claims": {{"tenant": "acme"}}}}]"#,
+            KEY_HASH.to_uppercase()
+        ))
+        .unwrap();
+        let key = keys.authenticate("secret").unwrap();
+        assert_eq!(key.name, "reports");
+        assert!(!key.grants.ad_hoc_sql);
+        assert_eq!(key.claims["tenant"], "acme");
+        assert!(keys.authenticate("other").is_none());
+        let caller = Caller(Some(key));
+        assert_eq!(caller.owner().as_deref(), Some("key:reports"));
+        assert!(caller.may_run("daily"));
+        assert!(!caller.may_run("weekly"));
+        assert!(Caller(None).may_run("weekly"));
+    }
+
+    #[test]
+    fn invalid_keys_are_rejected() {
+        for json in [
+            r#"[{"name": "short", "sha256": "abc"}]"#,
+            &format!(r#"[{{"name": "typo", "sha256": "{KEY_HASH}", "ad_hoc": true}}]"#),
+            &format!(r#"[{{"name": "slow", "sha256": "{KEY_HASH}", "timeout": -1}}]"#),
+            &format!(r#"[{{"name": "idle", "sha256": "{KEY_HASH}", "rate_limit": 0}}]"#),
+            &format!(
+                r#"[{{"name": "a", "sha256": "{KEY_HASH}"}}, {{"name": "b", "sha256": "{KEY_HASH}"}}]"#
+            ),
+        ] {
+            assert!(ApiKeys::parse(json).is_err(), "{json}");
+        }
+    }
+
+    #[test]
+    fn credentials_are_bearer_or_header() {
+        let mut headers = HeaderMap::new();
+        assert!(credentials(&headers).is_none());
+        headers.insert(API_KEY_HEADER, HeaderValue::from_static("from-header"));
+        assert!(matches!(
+            credentials(&headers),
+            Some(Credentials::ApiKey("from-header"))
+        ));
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_static("bearer from-bearer"),
+        );
+        assert!(matches!(
+            credentials(&headers),
+            Some(Credentials::Bearer("from-bearer"))
+        ));
+    }
+}
@@ -0,0 +1,257 @@
+use crate::core::engine::{
+    ExecutableQuery, QueryError, QueryInterrupt, QueryScope, RecordBatchConsumer,
+};
+use crate::core::error::UQueryError;
+use crate::core::params::{QueryParam, QueryParams};
+use crate::web::auth::Caller;
+use crate::web::consumers::RowCounter;
+use crate::web::queries;
+use crate::web::response::accepts_trailers;
+use crate::web::routers::{UQueryState, negotiate_format, stream_query};
+use arrow::datatypes::SchemaRef;
+use arrow::record_batch::RecordBatch;
+use axum::extract::{Path, Query, State};
+use axum::http::HeaderMap;
+use axum::response::Response;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// Attached databases: the in-memory or attached database, attached catalogs such as
+/// `iceberg`, but not DuckDB's internal `system` and `temp` databases.
+const CATALOGS_SQL: &str = "SELECT database_name AS name, type, path, readonly, comment \
+     FROM duckdb_databases() WHERE NOT internal";
+
+const SCHEMAS_SQL: &str = "SELECT database_name AS catalog, schema_name AS name, comment \
+     FROM duckdb_schemas() \
+     WHERE database_name IN (SELECT database_name FROM duckdb_databases() WHERE NOT internal)";
+
+/// Tables and views. `estimated_rows` is DuckDB's estimate, always null for views.
+const TABLES_SQL: &str = "SELECT database_name AS catalog, schema_name AS \"schema\", \
+     table_name AS name, 'table' AS type, column_count AS columns, \
+     estimated_size AS estimated_rows, comment \
+     FROM duckdb_tables() WHERE NOT internal \
+     UNION ALL \
+     SELECT database_name, schema_name, view_name, 'view', column_count, NULL, comment \
+     FROM duckdb_views() WHERE NOT internal";
+
+const COLUMNS_SQL: &str = "SELECT database_name AS catalog, schema_name AS \"schema\", \
+     table_name AS \"table\", column_name AS name, column_index AS position, data_type AS type, \
+     is_nullable AS nullable, column_default AS \"default\", comment \
+     FROM duckdb_columns() WHERE NOT internal";
+
+/// `GET /catalogs`: the databases that can be queried by the caller.
+pub(crate) async fn list_catalogs(
+    State(state): State<Arc<UQueryState>>,
+    caller: Caller,
+    headers: HeaderMap,
+    Query(options): Query<Vec<(String, String)>>,
+) -> Result<Response, UQueryError> {
+    let query = CatalogQuery::new(CATALOGS_SQL)
+        .within("name", caller.catalogs())
+        .order_by("name");
+    query.stream(&state, &caller, &headers, options).await
+}
+
+/// `GET /schemas`: the schemas of every catalog, or of the `catalog` query parameter.
+pub(crate) async fn list_schemas(
+    State(state): State<Arc<UQueryState>>,
+    caller: Caller,
+    headers: HeaderMap,
+    Query(options): Query<Vec<(String, String)>>,
+) -> Result<Response, UQueryError> {
+    let query = CatalogQuery::new(SCHEMAS_SQL)
+        .filter("catalog", "catalog", &options)
+        .within("catalog", caller.catalogs())
+        .order_by("catalog, name");
+    query.stream(&state, &caller, &headers, options).await
+}
+
+/// `GET /tables`: tables and views, filtered by the `catalog` and `schema` query
+/// parameters.
+pub(crate) async fn list_tables(
+    State(state): State<Arc<UQueryState>>,
+    caller: Caller,
+    headers: HeaderMap,
+    Query(options): Query<Vec<(String, String)>>,
+) -> Result<Response, UQueryError> {
+    let query = CatalogQuery::new(TABLES_SQL)
+        .filter("catalog", "catalog", &options)
+        .filter("schema", "schema", &options)
+        .within("catalog", caller.catalogs())
+        .order_by("catalog, \"schema\", name");
+    query.stream(&state, &caller, &headers, options).await
+}
+
+/// `GET /tables/{name}`: the columns of a table or view, one row per column. The
+/// `catalog` and `schema` query parameters disambiguate tables with the same name.
+pub(crate) async fn describe_table(
+    State(state): State<Arc<UQueryState>>,
+    Path(name): Path<String>,
+    caller: Caller,
+    headers: HeaderMap,
+    Query(options): Query<Vec<(String, String)>>,
+) -> Result<Response, UQueryError> {
+    let query = CatalogQuery::new(COLUMNS_SQL)
+        .equals("table", "table", name.clone())
+        .filter("catalog", "catalog", &options)
+        .filter("schema", "schema", &options)
+        .within("catalog", caller.catalogs())
+        .order_by("catalog, \"schema\", position")
+        .not_found(format!("table [{name}] does not exist"));
+    query.stream(&state, &caller, &headers, options).await
+}
+
+/// A query over DuckDB's metadata functions, with optional equality filters bound as
+/// named parameters.
+struct CatalogQuery {
+    sql: String,
+    filters: Vec<String>,
+    params: BTreeMap<String, QueryParam>,
+    order_by: &'static str,
+    /// Reported with a 404 when the query has no rows.
+    not_found: Option<String>,
+}
+
+impl CatalogQuery {
+    fn new(sql: &str) -> Self {
+        Self {
+            sql: sql.to_string(),
+            filters: Vec::new(),
+            params: BTreeMap::new(),
+            order_by: "1",
+            not_found: None,
+        }
+    }
+
+    /// Keep the rows where `column` equals the value of the `key` option, if present.
+    fn filter(self, column: &str, key: &str, options: &[(String, String)]) -> Self {
+        match options.iter().rev().find(|(k, _)| k == key) {
+            Some((_, value)) => self.equals(column, key, value.clone()),
+            None => self,
+        }
+    }
+
+    /// Keep the rows where `column` equals `value`, bound as the `key` parameter.
+    fn equals(mut self, column: &str, key: &str, value: String) -> Self {
+        self.filters.push(format!("\"{column}\" = ${key}"));
+        self.params.insert(key.to_string(), QueryParam::Text(value));
+        self
+    }
+
+    /// Keep the rows where `column` is one of `values`, if given, compared case
+    /// insensitively like DuckDB's catalog names.
+    fn within(mut self, column: &str, values: Option<&[String]>) -> Self {
+        let Some(values) = values else {
+            return self;
+        };
+        let mut keys = Vec::with_capacity(values.len());
+        for (i, value) in values.iter().enumerate() {
+            let key = format!("{column}_within_{i}");
+            keys.push(format!("lower(${key})"));
+            self.params.insert(key, QueryParam::Text(value.clone()));
+        }
+        self.filters.push(match keys.is_empty() {
+            true => "false".to_string(),
+            false => format!("lower(\"{column}\") IN ({})", keys.join(", ")),
+        });
+        self
+    }
+
+    fn order_by(mut self, order_by: &'static str) -> Self {
+        self.order_by = order_by;
+        self
+    }
+
+    /// Answer `404 Not Found` with `detail` rather than an empty result.
+    fn not_found(mut self, detail: String) -> Self {
+        self.not_found = Some(detail);
+        self
+    }
+
+    fn sql(&self) -> String {
+        let mut sql = format!("SELECT * FROM ({}) AS catalog_query", self.sql);
+        if !self.filters.is_empty() {
+            sql.push_str(" WHERE ");
+            sql.push_str(&self.filters.join(" AND "));
+        }
+        format!("{sql} ORDER BY {}", self.order_by)
+    }
+
+    fn params(&self) -> QueryParams {
+        QueryParams::Named(self.params.clone())
+    }
+
+    async fn stream(
+        self,
+        state: &UQueryState,
+        caller: &Caller,
+        headers: &HeaderMap,
+        options: Vec<(String, String)>,
+    ) -> Result<Response, UQueryError> {
+        let format = negotiate_format(headers, options)?;
+        let query_id = queries::query_id(headers)?;
+        let timeouts = state.timeouts.lowered_by(headers, &caller.scope())?;
+        let engine = Arc::clone(&state.engine);
+        let (sql, params, not_found) = (self.sql(), self.params(), self.not_found);
+        stream_query(
+            state,
+            query_id,
+            caller.owner(),
+            format,
+            timeouts,
+            accepts_trailers(headers),
+            move || {
+                let query = engine.prepare(&sql, params, QueryScope::default())?;
+                Ok(match not_found {
+                    Some(detail) => Box::new(NotEmpty { query, detail }),
+                    None => query,
+                })
+            },
+        )
+        .await
+    }
+}
+
+/// A query failing with [`QueryError::NotFound`] when it has no rows. The result is
+/// only finished once it has some, so that an empty one is answered with a 404.
+struct NotEmpty {
+    query: Box<dyn ExecutableQuery>,
+    detail: String,
+}
+
+impl ExecutableQuery for NotEmpty {
+    fn execute(&mut self, consumer: &mut dyn RecordBatchConsumer) -> Result<(), QueryError> {
+        let mut counter = RowCounter::new(Unfinished(&mut *consumer));
+        self.query.execute(&mut counter)?;
+        if counter.rows() == 0 {
+            return Err(QueryError::NotFound(self.detail.clone()));
+        }
+        Ok(consumer.finish()?)
+    }
+
+    fn interrupt_handle(&self) -> Option<Arc<dyn QueryInterrupt>> {
+        self.query.interrupt_handle()
+    }
+}
+
+/// Forwards the schema and the batches to the inner consumer, but not the end of the
+/// result.
+struct Unfinished<'a>(&'a mut dyn RecordBatchConsumer);
+
+impl RecordBatchConsumer for Unfinished<'_> {
+    fn on_schema(&mut self, schema: SchemaRef) -> Result<(), String> {
+        self.0.on_schema(schema)
+    }
+
+    fn on_batch(&mut self, batch: RecordBatch) -> Result<(), String> {
+        self.0.on_batch(batch)
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn on_truncated(&mut self) {
+        self.0.on_truncated();
+    }
+}
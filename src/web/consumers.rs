@@ -1,8 +1,25 @@
 use crate::core::engine::RecordBatchConsumer;
-use arrow::datatypes::SchemaRef;
+use arrow::array::{Array, ArrayRef, AsArray, Int64Array, StringArray};
+use arrow::compute::cast;
+use arrow::csv::{Writer as CsvWriter, WriterBuilder as CsvWriterBuilder};
+use arrow::datatypes::{DataType, FieldRef, Float64Type, Schema, SchemaRef, TimeUnit};
+use arrow::error::ArrowError;
 use arrow::ipc::writer::StreamWriter;
+use arrow::json::writer::{
+    Encoder, EncoderFactory, EncoderOptions, JsonFormat, LineDelimited, NullableEncoder,
+};
+use arrow::json::{Writer as JsonWriter, WriterBuilder as JsonWriterBuilder};
 use arrow::record_batch::{RecordBatch, RecordBatchWriter};
-use std::io::Write;
+use arrow::util::display::{ArrayFormatter, FormatOptions};
+use base64::prelude::{BASE64_STANDARD, Engine};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{Compression, GzipLevel, ZstdLevel};
+use parquet::file::properties::WriterProperties;
+use rust_xlsxwriter::{Format, Workbook, Worksheet, XlsxError};
+use serde_json::{Value, json};
+use std::io::{self, Seek, Write};
+use std::sync::Arc;
+use std::time::Instant;
 
 /// Generic consumer that delegates `on_batch` and `finish` to any `RecordBatchWriter`.
 /// `on_schema` is a no-op; writers that need the schema upfront (e.g., StreamWriter) should
@@ -73,3 +90,825 @@ impl<W: Write + Send> RecordBatchConsumer for ArrowConsumer<W> {
         self.inner.finish()
     }
 }
+
+/// Counts the rows flowing to the inner consumer.
+pub(crate) struct RowCounter<C: RecordBatchConsumer> {
+    inner: C,
+    rows: u64,
+    truncated: bool,
+}
+
+impl<C: RecordBatchConsumer> RowCounter<C> {
+    pub fn new(inner: C) -> Self {
+        Self {
+            inner,
+            rows: 0,
+            truncated: false,
+        }
+    }
+
+    pub fn rows(&self) -> u64 {
+        self.rows
+    }
+
+    /// Whether rows were left out of the result, past the `max_rows` of the caller.
+    pub fn truncated(&self) -> bool {
+        self.truncated
+    }
+}
+
+impl<C: RecordBatchConsumer> RecordBatchConsumer for RowCounter<C> {
+    fn on_schema(&mut self, schema: SchemaRef) -> Result<(), String> {
+        self.inner.on_schema(schema)
+    }
+
+    fn on_batch(&mut self, batch: RecordBatch) -> Result<(), String> {
+        self.rows += batch.num_rows() as u64;
+        self.inner.on_batch(batch)
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        self.inner.finish()
+    }
+
+    fn on_truncated(&mut self) {
+        self.truncated = true;
+        self.inner.on_truncated();
+    }
+}
+
+/// CSV dialect, selected with media type parameters or query-string options
+/// (`delimiter`, `quote`, `header`, `null`, `date_format`, `timestamp_format`, `bom`).
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct CsvOptions {
+    pub delimiter: u8,
+    pub quote: u8,
+    pub header: bool,
+    pub null: String,
+    pub date_format: Option<String>,
+    pub timestamp_format: Option<String>,
+    pub bom: bool,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        Self {
+            delimiter: b',',
+            quote: b'"',
+            header: true,
+            null: String::new(),
+            date_format: None,
+            timestamp_format: None,
+            bom: false,
+        }
+    }
+}
+
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+impl CsvOptions {
+    /// Tab separated values.
+    pub fn tsv() -> Self {
+        Self {
+            delimiter: b'\t',
+            ..Self::default()
+        }
+    }
+
+    /// Override `self` with the options from `key=value` pairs, later pairs taking
+    /// precedence. Unknown keys are ignored.
+    pub fn parse(mut self, options: &[(String, String)]) -> Result<Self, String> {
+        for (key, value) in options {
+            match key.trim().to_lowercase().as_str() {
+                "delimiter" => self.delimiter = csv_char("delimiter", value)?,
+                "quote" => self.quote = csv_char("quote", value)?,
+                "header" => {
+                    self.header = match value.to_lowercase().as_str() {
+                        "present" | "true" => true,
+                        "absent" | "false" => false,
+                        _ => {
+                            return Err(format!("header must be present or absent, got [{value}]"));
+                        }
+                    }
+                }
+                "null" => self.null = value.clone(),
+                "date_format" => self.date_format = Some(value.clone()),
+                "timestamp_format" => self.timestamp_format = Some(value.clone()),
+                "bom" => {
+                    self.bom = value
+                        .parse()
+                        .map_err(|_| format!("bom must be true or false, got [{value}]"))?
+                }
+                _ => {}
+            }
+        }
+        if self.delimiter == self.quote {
+            return Err("delimiter and quote must be different characters".to_string());
+        }
+        Ok(self)
+    }
+
+    pub fn writer<W: Write>(&self, sink: W) -> CsvWriter<BomWriter<W>> {
+        let mut builder = CsvWriterBuilder::new()
+            .with_header(self.header)
+            .with_delimiter(self.delimiter)
+            .with_quote(self.quote)
+            .with_null(self.null.clone());
+        if let Some(format) = &self.date_format {
+            builder = builder.with_date_format(format.clone());
+        }
+        if let Some(format) = &self.timestamp_format {
+            builder = builder
+                .with_timestamp_format(format.clone())
+                .with_timestamp_tz_format(format.clone());
+        }
+        builder.build(BomWriter {
+            inner: sink,
+            bom_pending: self.bom,
+        })
+    }
+}
+
+/// A single ASCII character, also accepting `tab`, `comma`, `semicolon` and `pipe`.
+fn csv_char(name: &str, value: &str) -> Result<u8, String> {
+    match value.to_lowercase().as_str() {
+        "tab" | "\\t" => Ok(b'\t'),
+        "comma" => Ok(b','),
+        "semicolon" => Ok(b';'),
+        "pipe" => Ok(b'|'),
+        _ if value.len() == 1 && value.is_ascii() && value != "\n" && value != "\r" => {
+            Ok(value.as_bytes()[0])
+        }
+        _ => Err(format!(
+            "{name} must be a single ASCII character, got [{value}]"
+        )),
+    }
+}
+
+/// Writes the UTF-8 byte order mark before the first bytes, when pending.
+pub(crate) struct BomWriter<W: Write> {
+    inner: W,
+    bom_pending: bool,
+}
+
+impl<W: Write> Write for BomWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.bom_pending {
+            self.inner.write_all(UTF8_BOM)?;
+            self.bom_pending = false;
+        }
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Parquet writing options, selected with media type parameters or query-string options
+/// (`compression`, `row_group_size`, `dictionary`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct ParquetOptions {
+    pub compression: Compression,
+    pub row_group_size: usize,
+    pub dictionary: bool,
+}
+
+/// Same default row group size as DuckDB: rows are buffered in memory until a row
+/// group is complete, so it also bounds the memory used per query.
+pub(crate) const DEFAULT_ROW_GROUP_SIZE: usize = 122_880;
+
+/// Largest row group a client may ask for, bounding the rows buffered per query.
+const MAX_ROW_GROUP_SIZE: usize = 1_048_576;
+
+impl Default for ParquetOptions {
+    fn default() -> Self {
+        Self {
+            compression: Compression::SNAPPY,
+            row_group_size: DEFAULT_ROW_GROUP_SIZE,
+            dictionary: true,
+        }
+    }
+}
+
+impl ParquetOptions {
+    /// Read the Parquet options from `key=value` pairs, later pairs taking precedence.
+    /// Unknown keys are ignored.
+    pub fn parse(options: &[(String, String)]) -> Result<Self, String> {
+        let mut parquet_options = Self::default();
+        for (key, value) in options {
+            let value = value.trim().trim_matches('"');
+            match key.trim().to_lowercase().as_str() {
+                "compression" => {
+                    parquet_options.compression = match value.to_lowercase().as_str() {
+                        "uncompressed" | "none" => Compression::UNCOMPRESSED,
+                        "snappy" => Compression::SNAPPY,
+                        "gzip" => Compression::GZIP(GzipLevel::default()),
+                        "zstd" => Compression::ZSTD(ZstdLevel::default()),
+                        "lz4_raw" | "lz4" => Compression::LZ4_RAW,
+                        _ => {
+                            return Err(format!(
+                                "unsupported parquet compression [{value}], expected one of uncompressed, snappy, gzip, zstd, lz4_raw"
+                            ));
+                        }
+                    }
+                }
+                "row_group_size" => {
+                    parquet_options.row_group_size = value
+                        .parse()
+                        .ok()
+                        .filter(|size| (1..=MAX_ROW_GROUP_SIZE).contains(size))
+                        .ok_or_else(|| {
+                            format!(
+                                "row_group_size must be an integer between 1 and {MAX_ROW_GROUP_SIZE}, got [{value}]"
+                            )
+                        })?
+                }
+                "dictionary" => {
+                    parquet_options.dictionary = value
+                        .parse()
+                        .map_err(|_| format!("dictionary must be true or false, got [{value}]"))?
+                }
+                _ => {}
+            }
+        }
+        Ok(parquet_options)
+    }
+
+    fn writer_properties(&self) -> WriterProperties {
+        WriterProperties::builder()
+            .set_compression(self.compression)
+            .set_max_row_group_size(self.row_group_size)
+            .set_dictionary_enabled(self.dictionary)
+            .build()
+    }
+}
+
+/// Parquet consumer: like `ArrowConsumer`, the writer needs the schema and is created
+/// in `on_schema`. Batches are buffered until a row group is complete, and the footer
+/// is only written by `finish`, so a Parquet response is readable once fully received.
+pub(crate) struct ParquetConsumer<W: Write + Send> {
+    inner: WriterConsumer<ArrowWriter<W>>,
+    sink: Option<W>,
+    options: ParquetOptions,
+}
+
+impl<W: Write + Send> ParquetConsumer<W> {
+    pub fn new(sink: W, options: ParquetOptions) -> Self {
+        Self {
+            inner: WriterConsumer { writer: None },
+            sink: Some(sink),
+            options,
+        }
+    }
+}
+
+impl<W: Write + Send> RecordBatchConsumer for ParquetConsumer<W> {
+    fn on_schema(&mut self, schema: SchemaRef) -> Result<(), String> {
+        let sink = self.sink.take().unwrap();
+        let writer = ArrowWriter::try_new(sink, schema, Some(self.options.writer_properties()))
+            .map_err(|e| e.to_string())?;
+        self.inner.writer = Some(writer);
+        Ok(())
+    }
+
+    fn on_batch(&mut self, batch: RecordBatch) -> Result<(), String> {
+        self.inner.on_batch(batch)
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        self.inner.finish()
+    }
+}
+
+/// Rows of an Excel sheet, header included.
+pub(crate) const EXCEL_MAX_ROWS: usize = 1_048_576;
+/// Columns of an Excel sheet.
+const EXCEL_MAX_COLUMNS: usize = 16_384;
+/// Days between the Excel epoch (1899-12-30) and the Unix epoch.
+const EXCEL_UNIX_EPOCH: f64 = 25_569.0;
+const MILLIS_PER_DAY: f64 = 86_400_000.0;
+const MICROS_PER_DAY: f64 = 86_400_000_000.0;
+
+/// How the values of a column are written to Excel cells.
+enum CellKind {
+    Number,
+    Decimal(Format),
+    Date,
+    Timestamp,
+    Time,
+    Boolean,
+    Text,
+}
+
+impl CellKind {
+    fn of(data_type: &DataType) -> Self {
+        match data_type {
+            t if t.is_integer() || t.is_floating() => CellKind::Number,
+            DataType::Decimal32(_, scale)
+            | DataType::Decimal64(_, scale)
+            | DataType::Decimal128(_, scale)
+            | DataType::Decimal256(_, scale) => CellKind::Decimal(decimal_format(*scale)),
+            DataType::Date32 | DataType::Date64 => CellKind::Date,
+            DataType::Timestamp(_, _) => CellKind::Timestamp,
+            DataType::Time32(_) | DataType::Time64(_) => CellKind::Time,
+            DataType::Boolean => CellKind::Boolean,
+            _ => CellKind::Text,
+        }
+    }
+}
+
+fn decimal_format(scale: i8) -> Format {
+    if scale > 0 {
+        Format::new().set_num_format(format!("0.{}", "0".repeat(scale as usize)))
+    } else {
+        Format::new().set_num_format("0")
+    }
+}
+
+/// Excel (xlsx) consumer. Arrow types are mapped to native cells: numbers, decimals,
+/// dates, timestamps (in UTC for zoned ones), times and booleans; other types are
+/// written as text. The sheet is kept in constant memory mode, but the xlsx file can
+/// only be sent by `finish`, once the workbook is complete: it is zipped into a
+/// temporary file then copied to the sink, so the workbook is never held in memory.
+pub(crate) struct XlsxConsumer<W: Write + Send> {
+    sink: Option<W>,
+    workbook: Workbook,
+    columns: Vec<CellKind>,
+    next_row: usize,
+    date_format: Format,
+    timestamp_format: Format,
+    time_format: Format,
+}
+
+impl<W: Write + Send> XlsxConsumer<W> {
+    pub fn new(sink: W) -> Self {
+        let mut workbook = Workbook::new();
+        workbook.add_worksheet_with_constant_memory();
+        Self {
+            sink: Some(sink),
+            workbook,
+            columns: Vec::new(),
+            next_row: 0,
+            date_format: Format::new().set_num_format("yyyy-mm-dd"),
+            timestamp_format: Format::new().set_num_format("yyyy-mm-dd hh:mm:ss"),
+            time_format: Format::new().set_num_format("hh:mm:ss"),
+        }
+    }
+
+    fn worksheet(&mut self) -> Result<&mut Worksheet, String> {
+        self.workbook
+            .worksheet_from_index(0)
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Values of a batch column, converted to what Excel cells hold.
+enum Cells {
+    Numbers(ArrayRef),
+    Booleans(ArrayRef),
+    Text(StringArray),
+}
+
+fn to_cells(kind: &CellKind, array: &ArrayRef) -> Result<Cells, ArrowError> {
+    let days = |array: ArrayRef, per_day: f64, offset: f64| -> Result<Cells, ArrowError> {
+        let values = cast(&array, &DataType::Float64)?;
+        let days = values
+            .as_primitive::<Float64Type>()
+            .unary::<_, Float64Type>(|v| v / per_day + offset);
+        Ok(Cells::Numbers(std::sync::Arc::new(days)))
+    };
+    match kind {
+        CellKind::Number | CellKind::Decimal(_) => {
+            Ok(Cells::Numbers(cast(array, &DataType::Float64)?))
+        }
+        CellKind::Date => {
+            let dates = cast(array, &DataType::Date32)?;
+            days(cast(&dates, &DataType::Int32)?, 1.0, EXCEL_UNIX_EPOCH)
+        }
+        CellKind::Timestamp => {
+            let millis = cast(array, &DataType::Timestamp(TimeUnit::Millisecond, None))?;
+            days(
+                cast(&millis, &DataType::Int64)?,
+                MILLIS_PER_DAY,
+                EXCEL_UNIX_EPOCH,
+            )
+        }
+        CellKind::Time => {
+            let micros = cast(array, &DataType::Time64(TimeUnit::Microsecond))?;
+            days(cast(&micros, &DataType::Int64)?, MICROS_PER_DAY, 0.0)
+        }
+        CellKind::Boolean => Ok(Cells::Booleans(array.clone())),
+        CellKind::Text => {
+            let formatter = ArrayFormatter::try_new(array.as_ref(), &FormatOptions::default())?;
+            Ok(Cells::Text(
+                (0..array.len())
+                    .map(|i| array.is_valid(i).then(|| formatter.value(i).to_string()))
+                    .collect(),
+            ))
+        }
+    }
+}
+
+impl<W: Write + Send> RecordBatchConsumer for XlsxConsumer<W> {
+    fn on_schema(&mut self, schema: SchemaRef) -> Result<(), String> {
+        if schema.fields().len() > EXCEL_MAX_COLUMNS {
+            return Err(format!(
+                "result has {} columns, more than the {EXCEL_MAX_COLUMNS} columns of an Excel sheet",
+                schema.fields().len()
+            ));
+        }
+        self.columns = schema
+            .fields()
+            .iter()
+            .map(|field| CellKind::of(field.data_type()))
+            .collect();
+        let header = Format::new().set_bold();
+        let worksheet = self.worksheet()?;
+        for (col, field) in schema.fields().iter().enumerate() {
+            worksheet
+                .write_string_with_format(0, col as u16, field.name(), &header)
+                .map_err(|e| e.to_string())?;
+        }
+        worksheet
+            .set_freeze_panes(1, 0)
+            .map_err(|e| e.to_string())?;
+        self.next_row = 1;
+        Ok(())
+    }
+
+    fn on_batch(&mut self, batch: RecordBatch) -> Result<(), String> {
+        if self.next_row + batch.num_rows() > EXCEL_MAX_ROWS {
+            return Err(format!(
+                "result has more than the {} rows of an Excel sheet, reduce it with a LIMIT or use another format",
+                EXCEL_MAX_ROWS - 1
+            ));
+        }
+        let cells = self
+            .columns
+            .iter()
+            .zip(batch.columns())
+            .map(|(kind, array)| to_cells(kind, array))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| e.to_string())?;
+        let first_row = self.next_row;
+        // borrow the formats and the worksheet separately
+        let Self {
+            workbook,
+            columns,
+            date_format,
+            timestamp_format,
+            time_format,
+            ..
+        } = self;
+        let worksheet = workbook
+            .worksheet_from_index(0)
+            .map_err(|e| e.to_string())?;
+        // rows are written in order, as required by the constant memory mode
+        for i in 0..batch.num_rows() {
+            let row = (first_row + i) as u32;
+            for (col, (kind, cells)) in columns.iter().zip(&cells).enumerate() {
+                let col = col as u16;
+                let result: Result<_, XlsxError> = match cells {
+                    Cells::Numbers(values) if values.is_valid(i) => {
+                        let value = values.as_primitive::<Float64Type>().value(i);
+                        match kind {
+                            CellKind::Decimal(format) => {
+                                worksheet.write_number_with_format(row, col, value, format)
+                            }
+                            CellKind::Date => {
+                                worksheet.write_number_with_format(row, col, value, date_format)
+                            }
+                            CellKind::Timestamp => worksheet.write_number_with_format(
+                                row,
+                                col,
+                                value,
+                                timestamp_format,
+                            ),
+                            CellKind::Time => {
+                                worksheet.write_number_with_format(row, col, value, time_format)
+                            }
+                            _ => worksheet.write_number(row, col, value),
+                        }
+                    }
+                    Cells::Booleans(values) if values.is_valid(i) => {
+                        worksheet.write_boolean(row, col, values.as_boolean().value(i))
+                    }
+                    Cells::Text(values) if values.is_valid(i) => {
+                        worksheet.write_string(row, col, values.value(i))
+                    }
+                    // nulls are left as empty cells
+                    _ => continue,
+                };
+                result.map_err(|e| e.to_string())?;
+            }
+        }
+        self.next_row += batch.num_rows();
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        // unnamed, the file is removed once closed, even if the server stops
+        let mut file = tempfile::tempfile().map_err(|e| e.to_string())?;
+        self.workbook
+            .save_to_writer(&mut file)
+            .map_err(|e| e.to_string())?;
+        file.rewind().map_err(|e| e.to_string())?;
+        let mut sink = self.sink.take().unwrap();
+        io::copy(&mut file, &mut sink).map_err(|e| e.to_string())?;
+        sink.flush().map_err(|e| e.to_string())
+    }
+}
+
+/// Encoding of 64-bit integers and decimals in JSON output.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) enum JsonNumbers {
+    #[default]
+    Number,
+    /// Strings, so that JavaScript clients do not lose precision.
+    String,
+}
+
+/// Encoding of timestamps in JSON output.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) enum JsonTimestamps {
+    /// Arrow's default, without timezone for timestamps without one.
+    #[default]
+    Default,
+    /// ISO-8601 with an explicit timezone, `Z` for timestamps without one.
+    Iso,
+    /// Milliseconds since the Unix epoch.
+    EpochMillis,
+}
+
+/// Encoding of binary values in JSON output.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) enum JsonBinary {
+    #[default]
+    Hex,
+    Base64,
+}
+
+/// JSON encoding options shared by the JSON, JSON Lines and envelope formats, selected
+/// with media type parameters, the `x-uquery-json-options` header or query-string options
+/// (`int64`, `decimal`, `timestamp`, `binary`, `nulls`).
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct JsonOptions {
+    pub int64: JsonNumbers,
+    pub decimal: JsonNumbers,
+    pub timestamp: JsonTimestamps,
+    pub binary: JsonBinary,
+    /// Write `null` values instead of omitting the keys.
+    pub explicit_nulls: bool,
+}
+
+impl JsonOptions {
+    /// Read the JSON options from `key=value` pairs, later pairs taking precedence.
+    /// Unknown keys are ignored.
+    pub fn parse(options: &[(String, String)]) -> Result<Self, String> {
+        let mut json_options = Self::default();
+        for (key, value) in options {
+            let key = key.trim().to_lowercase();
+            let lowercase_value = value.trim().to_lowercase();
+            match (key.as_str(), lowercase_value.as_str()) {
+                ("int64", "number") => json_options.int64 = JsonNumbers::Number,
+                ("int64", "string") => json_options.int64 = JsonNumbers::String,
+                ("decimal", "number") => json_options.decimal = JsonNumbers::Number,
+                ("decimal", "string") => json_options.decimal = JsonNumbers::String,
+                ("int64" | "decimal", _) => {
+                    return Err(format!("{key} must be number or string, got [{value}]"));
+                }
+                ("timestamp", "default") => json_options.timestamp = JsonTimestamps::Default,
+                ("timestamp", "iso") => json_options.timestamp = JsonTimestamps::Iso,
+                ("timestamp", "epoch_ms") => json_options.timestamp = JsonTimestamps::EpochMillis,
+                ("timestamp", _) => {
+                    return Err(format!(
+                        "timestamp must be default, iso or epoch_ms, got [{value}]"
+                    ));
+                }
+                ("binary", "hex") => json_options.binary = JsonBinary::Hex,
+                ("binary", "base64") => json_options.binary = JsonBinary::Base64,
+                ("binary", _) => {
+                    return Err(format!("binary must be hex or base64, got [{value}]"));
+                }
+                ("nulls", "omit") => json_options.explicit_nulls = false,
+                ("nulls", "explicit") => json_options.explicit_nulls = true,
+                ("nulls", _) => {
+                    return Err(format!("nulls must be omit or explicit, got [{value}]"));
+                }
+                _ => {}
+            }
+        }
+        Ok(json_options)
+    }
+
+    pub fn writer<W: Write, F: JsonFormat>(&self, sink: W) -> JsonWriter<W, F> {
+        let mut builder = JsonWriterBuilder::new()
+            .with_explicit_nulls(self.explicit_nulls)
+            .with_encoder_factory(Arc::new(JsonEncoderFactory(*self)));
+        if self.timestamp == JsonTimestamps::Iso {
+            builder = builder
+                .with_timestamp_format("%Y-%m-%dT%H:%M:%S%.fZ".to_string())
+                .with_timestamp_tz_format("%Y-%m-%dT%H:%M:%S%.f%:z".to_string());
+        }
+        builder.build(sink)
+    }
+}
+
+/// Overrides arrow's JSON encoders for the types affected by [`JsonOptions`].
+#[derive(Debug)]
+struct JsonEncoderFactory(JsonOptions);
+
+impl EncoderFactory for JsonEncoderFactory {
+    fn make_default_encoder<'a>(
+        &self,
+        _field: &'a FieldRef,
+        array: &'a dyn Array,
+        _options: &'a EncoderOptions,
+    ) -> Result<Option<NullableEncoder<'a>>, ArrowError> {
+        let options = self.0;
+        let encoder: Box<dyn Encoder + 'a> = match array.data_type() {
+            DataType::Int64 | DataType::UInt64 if options.int64 == JsonNumbers::String => Box::new(
+                QuotedEncoder(ArrayFormatter::try_new(array, &FormatOptions::default())?),
+            ),
+            DataType::Decimal32(_, _)
+            | DataType::Decimal64(_, _)
+            | DataType::Decimal128(_, _)
+            | DataType::Decimal256(_, _)
+                if options.decimal == JsonNumbers::String =>
+            {
+                Box::new(QuotedEncoder(ArrayFormatter::try_new(
+                    array,
+                    &FormatOptions::default(),
+                )?))
+            }
+            DataType::Timestamp(_, tz) if options.timestamp == JsonTimestamps::EpochMillis => {
+                let millis = cast(
+                    array,
+                    &DataType::Timestamp(TimeUnit::Millisecond, tz.clone()),
+                )?;
+                Box::new(EpochMillisEncoder(
+                    cast(&millis, &DataType::Int64)?.as_primitive().clone(),
+                ))
+            }
+            DataType::Binary | DataType::LargeBinary | DataType::BinaryView
+                if options.binary == JsonBinary::Base64 =>
+            {
+                Box::new(Base64Encoder(cast(array, &DataType::LargeBinary)?))
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(NullableEncoder::new(encoder, array.logical_nulls())))
+    }
+}
+
+/// Values as JSON strings, for values that never need escaping (numbers).
+struct QuotedEncoder<'a>(ArrayFormatter<'a>);
+
+impl Encoder for QuotedEncoder<'_> {
+    fn encode(&mut self, idx: usize, out: &mut Vec<u8>) {
+        // writing to a Vec is infallible
+        write!(out, "\"{}\"", self.0.value(idx)).unwrap();
+    }
+}
+
+struct EpochMillisEncoder(Int64Array);
+
+impl Encoder for EpochMillisEncoder {
+    fn encode(&mut self, idx: usize, out: &mut Vec<u8>) {
+        write!(out, "{}", self.0.value(idx)).unwrap();
+    }
+}
+
+/// Binary values as base64 JSON strings, the array being cast to `LargeBinary`.
+struct Base64Encoder(ArrayRef);
+
+impl Encoder for Base64Encoder {
+    fn encode(&mut self, idx: usize, out: &mut Vec<u8>) {
+        out.push(b'"');
+        out.extend(
+            BASE64_STANDARD
+                .encode(self.0.as_binary::<i64>().value(idx))
+                .as_bytes(),
+        );
+        out.push(b'"');
+    }
+}
+
+/// JSON envelope options, selected with media type parameters or query-string options.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct EnvelopeOptions {
+    /// Rows kept in `data`, the result being flagged as `truncated` beyond.
+    pub max_rows: Option<usize>,
+    /// Encoding of the rows in `data`.
+    pub json: JsonOptions,
+}
+
+impl EnvelopeOptions {
+    /// Read the envelope options from `key=value` pairs, later pairs taking precedence.
+    /// Unknown keys are ignored.
+    pub fn parse(options: &[(String, String)]) -> Result<Self, String> {
+        let mut envelope_options = Self {
+            json: JsonOptions::parse(options)?,
+            ..Self::default()
+        };
+        for (key, value) in options {
+            if key.trim().eq_ignore_ascii_case("max_rows") {
+                envelope_options.max_rows = Some(value.trim().parse().map_err(|_| {
+                    format!("max_rows must be a non negative integer, got [{value}]")
+                })?);
+            }
+        }
+        Ok(envelope_options)
+    }
+}
+
+/// The fields of `schema` as JSON: `[{"name", "type", "nullable"}]`, types being Arrow
+/// data types.
+pub(crate) fn schema_fields(schema: &Schema) -> Value {
+    schema
+        .fields()
+        .iter()
+        .map(|field| {
+            json!({
+                "name": field.name(),
+                "type": field.data_type().to_string(),
+                "nullable": field.is_nullable(),
+            })
+        })
+        .collect()
+}
+
+/// Self-describing JSON consumer writing
+/// `{"schema": [...], "data": [...], "stats": {"rows", "elapsed_ms", "truncated"}}`.
+/// The schema comes from `on_schema`, so it is there even when no rows are returned.
+pub(crate) struct EnvelopeConsumer<W: Write + Send> {
+    sink: W,
+    start: Instant,
+    options: EnvelopeOptions,
+    rows: usize,
+    truncated: bool,
+}
+
+impl<W: Write + Send> EnvelopeConsumer<W> {
+    /// `start` is the beginning of the request, reported in `stats.elapsed_ms`.
+    pub fn new(sink: W, start: Instant, options: EnvelopeOptions) -> Self {
+        Self {
+            sink,
+            start,
+            options,
+            rows: 0,
+            truncated: false,
+        }
+    }
+}
+
+impl<W: Write + Send> RecordBatchConsumer for EnvelopeConsumer<W> {
+    fn on_schema(&mut self, schema: SchemaRef) -> Result<(), String> {
+        let fields = schema_fields(&schema);
+        write!(self.sink, r#"{{"schema":{fields},"data":["#).map_err(|e| e.to_string())
+    }
+
+    fn on_batch(&mut self, batch: RecordBatch) -> Result<(), String> {
+        let remaining = self
+            .options
+            .max_rows
+            .map_or(batch.num_rows(), |max| max.saturating_sub(self.rows));
+        if remaining < batch.num_rows() {
+            self.truncated = true;
+        }
+        let batch = batch.slice(0, remaining.min(batch.num_rows()));
+        if batch.num_rows() == 0 {
+            return Ok(());
+        }
+        // rows are encoded as JSON lines, then joined into the data array
+        let mut writer = self.options.json.writer::<_, LineDelimited>(Vec::new());
+        writer.write(&batch).map_err(|e| e.to_string())?;
+        writer.finish().map_err(|e| e.to_string())?;
+        for line in writer.into_inner().split(|b| *b == b'\n') {
+            if line.is_empty() {
+                continue;
+            }
+            if self.rows > 0 {
+                self.sink.write_all(b",").map_err(|e| e.to_string())?;
+            }
+            self.sink.write_all(line).map_err(|e| e.to_string())?;
+            self.rows += 1;
+        }
+        Ok(())
+    }
+
+    fn on_truncated(&mut self) {
+        self.truncated = true;
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        let stats = json!({
+            "rows": self.rows,
+            "elapsed_ms": self.start.elapsed().as_millis() as u64,
+            "truncated": self.truncated,
+        });
+        write!(self.sink, r#"],"stats":{stats}}}"#).map_err(|e| e.to_string())?;
+        self.sink.flush().map_err(|e| e.to_string())
+    }
+}
@@ -0,0 +1,115 @@
+use crate::core::engine::{DescribeError, PrepareError, QueryDescription, QueryError};
+use crate::core::error::UQueryError;
+use crate::web::auth::Caller;
+use crate::web::consumers::schema_fields;
+use crate::web::request::QueryRequest;
+use crate::web::response::QueryResponseFormat;
+use crate::web::routers::{UQueryState, negotiate_format, query_error};
+use arrow::datatypes::Schema;
+use arrow::error::ArrowError;
+use arrow::ipc::writer::StreamWriter;
+use axum::extract::{Query, State};
+use axum::http::header::CONTENT_TYPE;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use serde_json::json;
+use std::sync::Arc;
+use tokio::task::spawn_blocking;
+use tracing::Span;
+
+/// `POST /describe`: parse and bind the statement without running it, and return its
+/// result schema and parameters, as JSON or as an Arrow IPC stream without batches.
+/// The statement is refused as `POST /` would refuse it for the caller.
+pub(crate) async fn describe(
+    State(state): State<Arc<UQueryState>>,
+    caller: Caller,
+    headers: HeaderMap,
+    Query(format_options): Query<Vec<(String, String)>>,
+    query_request: QueryRequest,
+) -> Result<Response, UQueryError> {
+    let format = negotiate_format(&headers, format_options)?;
+    if !matches!(
+        format,
+        QueryResponseFormat::Json(_) | QueryResponseFormat::Arrow
+    ) {
+        return Err(UQueryError {
+            status_code: StatusCode::NOT_ACCEPTABLE.as_u16(),
+            title: "Unsupported response format".to_string(),
+            detail: format!("format [{format}] is not supported by describe"),
+        });
+    }
+    let sql = query_request.get_sql_query().to_string();
+    let scope = caller.scope();
+    let engine = Arc::clone(&state.engine);
+    let span = Span::current();
+    let description = spawn_blocking(move || span.in_scope(|| engine.describe(&sql, &scope)))
+        .await
+        .map_err(|e| UQueryError {
+            status_code: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+            title: "Internal Error".to_string(),
+            detail: e.to_string(),
+        })?;
+    match description {
+        Ok(description) => description_response(description, &format),
+        Err(DescribeError::Invalid(error)) => Ok(prepare_error_response(error)),
+        Err(DescribeError::Forbidden(detail)) => Err(query_error(QueryError::Forbidden(detail))),
+        Err(DescribeError::Unavailable(detail)) => {
+            Err(query_error(QueryError::Unavailable(detail)))
+        }
+    }
+}
+
+fn description_response(
+    description: QueryDescription,
+    format: &QueryResponseFormat,
+) -> Result<Response, UQueryError> {
+    if *format == QueryResponseFormat::Arrow {
+        let body = schema_stream(&description.schema).map_err(|e| UQueryError {
+            status_code: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+            title: "Internal Error".to_string(),
+            detail: e.to_string(),
+        })?;
+        return Ok(([(CONTENT_TYPE, format.to_string())], body).into_response());
+    }
+    let parameters: Vec<_> = description
+        .parameters
+        .iter()
+        .map(|parameter| {
+            json!({
+                "name": parameter.name,
+                "type": parameter.data_type.as_ref().map(|data_type| data_type.to_string()),
+            })
+        })
+        .collect();
+    let body = json!({
+        "schema": schema_fields(&description.schema),
+        "parameters": parameters,
+    });
+    Ok(([(CONTENT_TYPE, format.to_string())], body.to_string()).into_response())
+}
+
+/// An Arrow IPC stream with the schema only.
+fn schema_stream(schema: &Schema) -> Result<Vec<u8>, ArrowError> {
+    let mut writer = StreamWriter::try_new(Vec::new(), schema)?;
+    writer.finish()?;
+    writer.into_inner()
+}
+
+/// `400 Bad Request` problem details, with the `position` of the error in the SQL
+/// when DuckDB reports it.
+fn prepare_error_response(error: PrepareError) -> Response {
+    let mut problem = json!({
+        "status": StatusCode::BAD_REQUEST.as_u16(),
+        "title": "SQL Error",
+        "detail": error.message,
+    });
+    if let Some(position) = error.position {
+        problem["position"] = json!({ "line": position.line, "column": position.column });
+    }
+    (
+        StatusCode::BAD_REQUEST,
+        [(CONTENT_TYPE, "application/problem+json")],
+        problem.to_string(),
+    )
+        .into_response()
+}
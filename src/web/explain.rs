@@ -0,0 +1,207 @@
+use crate::core::engine::{QueryError, RecordBatchConsumer};
+use crate::core::error::UQueryError;
+use crate::web::auth::Caller;
+use crate::web::queries;
+use crate::web::queries::{QUERY_ID_HEADER, QueryGuard};
+use crate::web::request::QueryRequest;
+use crate::web::routers::{UQueryState, query_error, query_timeout_error};
+use arrow::array::{Array, AsArray};
+use arrow::compute::cast;
+use arrow::datatypes::{DataType, SchemaRef};
+use arrow::record_batch::RecordBatch;
+use axum::Json;
+use axum::extract::{Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use serde::Deserialize;
+use serde_json::{Map, Value, json};
+use std::sync::Arc;
+use tokio::task::spawn_blocking;
+use tracing::Span;
+
+/// Key of the estimated cardinality in the `extra_info` of DuckDB's JSON plans.
+const ESTIMATED_CARDINALITY: &str = "__estimated_cardinality__";
+
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct ExplainOptions {
+    #[serde(default)]
+    analyze: bool,
+}
+
+/// `POST /explain`: the physical plan of the statement as a JSON tree. With
+/// `analyze=true` the statement is run under `EXPLAIN ANALYZE`, and the plan carries
+/// actual cardinalities and timings. DuckDB enables profiling for that statement only,
+/// so it never leaks to the next queries of the pooled connection.
+pub(crate) async fn explain(
+    State(state): State<Arc<UQueryState>>,
+    caller: Caller,
+    headers: HeaderMap,
+    Query(options): Query<ExplainOptions>,
+    query_request: QueryRequest,
+) -> Result<Response, UQueryError> {
+    let query_id = queries::query_id(&headers)?;
+    let scope = caller.scope();
+    let timeouts = state.timeouts.lowered_by(&headers, &scope)?;
+    let sql = format!(
+        "EXPLAIN ({}FORMAT json) {}",
+        if options.analyze { "ANALYZE, " } else { "" },
+        query_request.get_sql_query().trim().trim_end_matches(';')
+    );
+    let params = query_request.get_params().clone();
+    let registry = Arc::clone(&state.queries);
+    if !registry.register(&query_id, caller.owner()) {
+        return Err(UQueryError {
+            status_code: StatusCode::CONFLICT.as_u16(),
+            title: "Duplicate Query Id".to_string(),
+            detail: format!("query [{query_id}] is already running"),
+        });
+    }
+    // interrupts the query if the timeout elapses or the client goes away
+    let mut guard = QueryGuard::new(query_id.clone(), Arc::clone(&registry));
+    let engine = Arc::clone(&state.engine);
+    let task_query_id = query_id.clone();
+    let span = Span::current();
+    let task = spawn_blocking(move || {
+        let _span = span.entered();
+        let result = engine
+            .prepare(&sql, params, scope)
+            .and_then(|mut prepared| {
+                if !registry.attach(&task_query_id, prepared.interrupt_handle()) {
+                    return Err(QueryError::Execution("query cancelled".to_string()));
+                }
+                let mut plan = PlanConsumer::default();
+                prepared.execute(&mut plan).map(|_| plan.plan)
+            });
+        registry.remove(&task_query_id);
+        result
+    });
+    let joined = match timeouts.shortest() {
+        Some(timeout) => tokio::time::timeout(timeout, task)
+            .await
+            .map_err(|_| query_timeout_error(format!("no plan within {timeout:?}")))?,
+        None => task.await,
+    };
+    guard.disarm();
+    let plan = joined
+        .map_err(|e| UQueryError {
+            status_code: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+            title: "Internal Error".to_string(),
+            detail: e.to_string(),
+        })?
+        .map_err(query_error)?;
+    let plan: Value = serde_json::from_str(&plan).map_err(|e| UQueryError {
+        status_code: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+        title: "Internal Error".to_string(),
+        detail: format!("invalid plan: {e}"),
+    })?;
+    Ok((
+        [(QUERY_ID_HEADER, query_id)],
+        Json(plan_tree(plan, options.analyze)),
+    )
+        .into_response())
+}
+
+/// Turn DuckDB's JSON plan into `{"analyze", "metrics", "plan": [node]}` where every
+/// node is `{"name", "estimated_cardinality", "cardinality", "timing", "rows_scanned",
+/// "extra_info", "children"}`. Query level metrics (`latency`, `rows_returned`...) are
+/// only there with `analyze`, as are the actual cardinality, timing and rows scanned.
+fn plan_tree(plan: Value, analyze: bool) -> Value {
+    let (metrics, children) = match plan {
+        // EXPLAIN ANALYZE: the query with its metrics, the plan being its children
+        Value::Object(mut query) => {
+            let children = query.remove("children").unwrap_or_default();
+            query.remove("extra_info");
+            (Value::Object(query), children)
+        }
+        // EXPLAIN: the root operators
+        plan => (json!({}), plan),
+    };
+    json!({
+        "analyze": analyze,
+        "metrics": metrics,
+        "plan": plan_nodes(children),
+    })
+}
+
+fn plan_nodes(nodes: Value) -> Vec<Value> {
+    match nodes {
+        Value::Array(nodes) => nodes.into_iter().map(plan_node).collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn plan_node(node: Value) -> Value {
+    let Value::Object(mut node) = node else {
+        return node;
+    };
+    let mut extra_info = match node.remove("extra_info") {
+        Some(Value::Object(extra_info)) => extra_info,
+        _ => Map::new(),
+    };
+    let estimated_cardinality = extra_info
+        .remove(ESTIMATED_CARDINALITY)
+        .or_else(|| extra_info.remove("Estimated Cardinality"))
+        .and_then(|estimate| {
+            estimate
+                .as_str()?
+                .trim_start_matches('~')
+                .parse::<u64>()
+                .ok()
+        });
+    let name = ["operator_name", "name", "operator_type"]
+        .iter()
+        .find_map(|key| node.get(*key).and_then(Value::as_str))
+        .unwrap_or_default()
+        .trim()
+        .to_string();
+    let mut tree = Map::new();
+    tree.insert("name".to_string(), json!(name));
+    tree.insert(
+        "estimated_cardinality".to_string(),
+        json!(estimated_cardinality),
+    );
+    for (key, metric) in [
+        ("cardinality", "operator_cardinality"),
+        ("timing", "operator_timing"),
+        ("rows_scanned", "operator_rows_scanned"),
+    ] {
+        if let Some(value) = node.remove(metric) {
+            tree.insert(key.to_string(), value);
+        }
+    }
+    tree.insert("extra_info".to_string(), Value::Object(extra_info));
+    tree.insert(
+        "children".to_string(),
+        json!(plan_nodes(node.remove("children").unwrap_or_default())),
+    );
+    Value::Object(tree)
+}
+
+/// Keeps the plan of the `explain_value` column, the last one of the result.
+#[derive(Default)]
+struct PlanConsumer {
+    plan: String,
+}
+
+impl RecordBatchConsumer for PlanConsumer {
+    fn on_schema(&mut self, _schema: SchemaRef) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn on_batch(&mut self, batch: RecordBatch) -> Result<(), String> {
+        if batch.num_rows() == 0 || batch.num_columns() == 0 {
+            return Ok(());
+        }
+        let values = cast(batch.column(batch.num_columns() - 1), &DataType::Utf8)
+            .map_err(|e| e.to_string())?;
+        let values = values.as_string::<i32>();
+        if values.is_valid(values.len() - 1) {
+            self.plan = values.value(values.len() - 1).to_string();
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
@@ -0,0 +1,480 @@
+use crate::core::engine::{ExecutableQuery, QueryError, RecordBatchConsumer};
+use crate::core::error::UQueryError;
+use crate::web::auth::Caller;
+use crate::web::consumers::{ArrowConsumer, RowCounter};
+use crate::web::limits::HeldPermit;
+use crate::web::request::QueryRequest;
+use crate::web::response::accepts_trailers;
+use crate::web::routers::{UQueryState, negotiate_format, query_error, stream_query};
+use crate::web::timeouts::QueryTimeouts;
+use crate::web::{new_id, periodically};
+use arrow::ipc::reader::StreamReader;
+use axum::extract::{Path, Query, State};
+use axum::http::header::LOCATION;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Json};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::oneshot;
+use tokio::task::spawn_blocking;
+use tokio::time::Instant;
+use tracing::{Span, debug, error, info_span, warn};
+
+const RESULT_EXTENSION: &str = "arrows";
+
+/// How often expired jobs and orphaned results are deleted.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+/// Status of an asynchronous query, as returned by `GET /jobs/{id}`.
+/// Timestamps are milliseconds since the Unix epoch.
+#[derive(Debug, Clone, Serialize)]
+pub struct Job {
+    id: String,
+    /// Name of the caller who submitted the job, the only one allowed to see it.
+    #[serde(skip)]
+    owner: Option<String>,
+    status: JobStatus,
+    submitted_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    started_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    finished_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    elapsed_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rows: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    truncated: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Keeps track of asynchronous jobs and spills their results as Arrow IPC streams
+/// in `dir`. Finished jobs are purged once `retention` has elapsed, and at most
+/// `max_pending` jobs are queued or running at once.
+pub struct JobStore {
+    dir: PathBuf,
+    retention: Duration,
+    max_pending: usize,
+    jobs: Mutex<HashMap<String, Job>>,
+}
+
+impl JobStore {
+    /// A store in `dir`, created readable by the current user only when missing.
+    pub fn new(dir: PathBuf, retention: Duration, max_pending: usize) -> Result<Self, String> {
+        let mut builder = fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        builder.create(&dir).map_err(|e| e.to_string())?;
+        let store = Self {
+            dir,
+            retention,
+            max_pending,
+            jobs: Mutex::new(HashMap::new()),
+        };
+        // results left by a previous run belong to no job
+        store.remove_orphans();
+        Ok(store)
+    }
+
+    fn result_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{id}.{RESULT_EXTENSION}"))
+    }
+
+    /// Queue a new job of `owner`, unless `max_pending` jobs are already queued or
+    /// running.
+    fn submit(&self, owner: Option<String>) -> Result<Job, String> {
+        self.purge_expired();
+        let mut jobs = self.jobs.lock().unwrap();
+        let pending = jobs
+            .values()
+            .filter(|job| matches!(job.status, JobStatus::Queued | JobStatus::Running))
+            .count();
+        if pending >= self.max_pending {
+            return Err(format!(
+                "{pending} jobs are already queued or running, the most allowed"
+            ));
+        }
+        let job = Job {
+            id: new_id(),
+            owner,
+            status: JobStatus::Queued,
+            submitted_at: now_millis(),
+            started_at: None,
+            finished_at: None,
+            elapsed_ms: None,
+            rows: None,
+            truncated: None,
+            error: None,
+        };
+        jobs.insert(job.id.clone(), job.clone());
+        Ok(job)
+    }
+
+    /// Job `id`, unless it was submitted by another caller than `owner`.
+    fn get(&self, id: &str, owner: Option<&str>) -> Option<Job> {
+        self.purge_expired();
+        self.jobs
+            .lock()
+            .unwrap()
+            .get(id)
+            .filter(|job| job.owner.as_deref() == owner)
+            .cloned()
+    }
+
+    fn update<F: FnOnce(&mut Job)>(&self, id: &str, f: F) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(id) {
+            f(job);
+        }
+    }
+
+    /// Mark job `id` as failed with `error` without running it.
+    fn fail(&self, id: &str, error: String) {
+        let finished_at = now_millis();
+        self.update(id, |job| {
+            job.status = JobStatus::Failed;
+            job.finished_at = Some(finished_at);
+            job.error = Some(error);
+        });
+    }
+
+    fn purge_expired(&self) {
+        let retention = self.retention.as_millis() as u64;
+        let now = now_millis();
+        let mut jobs = self.jobs.lock().unwrap();
+        let expired: Vec<String> = jobs
+            .values()
+            .filter(|job| {
+                job.finished_at
+                    .is_some_and(|t| now.saturating_sub(t) > retention)
+            })
+            .map(|job| job.id.clone())
+            .collect();
+        for id in expired {
+            jobs.remove(&id);
+            let _ = fs::remove_file(self.result_path(&id));
+            debug!("job {id} expired");
+        }
+    }
+
+    /// Delete the results older than the retention that belong to no job, such as the
+    /// ones of a previous run. Younger ones may belong to another server sharing `dir`.
+    fn remove_orphans(&self) {
+        let Ok(entries) = fs::read_dir(&self.dir) else {
+            return;
+        };
+        let now = SystemTime::now();
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().is_none_or(|ext| ext != RESULT_EXTENSION) {
+                continue;
+            }
+            let known = path
+                .file_stem()
+                .and_then(|id| id.to_str())
+                .is_some_and(|id| self.jobs.lock().unwrap().contains_key(id));
+            let expired = entry
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .is_ok_and(|modified| {
+                    now.duration_since(modified)
+                        .is_ok_and(|age| age > self.retention)
+                });
+            if !known && expired {
+                match fs::remove_file(&path) {
+                    Ok(()) => debug!("orphaned result {} removed", path.display()),
+                    Err(e) => warn!("failed to remove {}: {e}", path.display()),
+                }
+            }
+        }
+    }
+
+    /// Execute `query` and spill its result, recording progress on job `id`.
+    fn run(&self, id: &str, mut query: Box<dyn ExecutableQuery>) {
+        let started_at = now_millis();
+        self.update(id, |job| {
+            job.status = JobStatus::Running;
+            job.started_at = Some(started_at);
+        });
+        let path = self.result_path(id);
+        let result = File::create(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|file| {
+                let mut consumer = RowCounter::new(ArrowConsumer::new(BufWriter::new(file)));
+                query
+                    .execute(&mut consumer)
+                    .map(|_| (consumer.rows(), consumer.truncated()))
+                    .map_err(|e| e.to_string())
+            });
+        let finished_at = now_millis();
+        if let Err(e) = &result {
+            error!("job {id} failed: {e}");
+            let _ = fs::remove_file(&path);
+        }
+        self.update(id, |job| {
+            job.finished_at = Some(finished_at);
+            job.elapsed_ms = Some(finished_at.saturating_sub(started_at));
+            match result {
+                Ok((rows, truncated)) => {
+                    job.status = JobStatus::Succeeded;
+                    job.rows = Some(rows);
+                    job.truncated = Some(truncated);
+                }
+                Err(e) => {
+                    job.status = JobStatus::Failed;
+                    job.error = Some(e);
+                }
+            }
+        });
+    }
+}
+
+/// Delete the expired jobs and the orphaned results every [`SWEEP_INTERVAL`], rather
+/// than only when jobs are accessed, as long as `jobs` is in use.
+pub fn sweep(jobs: &Arc<JobStore>) {
+    periodically(jobs, SWEEP_INTERVAL, |jobs| {
+        jobs.purge_expired();
+        jobs.remove_orphans();
+    });
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Replays a spilled job result so it can be served in any response format.
+struct StoredResult {
+    path: PathBuf,
+    /// Whether the job left rows out of the result.
+    truncated: bool,
+}
+
+impl ExecutableQuery for StoredResult {
+    fn execute(&mut self, consumer: &mut dyn RecordBatchConsumer) -> Result<(), QueryError> {
+        let file = File::open(&self.path).map_err(|e| e.to_string())?;
+        let reader = StreamReader::try_new_buffered(file, None).map_err(|e| e.to_string())?;
+        consumer.on_schema(reader.schema())?;
+        for batch in reader {
+            consumer.on_batch(batch.map_err(|e| e.to_string())?)?;
+        }
+        if self.truncated {
+            consumer.on_truncated();
+        }
+        Ok(consumer.finish()?)
+    }
+}
+
+pub(crate) async fn submit_job(
+    State(state): State<Arc<UQueryState>>,
+    caller: Caller,
+    permit: Option<Extension<HeldPermit>>,
+    query_request: QueryRequest,
+) -> Response {
+    let job = match state.jobs.submit(caller.owner()) {
+        Ok(job) => job,
+        Err(detail) => return query_error(QueryError::Unavailable(detail)).into_response(),
+    };
+    let id = job.id.clone();
+    let sql = query_request.get_sql_query().to_string();
+    let params = query_request.get_params().clone();
+    let scope = caller.scope();
+    let timeout = scope.timeout;
+    let task_state = Arc::clone(&state);
+    // jobs are registered under their own id so they can be cancelled like queries
+    state.queries.register(&id, caller.owner());
+    // the watchdog interrupts the job once the timeout of the caller has elapsed, and
+    // stops as soon as the job ends and drops `watchdog_tx`
+    let (watchdog_tx, watchdog_rx) = oneshot::channel::<()>();
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    if let Some(deadline) = deadline {
+        let queries = Arc::clone(&state.queries);
+        let id = id.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = tokio::time::sleep_until(deadline) => {
+                    queries.cancel(&id);
+                }
+                _ = watchdog_rx => {}
+            }
+        });
+    }
+    // a job outlives its request: it has a trace of its own, linked to the request
+    let span = info_span!(parent: None, "job", job.id = %id);
+    span.follows_from(Span::current());
+    spawn_blocking(move || {
+        let _span = span.entered();
+        let _watchdog = watchdog_tx;
+        // the job counts against the concurrency limits until it ends, not its response
+        let _permit = permit;
+        match task_state.engine.prepare(&sql, params, scope) {
+            Ok(query) if task_state.queries.attach(&id, query.interrupt_handle()) => {
+                task_state.jobs.run(&id, query);
+            }
+            Ok(_) => task_state.jobs.fail(&id, "job cancelled".to_string()),
+            Err(e) => task_state.jobs.fail(&id, e.to_string()),
+        }
+        if let (Some(deadline), Some(timeout)) = (deadline, timeout)
+            && Instant::now() >= deadline
+        {
+            task_state.jobs.update(&id, |job| {
+                if job.status == JobStatus::Failed {
+                    job.error = Some(format!("job exceeded its timeout of {timeout:?}"));
+                }
+            });
+        }
+        task_state.queries.remove(&id);
+    });
+    (
+        StatusCode::ACCEPTED,
+        [(LOCATION, format!("/jobs/{}", job.id))],
+        Json(job),
+    )
+        .into_response()
+}
+
+pub(crate) async fn get_job(
+    State(state): State<Arc<UQueryState>>,
+    caller: Caller,
+    Path(id): Path<String>,
+) -> Result<Response, UQueryError> {
+    let job = state
+        .jobs
+        .get(&id, caller.owner().as_deref())
+        .ok_or_else(|| job_not_found(&id))?;
+    Ok(Json(job).into_response())
+}
+
+pub(crate) async fn get_job_result(
+    State(state): State<Arc<UQueryState>>,
+    caller: Caller,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    Query(format_options): Query<Vec<(String, String)>>,
+) -> Result<Response, UQueryError> {
+    let format = negotiate_format(&headers, format_options)?;
+    let owner = caller.owner();
+    let job = state
+        .jobs
+        .get(&id, owner.as_deref())
+        .ok_or_else(|| job_not_found(&id))?;
+    match job.status {
+        JobStatus::Succeeded => {}
+        JobStatus::Failed => {
+            return Err(UQueryError {
+                status_code: StatusCode::CONFLICT.as_u16(),
+                title: "Job Failed".to_string(),
+                detail: job.error.unwrap_or_default(),
+            });
+        }
+        JobStatus::Queued | JobStatus::Running => {
+            return Err(UQueryError {
+                status_code: StatusCode::CONFLICT.as_u16(),
+                title: "Job Not Finished".to_string(),
+                detail: format!("job [{id}] is still running"),
+            });
+        }
+    }
+    let truncated = job.truncated.unwrap_or_default();
+    let path = state.jobs.result_path(&id);
+    if !path.exists() {
+        warn!("result of job {id} is missing");
+        return Err(job_not_found(&id));
+    }
+    stream_query(
+        &state,
+        new_id(),
+        owner,
+        format,
+        QueryTimeouts::default(),
+        accepts_trailers(&headers),
+        move || Ok(Box::new(StoredResult { path, truncated })),
+    )
+    .await
+}
+
+fn job_not_found(id: &str) -> UQueryError {
+    UQueryError {
+        status_code: StatusCode::NOT_FOUND.as_u16(),
+        title: "Job Not Found".to_string(),
+        detail: format!("job [{id}] does not exist or has expired"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jobs_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("uquery-jobs-{}", new_id()))
+    }
+
+    #[test]
+    fn pending_jobs_are_limited() {
+        let dir = jobs_dir();
+        let store = JobStore::new(dir.clone(), Duration::from_secs(60), 2).unwrap();
+        let first = store.submit(None).unwrap();
+        store.submit(None).unwrap();
+        assert!(store.submit(None).is_err());
+        store.fail(&first.id, "job cancelled".to_string());
+        store.submit(None).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn created_dir_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = jobs_dir().join("results");
+        JobStore::new(dir.clone(), Duration::from_secs(60), 1).unwrap();
+        let mode = fs::metadata(&dir).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+        fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn orphaned_results_are_removed() {
+        let dir = jobs_dir();
+        fs::create_dir_all(&dir).unwrap();
+        let old = SystemTime::now() - Duration::from_secs(120);
+        for name in ["orphan.arrows", "recent.arrows", "other.txt"] {
+            let file = File::create(dir.join(name)).unwrap();
+            if name != "recent.arrows" {
+                file.set_modified(old).unwrap();
+            }
+        }
+        let store = JobStore::new(dir.clone(), Duration::from_secs(60), 1).unwrap();
+        assert!(!dir.join("orphan.arrows").exists());
+        assert!(dir.join("recent.arrows").exists());
+        assert!(dir.join("other.txt").exists());
+
+        // results of known jobs are kept, however old
+        let job = store.submit(None).unwrap();
+        File::create(store.result_path(&job.id))
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+        store.remove_orphans();
+        assert!(store.result_path(&job.id).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
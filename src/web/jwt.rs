@@ -0,0 +1,602 @@
+use crate::core::claims::{claim, claim_matches};
+use crate::core::error::UQueryError;
+use crate::web::auth::{Grants, Principal, unauthorized};
+use axum::http::StatusCode;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use ring::signature::{
+    ECDSA_P256_SHA256_FIXED, RSA_PKCS1_2048_8192_SHA256, RsaPublicKeyComponents, UnparsedPublicKey,
+};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+/// Leeway on `exp` and `nbf`, for the clock skew between the issuer and the server.
+const CLOCK_SKEW_SECS: f64 = 60.0;
+
+/// Shortest delay between two fetches of the key set caused by tokens signed with an
+/// unknown key, so that forged tokens can't hammer the identity provider.
+const JWKS_MIN_REFRESH: Duration = Duration::from_secs(30);
+
+const JWKS_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Settings of the JWT bearer authentication.
+#[derive(Debug, Clone)]
+pub struct JwtConfig {
+    /// Path or `http(s)` URL of the JSON Web Key Set of the issuer.
+    pub jwks: String,
+    /// How long the key set is cached before being fetched again.
+    pub jwks_cache: Duration,
+    /// Required `iss` claim, not checked when absent.
+    pub issuer: Option<String>,
+    /// Audience that the `aud` claim must contain, not checked when absent.
+    pub audience: Option<String>,
+    /// Claims exposed to the query pipeline besides `sub`, nested ones as `a.b`.
+    pub claims: Vec<String>,
+    /// File of the policies granting permissions by claim: every valid token is
+    /// granted every permission without it.
+    pub policies: Option<PathBuf>,
+}
+
+impl JwtConfig {
+    pub fn new(jwks: String) -> Self {
+        Self {
+            jwks,
+            jwks_cache: Duration::from_secs(300),
+            issuer: None,
+            audience: None,
+            claims: Vec::new(),
+            policies: None,
+        }
+    }
+}
+
+/// Permissions granted to the tokens whose claims match.
+#[derive(Debug, Deserialize)]
+struct Policy {
+    name: String,
+    /// Claim values required by the policy, see [`claim_matches`].
+    #[serde(default)]
+    claims: BTreeMap<String, Value>,
+    #[serde(flatten)]
+    grants: Grants,
+    #[serde(flatten)]
+    unknown: BTreeMap<String, Value>,
+}
+
+impl Policy {
+    fn matches(&self, claims: &Map<String, Value>) -> bool {
+        self.claims.iter().all(|(name, expected)| {
+            claim(claims, name).is_some_and(|value| claim_matches(value, expected))
+        })
+    }
+}
+
+fn parse_policies(json: &str) -> Result<Vec<Policy>, String> {
+    let policies: Vec<Policy> = serde_json::from_str(json).map_err(|e| e.to_string())?;
+    for policy in &policies {
+        policy.grants.validate(&policy.name, &policy.unknown)?;
+    }
+    Ok(policies)
+}
+
+/// A public key of the key set, able to check signatures of its algorithm.
+struct Jwk {
+    kid: Option<String>,
+    alg: &'static str,
+    key: VerifyingKey,
+}
+
+enum VerifyingKey {
+    Rsa {
+        n: Vec<u8>,
+        e: Vec<u8>,
+    },
+    /// Uncompressed P-256 point: `0x04`, then `x` and `y`.
+    P256(Vec<u8>),
+}
+
+impl Jwk {
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        match &self.key {
+            VerifyingKey::Rsa { n, e } => RsaPublicKeyComponents { n, e }
+                .verify(&RSA_PKCS1_2048_8192_SHA256, message, signature)
+                .is_ok(),
+            VerifyingKey::P256(point) => UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, point)
+                .verify(message, signature)
+                .is_ok(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RawJwk {
+    kty: String,
+    kid: Option<String>,
+    alg: Option<String>,
+    #[serde(rename = "use")]
+    usage: Option<String>,
+    crv: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawJwkSet {
+    keys: Vec<RawJwk>,
+}
+
+/// The RS256 and ES256 signing keys of a JSON Web Key Set. Encryption keys and keys of
+/// other algorithms are skipped.
+fn parse_jwks(json: &str) -> Result<Vec<Jwk>, String> {
+    let set: RawJwkSet = serde_json::from_str(json).map_err(|e| format!("invalid JWKS: {e}"))?;
+    let decode = |value: Option<String>| -> Option<Vec<u8>> { URL_SAFE_NO_PAD.decode(value?).ok() };
+    let keys = set
+        .keys
+        .into_iter()
+        .filter(|jwk| jwk.usage.as_deref().is_none_or(|usage| usage == "sig"))
+        .filter_map(|jwk| {
+            let (alg, key) = match (jwk.kty.as_str(), jwk.crv.as_deref()) {
+                ("RSA", _) => (
+                    "RS256",
+                    VerifyingKey::Rsa {
+                        n: decode(jwk.n)?,
+                        e: decode(jwk.e)?,
+                    },
+                ),
+                ("EC", Some("P-256")) => {
+                    let (x, y) = (decode(jwk.x)?, decode(jwk.y)?);
+                    if x.len() != 32 || y.len() != 32 {
+                        return None;
+                    }
+                    ("ES256", VerifyingKey::P256([&[4], &x[..], &y[..]].concat()))
+                }
+                _ => return None,
+            };
+            jwk.alg
+                .is_none_or(|declared| declared == alg)
+                .then_some(Jwk {
+                    kid: jwk.kid,
+                    alg,
+                    key,
+                })
+        })
+        .collect();
+    Ok(keys)
+}
+
+struct KeySet {
+    keys: Vec<Jwk>,
+}
+
+impl KeySet {
+    /// The keys that may have signed a token of `alg` and `kid`.
+    fn candidates<'a>(
+        &'a self,
+        alg: &'a str,
+        kid: Option<&'a str>,
+    ) -> impl Iterator<Item = &'a Jwk> + 'a {
+        self.keys
+            .iter()
+            .filter(move |jwk| jwk.alg == alg && (kid.is_none() || jwk.kid.as_deref() == kid))
+    }
+}
+
+/// Validates RS256 and ES256 tokens against the cached key set of their issuer, and
+/// grants them the permissions of the first matching policy.
+pub struct JwtValidator {
+    config: JwtConfig,
+    policies: Vec<Policy>,
+    client: reqwest::Client,
+    /// The key set, with the time it was fetched.
+    key_set: Mutex<Option<(Arc<KeySet>, Instant)>>,
+}
+
+impl JwtValidator {
+    pub fn new(config: JwtConfig) -> Result<Self, String> {
+        let policies = match &config.policies {
+            Some(file) => {
+                let json =
+                    fs::read_to_string(file).map_err(|e| format!("{}: {e}", file.display()))?;
+                parse_policies(&json).map_err(|e| format!("{}: {e}", file.display()))?
+            }
+            None => Vec::new(),
+        };
+        let client = reqwest::Client::builder()
+            .timeout(JWKS_FETCH_TIMEOUT)
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(Self {
+            config,
+            policies,
+            client,
+            key_set: Mutex::new(None),
+        })
+    }
+
+    /// The principal of a valid `token`: `401 Unauthorized` when its signature or its
+    /// `exp`, `nbf`, `iss` or `aud` claims are invalid, `403 Forbidden` when no policy
+    /// grants it access.
+    pub async fn validate(&self, token: &str) -> Result<Principal, UQueryError> {
+        let invalid = |detail: &str| unauthorized(&format!("invalid token: {detail}"));
+        let (message, signature) = token.rsplit_once('.').ok_or_else(|| invalid("malformed"))?;
+        let (header, payload) = message
+            .split_once('.')
+            .ok_or_else(|| invalid("malformed"))?;
+        let header = decode_json(header).ok_or_else(|| invalid("malformed header"))?;
+        let claims = decode_json(payload).ok_or_else(|| invalid("malformed claims"))?;
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| invalid("malformed signature"))?;
+        let alg = match header.get("alg").and_then(Value::as_str) {
+            Some(alg @ ("RS256" | "ES256")) => alg,
+            alg => {
+                let alg = alg.unwrap_or("none");
+                return Err(invalid(&format!("unsupported algorithm {alg}")));
+            }
+        };
+        let kid = header.get("kid").and_then(Value::as_str);
+
+        let mut key_set = self.key_set(false).await?;
+        if key_set.candidates(alg, kid).next().is_none() {
+            // the issuer may have rotated its keys
+            key_set = self.key_set(true).await?;
+        }
+        let verified = key_set
+            .candidates(alg, kid)
+            .any(|jwk| jwk.verify(message.as_bytes(), &signature));
+        if !verified {
+            return Err(invalid(
+                "the signature does not match any key of the key set",
+            ));
+        }
+        let subject = self
+            .check_claims(&claims)
+            .map_err(|detail| invalid(&detail))?;
+        self.principal(subject, &claims)
+    }
+
+    /// Check the validity of the claims, returning the subject of the token.
+    fn check_claims<'a>(&self, claims: &'a Map<String, Value>) -> Result<&'a str, String> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| e.to_string())?
+            .as_secs_f64();
+        let expires = claims
+            .get("exp")
+            .and_then(Value::as_f64)
+            .ok_or("the exp claim is required")?;
+        if now > expires + CLOCK_SKEW_SECS {
+            return Err("expired".to_string());
+        }
+        if let Some(not_before) = claims.get("nbf").and_then(Value::as_f64)
+            && now + CLOCK_SKEW_SECS < not_before
+        {
+            return Err("not valid yet".to_string());
+        }
+        if let Some(issuer) = &self.config.issuer
+            && claims.get("iss").and_then(Value::as_str) != Some(issuer)
+        {
+            return Err(format!("the issuer is not {issuer}"));
+        }
+        if let Some(audience) = &self.config.audience {
+            let audience = Value::String(audience.clone());
+            if !claims
+                .get("aud")
+                .is_some_and(|aud| claim_matches(aud, &audience))
+            {
+                return Err(format!("the audience does not include {audience}"));
+            }
+        }
+        // the subject owns the queries and jobs of the token, and is limited on its own
+        claims
+            .get("sub")
+            .and_then(Value::as_str)
+            .filter(|subject| !subject.is_empty())
+            .ok_or_else(|| "the sub claim is required, as a string".to_string())
+    }
+
+    fn principal(
+        &self,
+        subject: &str,
+        claims: &Map<String, Value>,
+    ) -> Result<Principal, UQueryError> {
+        let name = subject.to_string();
+        let grants = match self.policies.is_empty() {
+            true => Grants::all(),
+            false => self
+                .policies
+                .iter()
+                .find(|policy| policy.matches(claims))
+                .map(|policy| policy.grants.clone())
+                .ok_or_else(|| UQueryError {
+                    status_code: StatusCode::FORBIDDEN.as_u16(),
+                    title: "Forbidden".to_string(),
+                    detail: format!("no policy grants access to [{name}]"),
+                })?,
+        };
+        let exposed = std::iter::once("sub")
+            .chain(self.config.claims.iter().map(String::as_str))
+            .filter_map(|name| Some((name.to_string(), claim(claims, name)?.clone())))
+            .collect();
+        Ok(Principal {
+            id: format!("jwt:{name}"),
+            name,
+            grants,
+            claims: exposed,
+        })
+    }
+
+    /// The cached key set, fetched again once expired or, with `refresh`, when it
+    /// is older than [`JWKS_MIN_REFRESH`]. A key set that can't be fetched again is
+    /// still used until the next expiry.
+    async fn key_set(&self, refresh: bool) -> Result<Arc<KeySet>, UQueryError> {
+        let mut cached = self.key_set.lock().await;
+        if let Some((key_set, fetched)) = cached.as_ref() {
+            let age = fetched.elapsed();
+            if age < self.config.jwks_cache && !(refresh && age >= JWKS_MIN_REFRESH) {
+                return Ok(Arc::clone(key_set));
+            }
+        }
+        let key_set = match (self.fetch_keys().await, cached.take()) {
+            (Ok(keys), _) => {
+                info!("loaded {} keys from {}", keys.len(), self.config.jwks);
+                Arc::new(KeySet { keys })
+            }
+            (Err(e), Some((stale, _))) => {
+                warn!("failed to refresh the key set, keeping the previous keys: {e}");
+                stale
+            }
+            (Err(e), None) => {
+                return Err(UQueryError {
+                    status_code: StatusCode::SERVICE_UNAVAILABLE.as_u16(),
+                    title: "Key Set Unavailable".to_string(),
+                    detail: e,
+                });
+            }
+        };
+        *cached = Some((Arc::clone(&key_set), Instant::now()));
+        Ok(key_set)
+    }
+
+    async fn fetch_keys(&self) -> Result<Vec<Jwk>, String> {
+        let jwks = &self.config.jwks;
+        let json = if jwks.starts_with("https://") || jwks.starts_with("http://") {
+            self.client
+                .get(jwks)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map_err(|e| e.to_string())?
+                .text()
+                .await
+                .map_err(|e| e.to_string())?
+        } else {
+            tokio::fs::read_to_string(jwks)
+                .await
+                .map_err(|e| format!("{jwks}: {e}"))?
+        };
+        parse_jwks(&json)
+    }
+}
+
+/// A base64url encoded JSON object of a token.
+fn decode_json(part: &str) -> Option<Map<String, Value>> {
+    serde_json::from_slice(&URL_SAFE_NO_PAD.decode(part).ok()?).ok()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{ECDSA_P256_SHA256_FIXED_SIGNING, EcdsaKeyPair, KeyPair};
+    use serde_json::json;
+
+    /// An ES256 signing key, with its key set.
+    pub(crate) struct TestSigner {
+        key_pair: EcdsaKeyPair,
+        pub kid: String,
+    }
+
+    impl TestSigner {
+        pub(crate) fn new(kid: &str) -> Self {
+            let rng = SystemRandom::new();
+            let pkcs8 =
+                EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+            let key_pair =
+                EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng)
+                    .unwrap();
+            Self {
+                key_pair,
+                kid: kid.to_string(),
+            }
+        }
+
+        pub(crate) fn jwks(&self) -> Value {
+            let point = self.key_pair.public_key().as_ref();
+            json!({"keys": [{
+                "kty": "EC", "crv": "P-256", "use": "sig", "kid": self.kid,
+                "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+                "y": URL_SAFE_NO_PAD.encode(&point[33..]),
+            }]})
+        }
+
+        pub(crate) fn sign(&self, claims: Value) -> String {
+            let header = json!({"alg": "ES256", "typ": "JWT", "kid": self.kid});
+            let message = format!(
+                "{}.{}",
+                URL_SAFE_NO_PAD.encode(header.to_string()),
+                URL_SAFE_NO_PAD.encode(claims.to_string())
+            );
+            let signature = self
+                .key_pair
+                .sign(&SystemRandom::new(), message.as_bytes())
+                .unwrap();
+            format!("{message}.{}", URL_SAFE_NO_PAD.encode(signature.as_ref()))
+        }
+    }
+
+    pub(crate) fn expires_in(secs: i64) -> i64 {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        now.as_secs() as i64 + secs
+    }
+
+    /// A validator of the tokens of `signer`, with its key set in a temporary file.
+    fn validator(signer: &TestSigner, configure: impl FnOnce(&mut JwtConfig)) -> JwtValidator {
+        jwks_validator(signer.jwks(), configure)
+    }
+
+    fn jwks_validator(jwks: Value, configure: impl FnOnce(&mut JwtConfig)) -> JwtValidator {
+        let file = std::env::temp_dir().join(format!("uquery-jwks-{}.json", crate::web::new_id()));
+        fs::write(&file, jwks.to_string()).unwrap();
+        let mut config = JwtConfig::new(file.display().to_string());
+        config.issuer = Some("https://idp.example.com".to_string());
+        config.audience = Some("uquery".to_string());
+        configure(&mut config);
+        JwtValidator::new(config).unwrap()
+    }
+
+    fn claims(extra: Value) -> Value {
+        let mut claims = json!({
+            "iss": "https://idp.example.com", "aud": ["uquery", "other"],
+            "sub": "alice", "exp": expires_in(600),
+        });
+        claims
+            .as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        claims
+    }
+
+    #[tokio::test]
+    async fn valid_tokens_expose_claims() {
+        let signer = TestSigner::new("k1");
+        let validator = validator(&signer, |config| {
+            config.claims = vec!["tenant".to_string(), "realm.roles".to_string()];
+        });
+        let token = signer.sign(claims(json!({
+            "tenant": "acme", "realm": {"roles": ["analyst"]}, "email": "alice@acme.com"
+        })));
+        let principal = validator.validate(&token).await.unwrap();
+        assert_eq!(principal.name, "alice");
+        assert_eq!(principal.id, "jwt:alice");
+        assert_eq!(principal.grants, Grants::all());
+        assert_eq!(
+            principal.claims,
+            BTreeMap::from([
+                ("realm.roles".to_string(), json!(["analyst"])),
+                ("sub".to_string(), json!("alice")),
+                ("tenant".to_string(), json!("acme")),
+            ])
+        );
+    }
+
+    /// A token of `bob` expiring in 2100, signed with openssl by the key of [`RSA_N`].
+    const RS256_TOKEN: &str = "eyJhbGciOiJSUzI1NiIsImtpZCI6InJzYTEifQ.\
+        eyJpc3MiOiJodHRwczovL2lkcC5leGFtcGxlLmNvbSIsImF1ZCI6InVxdWVyeSIsInN1YiI6ImJvYiIsImV4cCI6NDEwMjQ0NDgwMH0.\
+        I5VglFG4mRs9zvqXInqxtVjhV5QCYfb4WDPy0mtuN8wuPKpcXPw7DcVIeeU_4NnSenl01j7ai2z1m6g8jth3lXv9Owpgm1UA2j9QI02wvLk2X7GG\
+        bABC0-n5NXeO8Z6_gYgA9OMBuqvlzvZhIHyw4R7dh2DsSaIxHsQ_ZMGLh11ZgRvdtpugPpQNYguuQSSf05OYJ-tVTcaX_DK_7zwl6CPdPXyNeJL9\
+        lfwvWkrBTlQ1q3b4fMfpB07rc6oGur7Yl4wBWFWnvjQddlYN1m1-ARLBehay1vDq2nfQb6OeamoUuBhwgvlopydkV8ayUfZ7ESfh-m5GWUWdHudaR2-Q_A";
+
+    const RSA_N: &str = "1EDsvK0T8ILWWMfNxE3rECuzoeh9Dt2iLr9YCpVAWHtUrIgnMEcMzX95EIJxqjjg8EtQqjiFeg4UdnXqsc9yo9aj\
+        CKHBkeKDgtTkHtd5MbmyCPb-4BxicSG_YMOdf0OqT8OnAUlSe34I_iN7WFREeEedOyvndrQXclQ0Dx7SP2nM9lAA8iIJLLoZLUYlGuENUwhw4Dmy\
+        TrGKRAvsBEdL4BumJONrNtBLX0BD1bS0AKwX1WLrAkedc121UvfcWDMl-r_kMZZ5g_zVcxvlD6gvJ9MFLW7dofW1EX1M5JsO0fx-36fusAuzmD16\
+        QVHGzkhBMNvZWvivGgs5eFK8jKc58Q";
+
+    #[tokio::test]
+    async fn rs256_tokens_are_verified() {
+        let jwks = json!({"keys": [
+            {"kty": "RSA", "kid": "rsa1", "alg": "RS256", "n": RSA_N, "e": "AQAB"},
+            {"kty": "RSA", "kid": "enc", "use": "enc", "n": RSA_N, "e": "AQAB"},
+        ]});
+        let validator = jwks_validator(jwks, |_| {});
+        let principal = validator.validate(RS256_TOKEN).await.unwrap();
+        assert_eq!(principal.name, "bob");
+        let tampered = RS256_TOKEN.replacen("eyJpc3Mi", "eyJpc3mi", 1);
+        assert_eq!(
+            validator.validate(&tampered).await.unwrap_err().status_code,
+            401
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_tokens_are_unauthorized() {
+        let signer = TestSigner::new("k1");
+        let validator = validator(&signer, |_| {});
+        let other = TestSigner::new("k1");
+        let valid = signer.sign(claims(json!({})));
+        let (message, _) = valid.rsplit_once('.').unwrap();
+        let unsigned = format!("{}.{}.", URL_SAFE_NO_PAD.encode(r#"{"alg":"none"}"#), {
+            let (_, payload) = message.split_once('.').unwrap();
+            payload
+        });
+        for token in [
+            other.sign(claims(json!({}))),
+            signer.sign(claims(json!({"exp": expires_in(-3600)}))),
+            signer.sign(claims(json!({"nbf": expires_in(3600)}))),
+            signer.sign(claims(json!({"iss": "https://evil.example.com"}))),
+            signer.sign(claims(json!({"aud": "other"}))),
+            signer.sign(json!({"sub": "alice", "iss": "https://idp.example.com", "aud": "uquery"})),
+            signer.sign(
+                json!({"iss": "https://idp.example.com", "aud": "uquery", "exp": expires_in(600)}),
+            ),
+            signer.sign(claims(json!({"sub": 42}))),
+            signer.sign(claims(json!({"sub": ""}))),
+            format!("{message}.{}", URL_SAFE_NO_PAD.encode([0u8; 64])),
+            unsigned,
+            "not.a.jwt".to_string(),
+        ] {
+            let error = validator.validate(&token).await.unwrap_err();
+            assert_eq!(error.status_code, 401, "{}", error.detail);
+        }
+    }
+
+    #[tokio::test]
+    async fn policies_grant_by_claim() {
+        let signer = TestSigner::new("k1");
+        let policies =
+            std::env::temp_dir().join(format!("uquery-policies-{}.json", crate::web::new_id()));
+        fs::write(
+            &policies,
+            json!([
+                {"name": "admins", "claims": {"roles": "admin"}, "ad_hoc_sql": true},
+                {"name": "acme", "claims": {"tenant": ["acme", "acme-eu"]}, "queries": ["daily"]},
+            ])
+            .to_string(),
+        )
+        .unwrap();
+        let validator = validator(&signer, |config| config.policies = Some(policies));
+        let grants = |extra| async {
+            validator
+                .validate(&signer.sign(claims(extra)))
+                .await
+                .map(|principal| principal.grants)
+        };
+        assert!(
+            grants(json!({"roles": ["user", "admin"]}))
+                .await
+                .unwrap()
+                .ad_hoc_sql
+        );
+        let acme = grants(json!({"tenant": "acme-eu"})).await.unwrap();
+        assert!(!acme.ad_hoc_sql);
+        assert_eq!(acme.queries, Some(vec!["daily".to_string()]));
+        let error = grants(json!({"tenant": "globex"})).await.unwrap_err();
+        assert_eq!(error.status_code, 403);
+    }
+
+    #[test]
+    fn invalid_policies_are_rejected() {
+        assert!(parse_policies(r#"[{"name": "typo", "claim": {"roles": "admin"}}]"#).is_err());
+        assert!(parse_policies(r#"[{"name": "slow", "timeout": 0}]"#).is_err());
+    }
+}
@@ -0,0 +1,488 @@
+use crate::core::error::UQueryError;
+use crate::web::auth::{Grants, Principal};
+use axum::body::{Body, Bytes, HttpBody};
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::StatusCode;
+use axum::http::header::RETRY_AFTER;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use http_body::{Frame, SizeHint};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::debug;
+
+/// Rate buckets kept before the ones refilled to capacity, which no longer limit their
+/// caller, are dropped.
+const MAX_IDLE_BUCKETS: usize = 1024;
+
+/// Request rates and concurrent queries allowed per caller and across callers. Callers
+/// are identified by their API key or token subject, or by their IP address.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Limits {
+    /// Requests per second allowed to each caller, unless its grants say otherwise.
+    pub rate: Option<f64>,
+    /// Requests a caller may send at once after being idle, the rate rounded up by default.
+    pub burst: Option<u32>,
+    /// Queries each caller may run at the same time, unless its grants say otherwise.
+    pub max_concurrent: Option<usize>,
+    /// Requests per second allowed across callers.
+    pub global_rate: Option<f64>,
+    /// Queries run at the same time across callers.
+    pub global_max_concurrent: Option<usize>,
+}
+
+impl Limits {
+    /// Check the limits, the per-caller rate and concurrency being checked by
+    /// [`Grants::validate`].
+    fn validate(&self) -> Result<(), String> {
+        for (name, rate) in [("rate", self.rate), ("global rate", self.global_rate)] {
+            if rate.is_some_and(|rate| !rate.is_finite() || rate <= 0.0) {
+                return Err(format!("the {name} limit must be a positive number"));
+            }
+        }
+        if self.burst == Some(0) {
+            return Err("the burst must be at least 1".to_string());
+        }
+        for (name, max) in [
+            ("concurrent", self.max_concurrent),
+            ("global concurrent", self.global_max_concurrent),
+        ] {
+            if max == Some(0) {
+                return Err(format!("the {name} queries limit must be at least 1"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whom the limits of a request apply to.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum CallerId {
+    Principal(String),
+    Address(IpAddr),
+    /// Requests without principal nor known address share their limits.
+    Unknown,
+}
+
+impl fmt::Display for CallerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CallerId::Principal(name) => write!(f, "[{name}]"),
+            CallerId::Address(address) => write!(f, "[{address}]"),
+            CallerId::Unknown => write!(f, "this client"),
+        }
+    }
+}
+
+/// Tokens refilled at `rate` per second up to `capacity`, one being taken by request.
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, burst: Option<u32>, now: Instant) -> Self {
+        let capacity = burst.map_or(rate.ceil(), f64::from).max(1.0);
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// Take a token, or tell how long until the next one.
+    fn take(&mut self, now: Instant) -> Result<(), Duration> {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+    }
+
+    fn is_full(&self) -> bool {
+        self.tokens >= self.capacity
+    }
+}
+
+/// A request refused by a limit.
+#[derive(Debug)]
+struct Refusal {
+    /// Which limit refused it, as reported in the metrics.
+    limit: &'static str,
+    detail: String,
+    retry_after: Duration,
+}
+
+impl IntoResponse for Refusal {
+    fn into_response(self) -> Response {
+        let secs = self.retry_after.as_secs_f64().ceil().max(1.0) as u64;
+        let error = UQueryError {
+            status_code: StatusCode::TOO_MANY_REQUESTS.as_u16(),
+            title: "Too Many Requests".to_string(),
+            detail: self.detail,
+        };
+        ([(RETRY_AFTER, secs.to_string())], error).into_response()
+    }
+}
+
+/// Enforces the [`Limits`], tracking the requests of each caller.
+#[derive(Debug)]
+pub struct RateLimiter {
+    limits: Limits,
+    global_bucket: Option<Mutex<TokenBucket>>,
+    buckets: Mutex<HashMap<CallerId, TokenBucket>>,
+    running: AtomicUsize,
+    running_by_caller: Mutex<HashMap<CallerId, usize>>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: Limits) -> Result<Self, String> {
+        limits.validate()?;
+        let global_bucket = limits
+            .global_rate
+            .map(|rate| Mutex::new(TokenBucket::new(rate, None, Instant::now())));
+        Ok(Self {
+            limits,
+            global_bucket,
+            buckets: Mutex::new(HashMap::new()),
+            running: AtomicUsize::new(0),
+            running_by_caller: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Admit a request of `caller` received at `now`, the returned permit counting it
+    /// as running until it is dropped.
+    fn admit(
+        self: &Arc<Self>,
+        caller: &CallerId,
+        grants: Option<&Grants>,
+        now: Instant,
+    ) -> Result<Permit, Refusal> {
+        let mut permit = Permit {
+            limiter: Arc::clone(self),
+            caller: None,
+        };
+        let running = self.running.fetch_add(1, Ordering::Relaxed) + 1;
+        metrics::gauge!("uquery_running_requests").set(running as f64);
+        if let Some(max) = self.limits.global_max_concurrent
+            && running > max
+        {
+            return Err(Refusal {
+                limit: "global_concurrency",
+                detail: format!("the server is already running {max} queries"),
+                retry_after: Duration::from_secs(1),
+            });
+        }
+        let max_concurrent = grants
+            .and_then(|grants| grants.max_concurrent_queries)
+            .or(self.limits.max_concurrent);
+        if let Some(max) = max_concurrent {
+            let mut running_by_caller = self.running_by_caller.lock().unwrap();
+            let running = running_by_caller.entry(caller.clone()).or_default();
+            if *running >= max {
+                return Err(Refusal {
+                    limit: "concurrency",
+                    detail: format!("{caller} is already running {max} queries"),
+                    retry_after: Duration::from_secs(1),
+                });
+            }
+            *running += 1;
+            permit.caller = Some(caller.clone());
+        }
+
+        let rate = grants
+            .and_then(|grants| grants.rate_limit)
+            .or(self.limits.rate);
+        if let Some(rate) = rate {
+            let mut buckets = self.buckets.lock().unwrap();
+            if !buckets.contains_key(caller) && buckets.len() >= MAX_IDLE_BUCKETS {
+                buckets.retain(|_, bucket| {
+                    bucket.refill(now);
+                    !bucket.is_full()
+                });
+            }
+            let bucket = buckets
+                .entry(caller.clone())
+                .or_insert_with(|| TokenBucket::new(rate, self.limits.burst, now));
+            if bucket.rate != rate {
+                // the rate of the caller changed with its grants
+                *bucket = TokenBucket::new(rate, self.limits.burst, now);
+            }
+            bucket.take(now).map_err(|retry_after| Refusal {
+                limit: "rate",
+                detail: format!("{caller} is limited to {rate} requests per second"),
+                retry_after,
+            })?;
+        }
+        if let (Some(bucket), Some(rate)) = (&self.global_bucket, self.limits.global_rate) {
+            let taken = bucket.lock().unwrap().take(now);
+            taken.map_err(|retry_after| Refusal {
+                limit: "global_rate",
+                detail: format!("the server is limited to {rate} requests per second"),
+                retry_after,
+            })?;
+        }
+        Ok(permit)
+    }
+}
+
+/// A request being run, released when dropped.
+#[derive(Debug)]
+struct Permit {
+    limiter: Arc<RateLimiter>,
+    /// The caller whose concurrent queries are limited.
+    caller: Option<CallerId>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let running = self.limiter.running.fetch_sub(1, Ordering::Relaxed) - 1;
+        metrics::gauge!("uquery_running_requests").set(running as f64);
+        if let Some(caller) = &self.caller {
+            let mut running_by_caller = self.limiter.running_by_caller.lock().unwrap();
+            if let Some(running) = running_by_caller.get_mut(caller) {
+                *running -= 1;
+                if *running == 0 {
+                    running_by_caller.remove(caller);
+                }
+            }
+        }
+    }
+}
+
+/// The permit of a request, shared with the handlers through the request extensions so
+/// that work outliving the response, like a job, keeps counting as running.
+#[derive(Debug, Clone)]
+pub(crate) struct HeldPermit {
+    _permit: Arc<Permit>,
+}
+
+/// Response body holding the permit of its request until it is fully sent or dropped,
+/// so a streamed query counts as running for as long as it streams.
+struct PermitBody {
+    inner: Body,
+    _permit: Arc<Permit>,
+}
+
+impl HttpBody for PermitBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        Pin::new(&mut self.inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Middleware refusing the requests over the limits of their caller, or over the
+/// global ones, with `429 Too Many Requests` and a `Retry-After` header. It runs after
+/// the authentication, to identify the callers by their principal.
+pub(crate) async fn limit(
+    State(limiter): State<Arc<RateLimiter>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let principal = request.extensions().get::<Arc<Principal>>().cloned();
+    let caller = match &principal {
+        Some(principal) => CallerId::Principal(principal.id.clone()),
+        None => request
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map_or(CallerId::Unknown, |ConnectInfo(address)| {
+                CallerId::Address(address.ip())
+            }),
+    };
+    let grants = principal.as_ref().map(|principal| &principal.grants);
+    let permit = match limiter.admit(&caller, grants, Instant::now()) {
+        Ok(permit) => permit,
+        Err(refusal) => {
+            debug!(
+                "{} {} refused: {}",
+                request.method(),
+                request.uri(),
+                refusal.detail
+            );
+            metrics::counter!("uquery_limited_requests_total", "limit" => refusal.limit)
+                .increment(1);
+            return refusal.into_response();
+        }
+    };
+    let permit = Arc::new(permit);
+    request.extensions_mut().insert(HeldPermit {
+        _permit: Arc::clone(&permit),
+    });
+    next.run(request).await.map(|body| {
+        Body::new(PermitBody {
+            inner: body,
+            _permit: permit,
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grants(rate_limit: Option<f64>, max_concurrent_queries: Option<usize>) -> Grants {
+        Grants {
+            rate_limit,
+            max_concurrent_queries,
+            ..Grants::all()
+        }
+    }
+
+    #[test]
+    fn buckets_refill_at_their_rate() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2.0, Some(3), start);
+        for _ in 0..3 {
+            assert!(bucket.take(start).is_ok());
+        }
+        assert_eq!(bucket.take(start), Err(Duration::from_millis(500)));
+        assert!(bucket.take(start + Duration::from_millis(500)).is_ok());
+        bucket.refill(start + Duration::from_secs(60));
+        assert!(bucket.is_full());
+        assert_eq!(TokenBucket::new(0.5, None, start).capacity, 1.0);
+    }
+
+    #[test]
+    fn callers_are_limited_separately() {
+        let limiter = Arc::new(
+            RateLimiter::new(Limits {
+                rate: Some(1.0),
+                ..Limits::default()
+            })
+            .unwrap(),
+        );
+        let start = Instant::now();
+        let dashboard = CallerId::Principal("dashboard".to_string());
+        let analyst = CallerId::Principal("analyst".to_string());
+        assert!(limiter.admit(&dashboard, None, start).is_ok());
+        let refusal = limiter.admit(&dashboard, None, start).unwrap_err();
+        assert_eq!(refusal.limit, "rate");
+        assert_eq!(refusal.retry_after, Duration::from_secs(1));
+        assert!(limiter.admit(&analyst, None, start).is_ok());
+
+        let fast = grants(Some(10.0), None);
+        assert!(limiter.admit(&dashboard, Some(&fast), start).is_ok());
+        assert!(limiter.admit(&analyst, None, start).is_err());
+        let later = start + Duration::from_secs(1);
+        assert!(limiter.admit(&analyst, None, later).is_ok());
+    }
+
+    #[test]
+    fn concurrent_queries_are_limited() {
+        let limiter = Arc::new(
+            RateLimiter::new(Limits {
+                max_concurrent: Some(1),
+                global_max_concurrent: Some(2),
+                ..Limits::default()
+            })
+            .unwrap(),
+        );
+        let dashboard = CallerId::Principal("dashboard".to_string());
+        let analyst = CallerId::Address("192.0.2.1".parse().unwrap());
+        let now = Instant::now();
+        let first = limiter.admit(&dashboard, None, now).unwrap();
+        assert_eq!(
+            limiter.admit(&dashboard, None, now).unwrap_err().limit,
+            "concurrency"
+        );
+        let second = limiter
+            .admit(&analyst, Some(&grants(None, Some(5))), now)
+            .unwrap();
+        assert_eq!(
+            limiter
+                .admit(&CallerId::Unknown, None, now)
+                .unwrap_err()
+                .limit,
+            "global_concurrency"
+        );
+        drop(first);
+        let _third = limiter.admit(&dashboard, None, now).unwrap();
+        drop(second);
+        assert_eq!(limiter.running.load(Ordering::Relaxed), 1);
+        assert!(
+            limiter
+                .running_by_caller
+                .lock()
+                .unwrap()
+                .get(&analyst)
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn held_permits_keep_queries_running() {
+        let limiter = Arc::new(
+            RateLimiter::new(Limits {
+                max_concurrent: Some(1),
+                ..Limits::default()
+            })
+            .unwrap(),
+        );
+        let dashboard = CallerId::Principal("dashboard".to_string());
+        let now = Instant::now();
+        let permit = Arc::new(limiter.admit(&dashboard, None, now).unwrap());
+        let job = HeldPermit {
+            _permit: Arc::clone(&permit),
+        };
+        // the response was sent, the job still runs
+        drop(permit);
+        assert!(limiter.admit(&dashboard, None, now).is_err());
+        drop(job);
+        assert!(limiter.admit(&dashboard, None, now).is_ok());
+    }
+
+    #[test]
+    fn invalid_limits_are_rejected() {
+        for limits in [
+            Limits {
+                rate: Some(0.0),
+                ..Limits::default()
+            },
+            Limits {
+                global_rate: Some(f64::NAN),
+                ..Limits::default()
+            },
+            Limits {
+                burst: Some(0),
+                ..Limits::default()
+            },
+            Limits {
+                global_max_concurrent: Some(0),
+                ..Limits::default()
+            },
+        ] {
+            assert!(RateLimiter::new(limits.clone()).is_err(), "{limits:?}");
+        }
+    }
+}
@@ -0,0 +1,189 @@
+use crate::core::engine::UQueryEngine;
+use crate::core::error::UQueryError;
+use axum::Router;
+use axum::extract::{MatchedPath, Request};
+use axum::http::StatusCode;
+use axum::http::header::CONTENT_TYPE;
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use metrics::{Unit, describe_counter, describe_gauge, describe_histogram};
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use std::sync::{Arc, OnceLock};
+use std::thread;
+use std::time::Duration;
+use tokio::task::spawn_blocking;
+use tokio::time::Instant;
+
+/// Content type of the Prometheus text exposition format.
+const CONTENT_TYPE_PROMETHEUS: &str = "text/plain; version=0.0.4";
+
+/// Upper bounds of the buckets of the duration histograms, in seconds.
+const DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 300.0,
+];
+
+/// How often the histograms drop their expired samples.
+const UPKEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The Prometheus recorder of the process, installed on first use.
+fn prometheus() -> &'static PrometheusHandle {
+    static HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+    HANDLE.get_or_init(|| {
+        let handle = PrometheusBuilder::new()
+            .set_buckets(DURATION_BUCKETS)
+            .expect("the buckets are not empty")
+            .install_recorder()
+            .expect("no other metrics recorder is installed");
+        describe();
+        let upkeep = handle.clone();
+        thread::spawn(move || {
+            loop {
+                thread::sleep(UPKEEP_INTERVAL);
+                upkeep.run_upkeep();
+            }
+        });
+        handle
+    })
+}
+
+/// Start recording the metrics of the process.
+pub fn install() {
+    prometheus();
+}
+
+fn describe() {
+    describe_counter!(
+        "uquery_http_requests_total",
+        "Requests by route, response format and status"
+    );
+    describe_histogram!(
+        "uquery_http_request_duration_seconds",
+        Unit::Seconds,
+        "Time until the response head is sent, by route, response format and status"
+    );
+    describe_histogram!(
+        "uquery_query_first_batch_seconds",
+        Unit::Seconds,
+        "Time from the request to the first result batch, by response format"
+    );
+    describe_histogram!(
+        "uquery_query_duration_seconds",
+        Unit::Seconds,
+        "Time from the request to the last byte of the result, by response format"
+    );
+    describe_counter!(
+        "uquery_rows_streamed_total",
+        "Rows sent in query results, by response format"
+    );
+    describe_counter!(
+        "uquery_bytes_streamed_total",
+        "Bytes of query results before compression, by response format"
+    );
+    describe_counter!("uquery_query_timeouts_total", "Queries that timed out");
+    describe_counter!(
+        "uquery_query_errors_total",
+        "Failed or refused queries, by class such as `Binder Error` or `Forbidden`"
+    );
+    describe_gauge!("uquery_pool_connections", "Connections of the pool");
+    describe_gauge!(
+        "uquery_pool_idle_connections",
+        "Connections of the pool not running any query"
+    );
+    describe_gauge!(
+        "uquery_pool_waiting",
+        "Queries waiting for a connection of the pool"
+    );
+    describe_histogram!(
+        "uquery_pool_wait_seconds",
+        Unit::Seconds,
+        "Time spent waiting for a connection of the pool"
+    );
+    describe_counter!(
+        "uquery_pool_rejected_total",
+        "Queries refused without a connection, by reason"
+    );
+    describe_gauge!(
+        "uquery_duckdb_memory_bytes",
+        Unit::Bytes,
+        "Memory used by DuckDB, by tag"
+    );
+    describe_gauge!(
+        "uquery_duckdb_temporary_storage_bytes",
+        Unit::Bytes,
+        "Temporary storage used by DuckDB, by tag"
+    );
+    describe_gauge!(
+        "uquery_running_requests",
+        "Requests running on the routes subject to the rate limits"
+    );
+    describe_counter!(
+        "uquery_limited_requests_total",
+        "Requests refused by a rate limit, by limit"
+    );
+}
+
+/// Middleware counting the requests and timing them until their response head is
+/// ready, by route, response format and status.
+pub(crate) async fn track(request: Request, next: Next) -> Response {
+    let start = Instant::now();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("", MatchedPath::as_str)
+        .to_string();
+    let response = next.run(request).await;
+    let format = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .unwrap_or_default()
+        .trim()
+        .to_string();
+    let labels = [
+        ("route", route),
+        ("format", format),
+        ("status", response.status().as_str().to_string()),
+    ];
+    metrics::counter!("uquery_http_requests_total", &labels).increment(1);
+    metrics::histogram!("uquery_http_request_duration_seconds", &labels)
+        .record(start.elapsed().as_secs_f64());
+    response
+}
+
+/// `GET /metrics`: the metrics of the process in the Prometheus text format, the
+/// figures of the engine being read on the fly.
+pub(crate) async fn get_metrics(engine: Arc<dyn UQueryEngine>) -> Result<Response, UQueryError> {
+    let stats = spawn_blocking(move || engine.stats())
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|stats| stats)
+        .map_err(|detail| UQueryError {
+            status_code: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+            title: "Internal Error".to_string(),
+            detail,
+        })?;
+    metrics::gauge!("uquery_pool_connections").set(stats.pool_size as f64);
+    metrics::gauge!("uquery_pool_idle_connections").set(stats.idle_connections as f64);
+    for usage in stats.memory {
+        metrics::gauge!("uquery_duckdb_memory_bytes", "tag" => usage.tag.clone())
+            .set(usage.memory_bytes as f64);
+        metrics::gauge!("uquery_duckdb_temporary_storage_bytes", "tag" => usage.tag)
+            .set(usage.temporary_storage_bytes as f64);
+    }
+    Ok((
+        [(CONTENT_TYPE, CONTENT_TYPE_PROMETHEUS)],
+        prometheus().render(),
+    )
+        .into_response())
+}
+
+/// Router of the admin port, serving the metrics apart from the queries.
+pub fn create_admin_router(engine: Arc<dyn UQueryEngine>) -> Router {
+    install();
+    Router::new()
+        .route("/metrics", get(move || get_metrics(engine)))
+        .route("/health", get(|| async { StatusCode::OK }))
+        .route_layer(middleware::from_fn(track))
+}
@@ -1,12 +1,69 @@
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
 pub const CONTENT_TYPE_CSV: &str = "text/csv";
+pub const CONTENT_TYPE_TSV: &str = "text/tab-separated-values";
 pub const CONTENT_TYPE_JSON: &str = "application/json";
+pub const CONTENT_TYPE_ENVELOPE: &str = "application/vnd.uquery+json";
 pub const CONTENT_TYPE_JSONLINES: &str = "application/jsonlines";
 pub const CONTENT_TYPE_JSONL: &str = "application/jsonl";
 pub const CONTENT_TYPE_ARROW: &str = "application/vnd.apache.arrow.stream";
+pub const CONTENT_TYPE_PARQUET: &str = "application/vnd.apache.parquet";
+pub const CONTENT_TYPE_XLSX: &str =
+    "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet";
 pub const CONTENT_TYPE_ANY: &str = "*/*";
 
+pub mod auth;
+pub mod catalog;
 pub mod consumers;
+pub mod describe;
+pub mod explain;
+pub mod jobs;
+pub mod jwt;
+pub mod limits;
+pub mod metrics;
 pub mod proxy;
+pub mod queries;
+pub mod reload;
 pub mod request;
 pub mod response;
 pub mod routers;
+pub mod saved;
+pub mod timeouts;
+pub mod traces;
+
+/// Generate an opaque, hard to guess identifier.
+pub(crate) fn new_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let seq = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let state = RandomState::new();
+    format!(
+        "{:016x}{:016x}",
+        state.hash_one(seq),
+        state.hash_one((seq, std::process::id()))
+    )
+}
+
+/// Run `task` on `target` every `interval`, on the blocking threads, as long as
+/// `target` is in use.
+pub(crate) fn periodically<T: Send + Sync + 'static>(
+    target: &Arc<T>,
+    interval: Duration,
+    task: fn(&T),
+) {
+    let target = Arc::downgrade(target);
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        ticks.tick().await;
+        loop {
+            ticks.tick().await;
+            let Some(target) = target.upgrade() else {
+                return;
+            };
+            let _ = tokio::task::spawn_blocking(move || task(&target)).await;
+        }
+    });
+}
@@ -0,0 +1,156 @@
+use crate::core::engine::QueryInterrupt;
+use crate::core::error::UQueryError;
+use crate::web::auth::Caller;
+use crate::web::new_id;
+use crate::web::routers::UQueryState;
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tracing::debug;
+
+/// Response header carrying the id of the query serving the response. Clients may
+/// also set it on the request to know the id before the first batch is ready.
+pub const QUERY_ID_HEADER: &str = "x-query-id";
+
+const MAX_QUERY_ID_LEN: usize = 128;
+
+/// Id of the query serving a request: the one provided by the client, or a new one.
+pub(crate) fn query_id(headers: &HeaderMap) -> Result<String, UQueryError> {
+    let Some(value) = headers.get(QUERY_ID_HEADER) else {
+        return Ok(new_id());
+    };
+    match value.to_str() {
+        Ok(id) if !id.is_empty() && id.len() <= MAX_QUERY_ID_LEN => Ok(id.to_string()),
+        _ => Err(UQueryError {
+            status_code: StatusCode::BAD_REQUEST.as_u16(),
+            title: "Invalid Query Id".to_string(),
+            detail: format!(
+                "{QUERY_ID_HEADER} must be a non empty string of at most {MAX_QUERY_ID_LEN} characters"
+            ),
+        }),
+    }
+}
+
+#[derive(Default)]
+struct RunningQuery {
+    /// Name of the caller running the query, the only one allowed to cancel it.
+    owner: Option<String>,
+    interrupt: Option<Arc<dyn QueryInterrupt>>,
+    cancelled: bool,
+}
+
+/// Queries currently waiting for a connection or executing, by id.
+#[derive(Default)]
+pub struct QueryRegistry {
+    queries: Mutex<HashMap<String, RunningQuery>>,
+}
+
+impl QueryRegistry {
+    /// Track a query of `owner` before it acquires a connection, so it can be
+    /// cancelled while waiting for the pool. Returns `false` if the id is already in use.
+    pub fn register(&self, id: &str, owner: Option<String>) -> bool {
+        let mut queries = self.queries.lock().unwrap();
+        if queries.contains_key(id) {
+            return false;
+        }
+        queries.insert(
+            id.to_string(),
+            RunningQuery {
+                owner,
+                ..RunningQuery::default()
+            },
+        );
+        true
+    }
+
+    /// Attach the interrupt handle of a prepared query. Returns `false` when the
+    /// query was cancelled in the meantime and must not be executed.
+    pub fn attach(&self, id: &str, interrupt: Option<Arc<dyn QueryInterrupt>>) -> bool {
+        match self.queries.lock().unwrap().get_mut(id) {
+            Some(query) if !query.cancelled => {
+                query.interrupt = interrupt;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Interrupt query `id`. Returns `false` if no such query is running.
+    pub fn cancel(&self, id: &str) -> bool {
+        self.cancel_if(id, |_| true)
+    }
+
+    /// Interrupt query `id` on behalf of `owner`. Returns `false` if `owner` runs no
+    /// such query.
+    pub fn cancel_owned(&self, id: &str, owner: Option<&str>) -> bool {
+        self.cancel_if(id, |query| query.owner.as_deref() == owner)
+    }
+
+    fn cancel_if(&self, id: &str, allowed: impl FnOnce(&RunningQuery) -> bool) -> bool {
+        match self.queries.lock().unwrap().get_mut(id) {
+            Some(query) if allowed(query) => {
+                query.cancelled = true;
+                if let Some(interrupt) = &query.interrupt {
+                    interrupt.interrupt();
+                }
+                debug!("query {id} cancelled");
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn remove(&self, id: &str) {
+        self.queries.lock().unwrap().remove(id);
+    }
+}
+
+/// Cancels the query when dropped before being disarmed, e.g. when the handler
+/// bails out before streaming or the client goes away mid-stream.
+pub(crate) struct QueryGuard {
+    id: String,
+    registry: Arc<QueryRegistry>,
+    armed: bool,
+}
+
+impl QueryGuard {
+    pub fn new(id: String, registry: Arc<QueryRegistry>) -> Self {
+        Self {
+            id,
+            registry,
+            armed: true,
+        }
+    }
+
+    /// The query ran to completion: nothing to interrupt anymore.
+    pub fn disarm(&mut self) {
+        self.armed = false;
+    }
+}
+
+impl Drop for QueryGuard {
+    fn drop(&mut self) {
+        if self.armed {
+            self.registry.cancel(&self.id);
+        }
+    }
+}
+
+/// `DELETE /queries/{id}`: interrupt a query of the caller, as if the queries of
+/// other callers did not exist.
+pub(crate) async fn cancel_query(
+    State(state): State<Arc<UQueryState>>,
+    caller: Caller,
+    Path(id): Path<String>,
+) -> Result<StatusCode, UQueryError> {
+    if state.queries.cancel_owned(&id, caller.owner().as_deref()) {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(UQueryError {
+            status_code: StatusCode::NOT_FOUND.as_u16(),
+            title: "Query Not Found".to_string(),
+            detail: format!("query [{id}] is not running"),
+        })
+    }
+}
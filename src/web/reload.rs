@@ -0,0 +1,63 @@
+use crate::web::periodically;
+use std::fs::Metadata;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tracing::warn;
+
+/// Modification time and size of a file, to detect changes on disk.
+pub(crate) type Fingerprint = (Option<SystemTime>, u64);
+
+pub(crate) fn fingerprint(metadata: &Metadata) -> Fingerprint {
+    (metadata.modified().ok(), metadata.len())
+}
+
+/// The fingerprint of the files as last loaded, so they are only loaded again once
+/// they changed.
+pub(crate) struct Loaded<F> {
+    fingerprint: Mutex<Option<F>>,
+}
+
+impl<F> Default for Loaded<F> {
+    fn default() -> Self {
+        Self {
+            fingerprint: Mutex::new(None),
+        }
+    }
+}
+
+impl<F: PartialEq> Loaded<F> {
+    /// Run `load` on the `fingerprint` of the files, unless they still have the one
+    /// they were last loaded with. A failed load is retried on the next call.
+    pub(crate) fn load_if_changed(
+        &self,
+        fingerprint: F,
+        load: impl FnOnce(&F) -> Result<(), String>,
+    ) -> Result<(), String> {
+        let mut current = self.fingerprint.lock().unwrap();
+        if current.as_ref() == Some(&fingerprint) {
+            return Ok(());
+        }
+        load(&fingerprint)?;
+        *current = Some(fingerprint);
+        Ok(())
+    }
+}
+
+/// Settings read from files, reloaded by [`watch`] when the files change.
+pub(crate) trait Reloadable: Send + Sync + 'static {
+    /// What is reloaded, as written in the logs.
+    const NAME: &'static str;
+
+    /// Reload from the files if they changed, keeping the current settings otherwise.
+    fn reload_if_changed(&self) -> Result<(), String>;
+}
+
+/// Check the files of `reloadable` for changes every `interval`, as long as it is in
+/// use.
+pub(crate) fn watch<R: Reloadable>(reloadable: &Arc<R>, interval: Duration) {
+    periodically(reloadable, interval, |reloadable| {
+        if let Err(e) = reloadable.reload_if_changed() {
+            warn!("failed to reload {}: {e}", R::NAME);
+        }
+    });
+}
@@ -1,23 +1,34 @@
 use crate::core::error::UQueryError;
+use crate::core::params::QueryParams;
 use crate::web::CONTENT_TYPE_JSON;
 use axum::body::Body;
 use axum::extract::FromRequest;
 use axum::http::header::CONTENT_TYPE;
 use axum::http::{Request, StatusCode};
 use serde::{Deserialize, Serialize};
+use tracing::field::Empty;
+use tracing::{Span, instrument};
 
 #[derive(Deserialize, Serialize)]
 pub struct QueryRequest {
     query: String,
+    #[serde(default, skip_serializing_if = "QueryParams::is_empty")]
+    params: QueryParams,
 }
 
 impl QueryRequest {
     pub fn new(query: String) -> Self {
-        Self { query }
+        Self {
+            query,
+            params: QueryParams::default(),
+        }
     }
     pub fn get_sql_query(&self) -> &str {
         &self.query
     }
+    pub fn get_params(&self) -> &QueryParams {
+        &self.params
+    }
 }
 
 impl<S> FromRequest<S> for QueryRequest
@@ -26,6 +37,7 @@ where
 {
     type Rejection = UQueryError;
 
+    #[instrument(name = "request.parse_body", skip_all, fields(http.request.body.size = Empty))]
     async fn from_request(
         req: Request<Body>,
         _state: &S,
@@ -45,6 +57,7 @@ where
                 title: "Failed to read request body".to_string(),
                 detail: e.to_string(),
             })?;
+        Span::current().record("http.request.body.size", bytes.len());
 
         if content_type.contains(CONTENT_TYPE_JSON) {
             let payload: QueryRequest =
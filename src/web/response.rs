@@ -1,29 +1,80 @@
 use crate::core::error::UQueryError;
-use crate::web::{CONTENT_TYPE_ARROW, CONTENT_TYPE_CSV, CONTENT_TYPE_JSON, CONTENT_TYPE_JSONLINES};
-use axum::http::StatusCode;
-use axum::http::header::CONTENT_TYPE;
+use crate::web::consumers::{CsvOptions, EnvelopeOptions, JsonOptions, ParquetOptions};
+use crate::web::queries::QueryGuard;
+use crate::web::{
+    CONTENT_TYPE_ARROW, CONTENT_TYPE_CSV, CONTENT_TYPE_ENVELOPE, CONTENT_TYPE_JSON,
+    CONTENT_TYPE_JSONLINES, CONTENT_TYPE_PARQUET, CONTENT_TYPE_TSV, CONTENT_TYPE_XLSX,
+};
+use axum::body::{Bytes, HttpBody};
+use axum::http::header::{CONTENT_TYPE, RETRY_AFTER, TE};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
 use axum::response::{IntoResponse, Response};
+use futures_util::Stream;
+use http_body::Frame;
 use std::fmt::Display;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use tokio::io::DuplexStream;
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+use tokio_util::io::ReaderStream;
 
+#[derive(Debug, Clone, PartialEq)]
 pub(crate) enum QueryResponseFormat {
-    Csv,
-    Json,
+    Csv(CsvOptions),
+    Tsv(CsvOptions),
+    Json(JsonOptions),
     Arrow,
-    JsonLINES,
+    JsonLINES(JsonOptions),
+    Parquet(ParquetOptions),
+    Xlsx,
+    Envelope(EnvelopeOptions),
+}
+
+impl QueryResponseFormat {
+    /// Apply the format options given as media type parameters or query-string options.
+    pub(crate) fn with_options(self, options: &[(String, String)]) -> Result<Self, String> {
+        match self {
+            QueryResponseFormat::Csv(csv) => Ok(QueryResponseFormat::Csv(csv.parse(options)?)),
+            QueryResponseFormat::Tsv(tsv) => Ok(QueryResponseFormat::Tsv(tsv.parse(options)?)),
+            QueryResponseFormat::Json(_) => {
+                Ok(QueryResponseFormat::Json(JsonOptions::parse(options)?))
+            }
+            QueryResponseFormat::JsonLINES(_) => {
+                Ok(QueryResponseFormat::JsonLINES(JsonOptions::parse(options)?))
+            }
+            QueryResponseFormat::Parquet(_) => Ok(QueryResponseFormat::Parquet(
+                ParquetOptions::parse(options)?,
+            )),
+            QueryResponseFormat::Envelope(_) => Ok(QueryResponseFormat::Envelope(
+                EnvelopeOptions::parse(options)?,
+            )),
+            format => Ok(format),
+        }
+    }
 }
 
 impl Display for QueryResponseFormat {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let str = match self {
-            QueryResponseFormat::Csv => CONTENT_TYPE_CSV.to_string(),
-            QueryResponseFormat::Json => CONTENT_TYPE_JSON.to_string(),
+            QueryResponseFormat::Csv(_) => CONTENT_TYPE_CSV.to_string(),
+            QueryResponseFormat::Tsv(_) => CONTENT_TYPE_TSV.to_string(),
+            QueryResponseFormat::Json(_) => CONTENT_TYPE_JSON.to_string(),
             QueryResponseFormat::Arrow => CONTENT_TYPE_ARROW.to_string(),
-            QueryResponseFormat::JsonLINES => CONTENT_TYPE_JSONLINES.to_string(),
+            QueryResponseFormat::JsonLINES(_) => CONTENT_TYPE_JSONLINES.to_string(),
+            QueryResponseFormat::Parquet(_) => CONTENT_TYPE_PARQUET.to_string(),
+            QueryResponseFormat::Xlsx => CONTENT_TYPE_XLSX.to_string(),
+            QueryResponseFormat::Envelope(_) => CONTENT_TYPE_ENVELOPE.to_string(),
         };
         write!(f, "{}", str)
     }
 }
 
+/// Seconds a client is asked to wait before retrying a request refused with
+/// `503 Service Unavailable`, once the server is less busy.
+const UNAVAILABLE_RETRY_AFTER: &str = "1";
+
 impl IntoResponse for UQueryError {
     fn into_response(self) -> Response {
         let mut response = (
@@ -32,9 +83,185 @@ impl IntoResponse for UQueryError {
         )
             .into_response();
 
+        let headers = response.headers_mut();
+        headers.insert(CONTENT_TYPE, "application/problem+json".parse().unwrap());
+        if self.status_code == StatusCode::SERVICE_UNAVAILABLE.as_u16() {
+            headers.insert(
+                RETRY_AFTER,
+                HeaderValue::from_static(UNAVAILABLE_RETRY_AFTER),
+            );
+        }
         response
-            .headers_mut()
-            .insert(CONTENT_TYPE, "application/problem+json".parse().unwrap());
-        response
     }
 }
+
+/// Trailer carrying the problem details of an execution that failed mid-stream.
+pub const ERROR_TRAILER: &str = "x-uquery-error";
+
+/// Trailer set to `true` when rows past the `max_rows` of the caller were left out.
+pub const TRUNCATED_TRAILER: &str = "x-uquery-truncated";
+
+/// Header carrying JSON encoding options as `key=value` pairs separated by `;`.
+pub const JSON_OPTIONS_HEADER: &str = "x-uquery-json-options";
+
+/// Whether the client announced it reads trailers (`TE: trailers`).
+pub(crate) fn accepts_trailers(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(TE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| coding.trim().eq_ignore_ascii_case("trailers"))
+}
+
+/// Streaming response body of a query. Once the result is exhausted it waits for
+/// the outcome of the execution. An execution failing after the response was sent
+/// is signalled so the partial result can't be mistaken for a complete one:
+///
+/// - JSON Lines results end with an `{"error": <problem details>}` record;
+/// - the problem details are sent in the `x-uquery-error` trailer to clients
+///   accepting trailers;
+/// - otherwise, for the other formats, the response is aborted and clients see a
+///   transport error instead of a clean end of stream.
+///
+/// A result cut at the `max_rows` of the caller ends with the `x-uquery-truncated`
+/// trailer, for clients accepting trailers.
+///
+/// Dropping the body before the end interrupts the query through its guard. The
+/// bytes sent and the time since `start` are then recorded in the metrics.
+pub(crate) struct QueryBody {
+    stream: ReaderStream<DuplexStream>,
+    /// Whether the result was truncated, or why the execution failed.
+    outcome: Option<oneshot::Receiver<Result<bool, UQueryError>>>,
+    guard: QueryGuard,
+    format: QueryResponseFormat,
+    trailers: bool,
+    /// Whether the data sent so far ends a line, to start the error record on its own.
+    at_line_start: bool,
+    /// Failure left to signal once the error record has been sent.
+    pending_error: Option<UQueryError>,
+    /// Start of the request.
+    start: Instant,
+    /// Bytes of the result sent so far.
+    bytes: u64,
+}
+
+impl QueryBody {
+    pub fn new(
+        stream: ReaderStream<DuplexStream>,
+        outcome: oneshot::Receiver<Result<bool, UQueryError>>,
+        guard: QueryGuard,
+        format: QueryResponseFormat,
+        trailers: bool,
+        start: Instant,
+    ) -> Self {
+        Self {
+            stream,
+            outcome: Some(outcome),
+            guard,
+            format,
+            trailers,
+            at_line_start: true,
+            pending_error: None,
+            start,
+            bytes: 0,
+        }
+    }
+
+    fn error_frame(&self, error: &UQueryError) -> Option<Result<Frame<Bytes>, io::Error>> {
+        if self.trailers {
+            Some(Ok(Frame::trailers(error_trailers(error))))
+        } else if matches!(self.format, QueryResponseFormat::JsonLINES(_)) {
+            None
+        } else {
+            Some(Err(io::Error::other(format!(
+                "{}: {}",
+                error.title, error.detail
+            ))))
+        }
+    }
+}
+
+impl HttpBody for QueryBody {
+    type Data = Bytes;
+    type Error = io::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+        if let Some(error) = this.pending_error.take() {
+            return Poll::Ready(this.error_frame(&error));
+        }
+        if let Some(chunk) = ready!(Pin::new(&mut this.stream).poll_next(cx)) {
+            if let Ok(chunk) = &chunk
+                && let Some(last) = chunk.last()
+            {
+                this.at_line_start = *last == b'\n';
+                this.bytes += chunk.len() as u64;
+            }
+            return Poll::Ready(Some(chunk.map(Frame::data)));
+        }
+        let Some(outcome) = this.outcome.as_mut() else {
+            return Poll::Ready(None);
+        };
+        let outcome = ready!(Pin::new(outcome).poll(cx));
+        this.outcome = None;
+        this.guard.disarm();
+        match outcome {
+            Ok(Err(error)) if matches!(this.format, QueryResponseFormat::JsonLINES(_)) => {
+                let record = error_record(&error, this.at_line_start);
+                this.pending_error = Some(error);
+                Poll::Ready(Some(Ok(Frame::data(record))))
+            }
+            Ok(Err(error)) => Poll::Ready(this.error_frame(&error)),
+            Ok(Ok(true)) if this.trailers => {
+                let mut trailers = HeaderMap::new();
+                trailers.insert(TRUNCATED_TRAILER, HeaderValue::from_static("true"));
+                Poll::Ready(Some(Ok(Frame::trailers(trailers))))
+            }
+            _ => Poll::Ready(None),
+        }
+    }
+}
+
+impl Drop for QueryBody {
+    fn drop(&mut self) {
+        let format = self.format.to_string();
+        metrics::counter!("uquery_bytes_streamed_total", "format" => format.clone())
+            .increment(self.bytes);
+        metrics::histogram!("uquery_query_duration_seconds", "format" => format)
+            .record(self.start.elapsed().as_secs_f64());
+    }
+}
+
+/// Terminal JSON Lines record reporting a failed execution.
+fn error_record(error: &UQueryError, at_line_start: bool) -> Bytes {
+    let mut record = if at_line_start {
+        String::new()
+    } else {
+        "\n".to_string()
+    };
+    record.push_str(&serde_json::json!({ "error": error }).to_string());
+    record.push('\n');
+    Bytes::from(record)
+}
+
+fn error_trailers(error: &UQueryError) -> HeaderMap {
+    // header values must be visible ASCII
+    let problem: String = serde_json::to_string(error)
+        .unwrap()
+        .chars()
+        .map(|c| {
+            if c.is_ascii() && !c.is_ascii_control() {
+                c
+            } else {
+                '?'
+            }
+        })
+        .collect();
+    let mut trailers = HeaderMap::new();
+    trailers.insert(ERROR_TRAILER, HeaderValue::from_str(&problem).unwrap());
+    trailers
+}
@@ -1,39 +1,86 @@
-use crate::core::engine::{RecordBatchConsumer, UQueryEngine};
+use crate::core::engine::{ExecutableQuery, QueryError, RecordBatchConsumer, UQueryEngine};
 use crate::core::error::UQueryError;
-use crate::web::consumers::{ArrowConsumer, WriterConsumer};
+use crate::web::auth;
+use crate::web::auth::{ApiKeys, Authenticator, Caller};
+use crate::web::catalog;
+use crate::web::consumers::{
+    ArrowConsumer, CsvOptions, EnvelopeConsumer, EnvelopeOptions, JsonOptions, ParquetConsumer,
+    ParquetOptions, WriterConsumer, XlsxConsumer,
+};
+use crate::web::describe;
+use crate::web::explain;
+use crate::web::jobs;
+use crate::web::jobs::JobStore;
+use crate::web::jwt::{JwtConfig, JwtValidator};
+use crate::web::limits;
+use crate::web::limits::{Limits, RateLimiter};
+use crate::web::metrics;
+use crate::web::queries::{QUERY_ID_HEADER, QueryGuard, QueryRegistry};
+use crate::web::reload;
 use crate::web::request::QueryRequest;
-use crate::web::response::QueryResponseFormat;
+use crate::web::response::{
+    ERROR_TRAILER, JSON_OPTIONS_HEADER, QueryBody, QueryResponseFormat, TRUNCATED_TRAILER,
+    accepts_trailers,
+};
+use crate::web::saved;
+use crate::web::saved::SavedQueries;
+use crate::web::timeouts::{DeadlineWriter, QueryTimeouts};
+use crate::web::traces;
 use crate::web::{
-    CONTENT_TYPE_ANY, CONTENT_TYPE_ARROW, CONTENT_TYPE_CSV, CONTENT_TYPE_JSON, CONTENT_TYPE_JSONL,
-    CONTENT_TYPE_JSONLINES,
+    CONTENT_TYPE_ANY, CONTENT_TYPE_ARROW, CONTENT_TYPE_CSV, CONTENT_TYPE_ENVELOPE,
+    CONTENT_TYPE_JSON, CONTENT_TYPE_JSONL, CONTENT_TYPE_JSONLINES, CONTENT_TYPE_PARQUET,
+    CONTENT_TYPE_TSV, CONTENT_TYPE_XLSX, queries,
 };
-use arrow::csv::Writer as CsvWriter;
 use arrow::datatypes::SchemaRef;
-use arrow::json::{ArrayWriter, LineDelimitedWriter};
+use arrow::json::writer::{JsonArray, LineDelimited};
 use arrow::record_batch::RecordBatch;
 
+use ::metrics::{counter, histogram};
 use axum::Router;
 use axum::body::Body;
-use axum::extract::State;
-use axum::http::header::{ACCEPT, CONTENT_TYPE};
+use axum::extract::{Query, State};
+use axum::http::header::{ACCEPT, CONTENT_TYPE, TRAILER};
 use axum::http::{HeaderMap, StatusCode};
+use axum::middleware;
 use axum::response::Response;
-use axum::routing::{get, post};
+use axum::routing::{delete, get, post};
+use std::env;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::oneshot;
 use tokio::task::spawn_blocking;
+use tokio::time::Instant;
 use tokio_util::io::{ReaderStream, SyncIoBridge};
 use tower::ServiceBuilder;
 use tower_http::compression::CompressionLayer;
 use tower_http::cors::CorsLayer;
-use tracing::error;
+use tracing::{Span, error, info_span};
 
 /// Wraps a consumer and fires `ready_tx` on the first batch (or on finish for
 /// empty results), signaling that DuckDB has produced its first result.
+/// With `ready_on_finish`, for consumers only writing in `finish`, it waits for the
+/// whole result so that errors are still reported with a proper status.
+///
+/// It also records the time to the first batch and the rows streamed in `format`.
 struct FirstBatchNotifier<C: RecordBatchConsumer> {
     inner: C,
-    ready_tx: Option<oneshot::Sender<Result<(), String>>>,
+    ready_tx: Option<oneshot::Sender<Result<(), UQueryError>>>,
+    ready_on_finish: bool,
+    /// Start of the request and span waiting for the first batch, until it comes.
+    first_batch: Option<(Instant, Span)>,
+    format: String,
+    /// Whether rows were left out of the result, past the `max_rows` of the caller.
+    truncated: bool,
+}
+
+impl<C: RecordBatchConsumer> FirstBatchNotifier<C> {
+    fn record_first_batch(&mut self) {
+        if let Some((start, _span)) = self.first_batch.take() {
+            histogram!("uquery_query_first_batch_seconds", "format" => self.format.clone())
+                .record(start.elapsed().as_secs_f64());
+        }
+    }
 }
 
 impl<C: RecordBatchConsumer> RecordBatchConsumer for FirstBatchNotifier<C> {
@@ -42,136 +89,407 @@ impl<C: RecordBatchConsumer> RecordBatchConsumer for FirstBatchNotifier<C> {
     }
 
     fn on_batch(&mut self, batch: RecordBatch) -> Result<(), String> {
-        if let Some(tx) = self.ready_tx.take() {
+        self.record_first_batch();
+        counter!("uquery_rows_streamed_total", "format" => self.format.clone())
+            .increment(batch.num_rows() as u64);
+        if !self.ready_on_finish
+            && let Some(tx) = self.ready_tx.take()
+        {
             let _ = tx.send(Ok(()));
         }
         self.inner.on_batch(batch)
     }
 
     fn finish(&mut self) -> Result<(), String> {
-        // Empty result set: no batches were produced, signal ready here.
+        self.record_first_batch();
+        // Empty result set (no batches were produced) or ready_on_finish: signal ready here.
         if let Some(tx) = self.ready_tx.take() {
             let _ = tx.send(Ok(()));
         }
         self.inner.finish()
     }
+
+    fn on_truncated(&mut self) {
+        self.truncated = true;
+        self.inner.on_truncated();
+    }
 }
 
 pub struct UQueryState {
     pub engine: Arc<dyn UQueryEngine>,
+    pub timeouts: QueryTimeouts,
+    pub jobs: Arc<JobStore>,
+    pub queries: Arc<QueryRegistry>,
+    pub saved_queries: Arc<SavedQueries>,
+}
+
+/// Server-side settings of the HTTP layer.
+pub struct RouterConfig {
+    pub cors_enabled: bool,
     pub query_timeout: Option<Duration>,
+    pub execution_timeout: Option<Duration>,
+    /// Directory where asynchronous job results are spilled.
+    pub jobs_dir: PathBuf,
+    /// How long a finished job and its result are kept.
+    pub jobs_retention: Duration,
+    /// Jobs queued or running at most, the next ones being refused.
+    pub jobs_max_pending: usize,
+    /// Directory of the `.sql` files exposed as `/q/{name}`.
+    pub queries_dir: Option<PathBuf>,
+    /// How often the queries directory is checked for changes.
+    pub queries_reload_interval: Duration,
+    /// Only run the saved queries: the endpoints taking SQL from the client are refused.
+    pub saved_queries_only: bool,
+    /// API keys as a JSON array: every request but `/health` must present one of them.
+    pub api_keys: Option<String>,
+    /// File holding the API keys as a JSON array, reloaded when it changes.
+    pub api_keys_file: Option<PathBuf>,
+    /// How often the API keys file is checked for changes.
+    pub api_keys_reload_interval: Duration,
+    /// Accept JSON Web Tokens as bearer tokens, validated against a key set.
+    pub jwt: Option<JwtConfig>,
+    /// Request rate and concurrent queries allowed per caller and in total.
+    pub limits: Limits,
+    /// Serve the Prometheus metrics on `/metrics`, off when they have a port of their own.
+    pub metrics: bool,
+}
+
+impl Default for RouterConfig {
+    fn default() -> Self {
+        Self {
+            cors_enabled: false,
+            query_timeout: None,
+            execution_timeout: None,
+            // instances must not share their results, nor remove each other's
+            jobs_dir: env::temp_dir().join(format!("uquery-jobs-{}", std::process::id())),
+            jobs_retention: Duration::from_secs(3600),
+            jobs_max_pending: 64,
+            queries_dir: None,
+            queries_reload_interval: Duration::from_secs(2),
+            saved_queries_only: false,
+            api_keys: None,
+            api_keys_file: None,
+            api_keys_reload_interval: Duration::from_secs(2),
+            jwt: None,
+            limits: Limits::default(),
+            metrics: true,
+        }
+    }
 }
 
-pub fn create_router(
-    engine: Arc<dyn UQueryEngine>,
-    cors_enabled: bool,
-    query_timeout: Option<Duration>,
-) -> Router {
+pub fn create_router(engine: Arc<dyn UQueryEngine>, config: RouterConfig) -> Router {
+    if config.saved_queries_only && config.queries_dir.is_none() {
+        panic!("saved queries only mode requires a queries directory");
+    }
+    metrics::install();
+    let jobs = Arc::new(
+        JobStore::new(
+            config.jobs_dir,
+            config.jobs_retention,
+            config.jobs_max_pending,
+        )
+        .unwrap_or_else(|e| panic!("failed to initialize jobs directory: {e}")),
+    );
+    jobs::sweep(&jobs);
+    let saved_queries = match config.queries_dir {
+        Some(dir) => {
+            let saved_queries = Arc::new(
+                SavedQueries::load(dir)
+                    .unwrap_or_else(|e| panic!("failed to load saved queries: {e}")),
+            );
+            reload::watch(&saved_queries, config.queries_reload_interval);
+            saved_queries
+        }
+        None => Arc::new(SavedQueries::default()),
+    };
+    let api_keys = match (config.api_keys, config.api_keys_file) {
+        (Some(json), _) => Some(Arc::new(
+            ApiKeys::parse(&json).unwrap_or_else(|e| panic!("invalid API keys: {e}")),
+        )),
+        (None, Some(file)) => {
+            let api_keys = Arc::new(
+                ApiKeys::load(file).unwrap_or_else(|e| panic!("failed to load API keys: {e}")),
+            );
+            reload::watch(&api_keys, config.api_keys_reload_interval);
+            Some(api_keys)
+        }
+        (None, None) => None,
+    };
+    let jwt = config.jwt.map(|jwt| {
+        Arc::new(JwtValidator::new(jwt).unwrap_or_else(|e| panic!("invalid JWT settings: {e}")))
+    });
+    let authenticator =
+        (api_keys.is_some() || jwt.is_some()).then(|| Arc::new(Authenticator { api_keys, jwt }));
     let state = Arc::new(UQueryState {
         engine,
-        query_timeout,
+        timeouts: QueryTimeouts {
+            first_batch: config.query_timeout,
+            execution: config.execution_timeout,
+        },
+        jobs,
+        queries: Arc::new(QueryRegistry::default()),
+        saved_queries,
     });
+    // in saved queries only mode, the ad-hoc SQL endpoints are not mounted
+    let ad_hoc_sql = if config.saved_queries_only {
+        Router::new()
+            .route("/", post(ad_hoc_sql_refused))
+            .route("/describe", post(ad_hoc_sql_refused))
+            .route("/explain", post(ad_hoc_sql_refused))
+            .route("/jobs", post(ad_hoc_sql_refused))
+    } else {
+        Router::new()
+            .route("/", post(query))
+            .route("/describe", post(describe::describe))
+            .route("/explain", post(explain::explain))
+            .route("/jobs", post(jobs::submit_job))
+            .route_layer(middleware::from_fn(auth::require_ad_hoc_sql))
+    };
     let router = Router::new()
+        .merge(ad_hoc_sql)
+        .route("/catalogs", get(catalog::list_catalogs))
+        .route("/schemas", get(catalog::list_schemas))
+        .route("/tables", get(catalog::list_tables))
+        .route("/tables/{name}", get(catalog::describe_table))
+        .route("/q", get(saved::list_saved_queries))
+        .route(
+            "/q/{name}",
+            get(saved::run_saved_query).post(saved::run_saved_query),
+        );
+    // the limits apply to the routes running queries, once the caller is authenticated,
+    // so that a caller at its limit can still follow its jobs and cancel its queries
+    let limiter =
+        Arc::new(RateLimiter::new(config.limits).unwrap_or_else(|e| panic!("invalid limits: {e}")));
+    let router = router
+        .route_layer(middleware::from_fn_with_state(limiter, limits::limit))
+        .route("/jobs/{id}", get(jobs::get_job))
+        .route("/jobs/{id}/result", get(jobs::get_job_result))
+        .route("/queries/{id}", delete(queries::cancel_query));
+    let router = if config.metrics {
+        router.route(
+            "/metrics",
+            get(|State(state): State<Arc<UQueryState>>| {
+                metrics::get_metrics(Arc::clone(&state.engine))
+            }),
+        )
+    } else {
+        router
+    };
+    // every route but the health check requires credentials when authentication is on
+    let router = match authenticator {
+        Some(authenticator) => router.route_layer(middleware::from_fn_with_state(
+            authenticator,
+            auth::authenticate,
+        )),
+        None => router,
+    };
+    let router = router
         .route("/health", get(|| async { StatusCode::OK }))
-        .route("/", post(query))
+        .route_layer(middleware::from_fn(metrics::track))
+        .route_layer(middleware::from_fn(traces::trace))
         .with_state(state)
         .layer(ServiceBuilder::new().layer(CompressionLayer::new()));
-    if cors_enabled {
+    if config.cors_enabled {
         router.layer(CorsLayer::permissive())
     } else {
         router
     }
 }
 
+/// `403 Forbidden` for the endpoints taking SQL from the client, when only the saved
+/// queries may run.
+async fn ad_hoc_sql_refused() -> UQueryError {
+    UQueryError {
+        status_code: StatusCode::FORBIDDEN.as_u16(),
+        title: "Ad-hoc SQL Disabled".to_string(),
+        detail: "this server only runs saved queries, listed by GET /q and run with /q/{name}"
+            .to_string(),
+    }
+}
+
 async fn query(
     State(state): State<Arc<UQueryState>>,
+    caller: Caller,
     headers: HeaderMap,
+    Query(format_options): Query<Vec<(String, String)>>,
     query_request: QueryRequest,
 ) -> Result<Response, UQueryError> {
-    let format = get_first_compatible_format(&headers).ok_or_else(|| UQueryError {
-        status_code: StatusCode::NOT_ACCEPTABLE.as_u16(),
-        title: "Unsupported response format".to_string(),
-        detail: format!(
-            "format [{}] is not supported",
-            headers
-                .get(ACCEPT)
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_lowercase()
-                .as_str()
-        ),
-    })?;
+    let format = negotiate_format(&headers, format_options)?;
+    let query_id = queries::query_id(&headers)?;
+    let scope = caller.scope();
+    let timeouts = state.timeouts.lowered_by(&headers, &scope)?;
+    let sql = query_request.get_sql_query().to_string();
+    let params = query_request.get_params().clone();
+    let uq_engine = Arc::clone(&state.engine);
+
+    // acquire a connection and defer SQL parsing to execute() — single prepare.
+    stream_query(
+        &state,
+        query_id,
+        caller.owner(),
+        format,
+        timeouts,
+        accepts_trailers(&headers),
+        move || uq_engine.prepare(&sql, params, scope),
+    )
+    .await
+}
 
+/// Run the query returned by `prepare` on a blocking thread and stream its result
+/// in `format`. The response is sent once the first batch is ready, so errors
+/// raised before any data is produced are still reported with a proper status.
+///
+/// The query is registered under the id returned in the `x-query-id` header and is
+/// interrupted if the response is dropped before the whole result has been sent,
+/// or once the execution timeout has elapsed.
+pub(crate) async fn stream_query<F>(
+    state: &UQueryState,
+    query_id: String,
+    owner: Option<String>,
+    format: QueryResponseFormat,
+    timeouts: QueryTimeouts,
+    trailers: bool,
+    prepare: F,
+) -> Result<Response, UQueryError>
+where
+    F: FnOnce() -> Result<Box<dyn ExecutableQuery>, QueryError> + Send + 'static,
+{
+    let start = Instant::now();
     let content_type = format.to_string();
-    let sql = query_request.get_sql_query().to_string();
     let (tx, rx) = tokio::io::duplex(1024 * 1024);
-    let reader_stream = ReaderStream::new(rx);
-    let (ready_tx, ready_rx) = oneshot::channel::<Result<(), String>>();
-    let uq_engine = Arc::clone(&state.engine);
-    let query_timeout = state.query_timeout;
+    let (ready_tx, ready_rx) = oneshot::channel::<Result<(), UQueryError>>();
+    let (outcome_tx, outcome_rx) = oneshot::channel::<Result<bool, UQueryError>>();
+    let registry = Arc::clone(&state.queries);
+    if !registry.register(&query_id, owner) {
+        return Err(UQueryError {
+            status_code: StatusCode::CONFLICT.as_u16(),
+            title: "Duplicate Query Id".to_string(),
+            detail: format!("query [{query_id}] is already running"),
+        });
+    }
+    let guard = QueryGuard::new(query_id.clone(), Arc::clone(&registry));
+    let task_query_id = query_id.clone();
+    let task_format = format.clone();
+    let task_content_type = content_type.clone();
+
+    // The watchdog interrupts the query once the execution deadline is reached. It
+    // stops as soon as the blocking task ends and drops `watchdog_tx`.
+    let (watchdog_tx, watchdog_rx) = oneshot::channel::<()>();
+    let deadline = timeouts.execution.map(|timeout| start + timeout);
+    if let Some(deadline) = deadline {
+        let registry = Arc::clone(&registry);
+        let query_id = query_id.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = tokio::time::sleep_until(deadline) => {
+                    registry.cancel(&query_id);
+                }
+                _ = watchdog_rx => {}
+            }
+        });
+    }
 
+    let writer = DeadlineWriter::new(tx, deadline);
+
+    let span = Span::current();
     spawn_blocking(move || {
-        // acquire a connection and defer SQL parsing to execute() — single prepare.
-        let mut prepared = uq_engine.prepare(&sql).expect("pool acquire failed");
+        let _span = span.entered();
+        let _watchdog = watchdog_tx;
+        let mut prepared = match prepare() {
+            Ok(prepared) => prepared,
+            Err(e) => {
+                registry.remove(&task_query_id);
+                let _ = ready_tx.send(Err(execution_error(e, deadline, timeouts)));
+                return;
+            }
+        };
+        if !registry.attach(&task_query_id, prepared.interrupt_handle()) {
+            registry.remove(&task_query_id);
+            let _ = ready_tx.send(Err(execution_error(
+                QueryError::Execution("query cancelled".to_string()),
+                deadline,
+                timeouts,
+            )));
+            return;
+        }
 
         // Execute. FirstBatchNotifier fires ready_tx on the first batch (or
         // finish for empty results). If execute() fails before any batch is
         // produced, ready_tx is still Some — we forward the error so the client
-        // gets a 400 instead of a dangling request.
-        let bridge = SyncIoBridge::new(tx);
+        // gets a 400 instead of a dangling request. Later failures are reported
+        // through the outcome channel, once the writer is dropped.
+        let _stream = info_span!(
+            "query.stream",
+            query.id = %task_query_id,
+            format = %task_content_type,
+        )
+        .entered();
+        let bridge = SyncIoBridge::new(writer);
         macro_rules! stream_with_notifier {
-            ($writer:expr) => {{
+            ($writer:expr) => {
+                stream_with_notifier!($writer, false)
+            };
+            ($writer:expr, $ready_on_finish:expr) => {{
                 let mut notifier = FirstBatchNotifier {
                     inner: $writer,
                     ready_tx: Some(ready_tx),
+                    ready_on_finish: $ready_on_finish,
+                    first_batch: Some((start, info_span!("query.first_batch"))),
+                    format: task_content_type,
+                    truncated: false,
                 };
-                if let Err(e) = prepared.execute(&mut notifier) {
-                    if let Some(tx) = notifier.ready_tx.take() {
-                        let _ = tx.send(Err(e.clone()));
-                    }
-                    error!("execution failed: {}", e);
-                }
+                let result = prepared.execute(&mut notifier);
+                (result, notifier.ready_tx.take(), notifier.truncated)
             }};
         }
-        match format {
-            QueryResponseFormat::Csv => {
-                stream_with_notifier!(WriterConsumer::new(CsvWriter::new(bridge)))
+        let (result, ready_tx, truncated) = match task_format {
+            QueryResponseFormat::Csv(options) | QueryResponseFormat::Tsv(options) => {
+                stream_with_notifier!(WriterConsumer::new(options.writer(bridge)))
             }
-            QueryResponseFormat::Json => {
-                stream_with_notifier!(WriterConsumer::new(ArrayWriter::new(bridge)))
+            QueryResponseFormat::Json(options) => {
+                stream_with_notifier!(WriterConsumer::new(options.writer::<_, JsonArray>(bridge)))
             }
             QueryResponseFormat::Arrow => stream_with_notifier!(ArrowConsumer::new(bridge)),
-            QueryResponseFormat::JsonLINES => {
-                stream_with_notifier!(WriterConsumer::new(LineDelimitedWriter::new(bridge)))
+            QueryResponseFormat::JsonLINES(options) => {
+                stream_with_notifier!(WriterConsumer::new(
+                    options.writer::<_, LineDelimited>(bridge)
+                ))
+            }
+            QueryResponseFormat::Parquet(options) => {
+                stream_with_notifier!(ParquetConsumer::new(bridge, options))
+            }
+            QueryResponseFormat::Xlsx => stream_with_notifier!(XlsxConsumer::new(bridge), true),
+            QueryResponseFormat::Envelope(options) => {
+                stream_with_notifier!(EnvelopeConsumer::new(bridge, start.into_std(), options))
+            }
+        };
+        registry.remove(&task_query_id);
+        match (result, ready_tx) {
+            (Ok(()), _) => {
+                let _ = outcome_tx.send(Ok(truncated));
+            }
+            (Err(e), Some(ready_tx)) => {
+                let _ = ready_tx.send(Err(execution_error(e, deadline, timeouts)));
+            }
+            (Err(e), None) => {
+                error!("execution failed: {}", e);
+                let _ = outcome_tx.send(Err(execution_error(e, deadline, timeouts)));
             }
         }
     });
 
     // Timeout covers the time from request start until the first batch is ready.
-    // Once streaming begins, results are delivered to completion.
-    let ready_result = match query_timeout {
-        Some(timeout) => {
-            tokio::time::timeout(timeout, ready_rx)
-                .await
-                .map_err(|_| UQueryError {
-                    status_code: StatusCode::REQUEST_TIMEOUT.as_u16(),
-                    title: "Query Timeout".to_string(),
-                    detail: format!("no result within {timeout:?}"),
-                })?
-        }
+    // Once streaming begins, only the execution timeout applies.
+    let ready_result = match timeouts.first_batch {
+        Some(timeout) => tokio::time::timeout(timeout, ready_rx)
+            .await
+            .map_err(|_| query_timeout_error(format!("no result within {timeout:?}")))?,
         None => ready_rx.await,
     };
 
     match ready_result {
         Ok(Ok(())) => {}
-        Ok(Err(err)) => {
-            return Err(UQueryError {
-                status_code: StatusCode::BAD_REQUEST.as_u16(),
-                title: "SQL Error".to_string(),
-                detail: err,
-            });
-        }
+        Ok(Err(err)) => return Err(err),
         Err(_) => {
             return Err(UQueryError {
                 status_code: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
@@ -181,106 +499,395 @@ async fn query(
         }
     }
 
-    Ok(Response::builder()
+    let mut response = Response::builder()
         .status(StatusCode::OK)
         .header(CONTENT_TYPE, content_type)
-        .body(Body::from_stream(reader_stream))
+        .header(QUERY_ID_HEADER, query_id);
+    if trailers {
+        response = response.header(TRAILER, format!("{ERROR_TRAILER}, {TRUNCATED_TRAILER}"));
+    }
+    Ok(response
+        .body(Body::new(QueryBody::new(
+            ReaderStream::new(rx),
+            outcome_rx,
+            guard,
+            format,
+            trailers,
+            start,
+        )))
         .unwrap())
 }
 
-fn get_first_compatible_format(headers: &HeaderMap) -> Option<QueryResponseFormat> {
-    let accept_value = headers.get(ACCEPT)?.to_str().unwrap().to_lowercase();
-    for format in accept_value.split(",").collect::<Vec<&str>>() {
-        match format {
-            CONTENT_TYPE_JSON | CONTENT_TYPE_ANY => return Some(QueryResponseFormat::Json),
-            CONTENT_TYPE_CSV => return Some(QueryResponseFormat::Csv),
-            CONTENT_TYPE_ARROW => return Some(QueryResponseFormat::Arrow),
+/// Map an execution failure to the error reported to the client: a query stopped
+/// by its execution deadline is a timeout, a refused statement is forbidden, anything
+/// else a SQL error.
+fn execution_error(
+    error: QueryError,
+    deadline: Option<Instant>,
+    timeouts: QueryTimeouts,
+) -> UQueryError {
+    match (deadline, timeouts.execution) {
+        (Some(deadline), Some(timeout)) if Instant::now() >= deadline => {
+            query_timeout_error(format!("execution exceeded {timeout:?}"))
+        }
+        _ => query_error(error),
+    }
+}
+
+/// The problem details of a query that failed or was refused.
+pub(crate) fn query_error(error: QueryError) -> UQueryError {
+    counter!("uquery_query_errors_total", "class" => error_class(&error)).increment(1);
+    match error {
+        QueryError::Forbidden(detail) => UQueryError {
+            status_code: StatusCode::FORBIDDEN.as_u16(),
+            title: "Statement Not Allowed".to_string(),
+            detail,
+        },
+        QueryError::Execution(detail) => UQueryError {
+            status_code: StatusCode::BAD_REQUEST.as_u16(),
+            title: "SQL Error".to_string(),
+            detail,
+        },
+        QueryError::Unavailable(detail) => UQueryError {
+            status_code: StatusCode::SERVICE_UNAVAILABLE.as_u16(),
+            title: "Server Busy".to_string(),
+            detail,
+        },
+        QueryError::NotFound(detail) => UQueryError {
+            status_code: StatusCode::NOT_FOUND.as_u16(),
+            title: "Not Found".to_string(),
+            detail,
+        },
+    }
+}
+
+/// Class of a failed query in the metrics: the class of the DuckDB error, such as
+/// `Binder Error`, or why the query was refused.
+fn error_class(error: &QueryError) -> String {
+    match error {
+        QueryError::Forbidden(_) => "Forbidden".to_string(),
+        QueryError::Unavailable(_) => "Unavailable".to_string(),
+        QueryError::NotFound(_) => "NotFound".to_string(),
+        QueryError::Execution(detail) => detail
+            .split_once(": ")
+            .map(|(class, _)| class)
+            .filter(|class| {
+                class.ends_with(" Error")
+                    && class.chars().all(|c| c.is_ascii_alphabetic() || c == ' ')
+            })
+            .unwrap_or("SQL Error")
+            .to_string(),
+    }
+}
+
+pub(crate) fn query_timeout_error(detail: String) -> UQueryError {
+    counter!("uquery_query_timeouts_total").increment(1);
+    UQueryError {
+        status_code: StatusCode::REQUEST_TIMEOUT.as_u16(),
+        title: "Query Timeout".to_string(),
+        detail,
+    }
+}
+
+/// Pick the response format from the `Accept` header. Format options are read from
+/// the parameters of the matching media type, then from the `x-uquery-json-options`
+/// header and finally from the query string.
+pub(crate) fn negotiate_format(
+    headers: &HeaderMap,
+    query_options: Vec<(String, String)>,
+) -> Result<QueryResponseFormat, UQueryError> {
+    // without an Accept header, any format is acceptable
+    let accept = match headers.get(ACCEPT) {
+        Some(accept) => accept.to_str().map_err(|_| UQueryError {
+            status_code: StatusCode::BAD_REQUEST.as_u16(),
+            title: "Invalid Accept Header".to_string(),
+            detail: "the Accept header must be visible ASCII".to_string(),
+        })?,
+        None => CONTENT_TYPE_ANY,
+    };
+    let (format, mut options) = get_first_compatible_format(accept).ok_or_else(|| UQueryError {
+        status_code: StatusCode::NOT_ACCEPTABLE.as_u16(),
+        title: "Unsupported response format".to_string(),
+        detail: format!("format [{}] is not supported", accept.to_lowercase()),
+    })?;
+    if let Some(json_options) = headers.get(JSON_OPTIONS_HEADER) {
+        let json_options = json_options.to_str().map_err(|_| UQueryError {
+            status_code: StatusCode::BAD_REQUEST.as_u16(),
+            title: "Invalid Format Option".to_string(),
+            detail: format!("{JSON_OPTIONS_HEADER} must be visible ASCII"),
+        })?;
+        options.extend(
+            split_unquoted(json_options, ';')
+                .into_iter()
+                .filter_map(format_option),
+        );
+    }
+    options.extend(query_options);
+    format.with_options(&options).map_err(|detail| UQueryError {
+        status_code: StatusCode::BAD_REQUEST.as_u16(),
+        title: "Invalid Format Option".to_string(),
+        detail,
+    })
+}
+
+fn get_first_compatible_format(
+    accept: &str,
+) -> Option<(QueryResponseFormat, Vec<(String, String)>)> {
+    for media_range in split_unquoted(accept, ',') {
+        let mut parts = split_unquoted(media_range, ';').into_iter();
+        let media_type = parts.next().unwrap_or_default().trim().to_lowercase();
+        let format = match media_type.as_str() {
+            CONTENT_TYPE_JSON | CONTENT_TYPE_ANY => {
+                QueryResponseFormat::Json(JsonOptions::default())
+            }
+            CONTENT_TYPE_CSV => QueryResponseFormat::Csv(CsvOptions::default()),
+            CONTENT_TYPE_TSV => QueryResponseFormat::Tsv(CsvOptions::tsv()),
+            CONTENT_TYPE_ARROW => QueryResponseFormat::Arrow,
             CONTENT_TYPE_JSONLINES | CONTENT_TYPE_JSONL => {
-                return Some(QueryResponseFormat::JsonLINES);
+                QueryResponseFormat::JsonLINES(JsonOptions::default())
             }
-            _ => {}
+            CONTENT_TYPE_PARQUET => QueryResponseFormat::Parquet(ParquetOptions::default()),
+            CONTENT_TYPE_XLSX => QueryResponseFormat::Xlsx,
+            CONTENT_TYPE_ENVELOPE => QueryResponseFormat::Envelope(EnvelopeOptions::default()),
+            _ => continue,
         };
+        return Some((format, parts.filter_map(format_option).collect()));
     }
     None
 }
 
+/// A `key=value` format option, the value possibly quoted.
+fn format_option(option: &str) -> Option<(String, String)> {
+    let (key, value) = option.split_once('=')?;
+    let value = value.trim();
+    let value = value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(value);
+    Some((key.trim().to_lowercase(), value.to_string()))
+}
+
+/// Split `value` on `separator`, except inside double quotes (e.g. `delimiter=";"`).
+fn split_unquoted(value: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    for (i, c) in value.char_indices() {
+        if c == '"' {
+            quoted = !quoted;
+        } else if c == separator && !quoted {
+            parts.push(&value[start..i]);
+            start = i + c.len_utf8();
+        }
+    }
+    parts.push(&value[start..]);
+    parts
+}
+
 #[test]
 fn content_negotiation_test() {
-    let mut headers = HeaderMap::new();
-    headers.insert(ACCEPT, "application/json,text/html".parse().unwrap());
+    let accept = "application/json,text/html";
     assert!(matches!(
-        get_first_compatible_format(&headers),
-        Some(QueryResponseFormat::Json)
+        get_first_compatible_format(accept),
+        Some((QueryResponseFormat::Json(_), _))
     ));
 
-    headers.remove(ACCEPT);
-    headers.insert(ACCEPT, "application/json".parse().unwrap());
+    let accept = "application/json";
     assert!(matches!(
-        get_first_compatible_format(&headers),
-        Some(QueryResponseFormat::Json)
+        get_first_compatible_format(accept),
+        Some((QueryResponseFormat::Json(_), _))
     ));
 
-    headers.remove(ACCEPT);
-    headers.insert(ACCEPT, "text/csv".parse().unwrap());
+    let accept = "text/csv";
     assert!(matches!(
-        get_first_compatible_format(&headers),
-        Some(QueryResponseFormat::Csv)
+        get_first_compatible_format(accept),
+        Some((QueryResponseFormat::Csv(_), _))
     ));
 
-    headers.remove(ACCEPT);
-    headers.insert(
-        ACCEPT,
-        "application/vnd.apache.arrow.stream".parse().unwrap(),
-    );
+    let accept = "application/vnd.apache.arrow.stream";
+    assert!(matches!(
+        get_first_compatible_format(accept),
+        Some((QueryResponseFormat::Arrow, _))
+    ));
+
+    let accept = "application/json,text/csv";
+    assert!(matches!(
+        get_first_compatible_format(accept),
+        Some((QueryResponseFormat::Json(_), _))
+    ));
+
+    let accept = "application/xml,application/vnd.apache.arrow.stream";
     assert!(matches!(
-        get_first_compatible_format(&headers),
-        Some(QueryResponseFormat::Arrow)
+        get_first_compatible_format(accept),
+        Some((QueryResponseFormat::Arrow, _))
     ));
 
-    headers.remove(ACCEPT);
-    headers.insert(ACCEPT, "application/json,text/csv".parse().unwrap());
+    let accept = "text/html,application/xml";
+    assert!(matches!(get_first_compatible_format(accept), None));
+
+    let accept = "application/jsonlines";
     assert!(matches!(
-        get_first_compatible_format(&headers),
-        Some(QueryResponseFormat::Json)
+        get_first_compatible_format(accept),
+        Some((QueryResponseFormat::JsonLINES(_), _))
     ));
 
-    headers.remove(ACCEPT);
+    let accept = "application/jsonl";
+    assert!(matches!(
+        get_first_compatible_format(accept),
+        Some((QueryResponseFormat::JsonLINES(_), _))
+    ));
+
+    let accept = "*/*";
+    assert!(matches!(
+        get_first_compatible_format(accept),
+        Some((QueryResponseFormat::Json(_), _))
+    ));
+
+    let accept = "text/html;q=0.9, text/csv; q=0.8";
+    assert!(matches!(
+        get_first_compatible_format(accept),
+        Some((QueryResponseFormat::Csv(_), _))
+    ));
+
+    let accept = "application/vnd.apache.parquet;compression=zstd;row_group_size=1000";
+    let (format, params) = get_first_compatible_format(accept).unwrap();
+    assert!(matches!(format, QueryResponseFormat::Parquet(_)));
+    assert_eq!(
+        params,
+        vec![
+            ("compression".to_string(), "zstd".to_string()),
+            ("row_group_size".to_string(), "1000".to_string())
+        ]
+    );
+}
+
+#[test]
+fn csv_dialect_test() {
+    let mut headers = HeaderMap::new();
     headers.insert(
         ACCEPT,
-        "application/xml,application/vnd.apache.arrow.stream"
+        r#"text/csv;header=absent;delimiter=";";null=NULL, application/json"#
             .parse()
             .unwrap(),
     );
-    assert!(matches!(
-        get_first_compatible_format(&headers),
-        Some(QueryResponseFormat::Arrow)
-    ));
+    let format = negotiate_format(
+        &headers,
+        vec![("date_format".to_string(), "%d/%m/%Y".to_string())],
+    )
+    .unwrap();
+    let QueryResponseFormat::Csv(options) = format else {
+        panic!("csv format expected");
+    };
+    assert_eq!(options.delimiter, b';');
+    assert!(!options.header);
+    assert_eq!(options.null, "NULL");
+    assert_eq!(options.date_format.as_deref(), Some("%d/%m/%Y"));
+
+    headers.insert(ACCEPT, "text/tab-separated-values".parse().unwrap());
+    let format = negotiate_format(&headers, vec![]).unwrap();
+    assert_eq!(format.to_string(), "text/tab-separated-values");
+    assert!(matches!(format, QueryResponseFormat::Tsv(options) if options.delimiter == b'\t'));
 
-    headers.remove(ACCEPT);
-    headers.insert(ACCEPT, "text/html,application/xml".parse().unwrap());
-    assert!(matches!(get_first_compatible_format(&headers), None));
+    headers.insert(ACCEPT, "text/csv;delimiter=ab".parse().unwrap());
+    assert_eq!(
+        negotiate_format(&headers, vec![]).unwrap_err().status_code,
+        400
+    );
+}
 
-    headers.remove(ACCEPT);
-    headers.insert(ACCEPT, "application/jsonlines".parse().unwrap());
+#[test]
+fn missing_or_invalid_accept_test() {
+    let mut headers = HeaderMap::new();
     assert!(matches!(
-        get_first_compatible_format(&headers),
-        Some(QueryResponseFormat::JsonLINES)
+        negotiate_format(&headers, vec![]),
+        Ok(QueryResponseFormat::Json(_))
     ));
+    headers.insert(
+        ACCEPT,
+        axum::http::HeaderValue::from_bytes(b"text/csv\xe9").unwrap(),
+    );
+    assert_eq!(
+        negotiate_format(&headers, vec![]).unwrap_err().status_code,
+        400
+    );
+}
 
-    headers.remove(ACCEPT);
-    headers.insert(ACCEPT, "application/jsonl".parse().unwrap());
+#[test]
+fn format_options_test() {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        ACCEPT,
+        "application/vnd.apache.parquet;compression=zstd;row_group_size=1000"
+            .parse()
+            .unwrap(),
+    );
+    let format = negotiate_format(
+        &headers,
+        vec![("row_group_size".to_string(), "10".to_string())],
+    )
+    .unwrap();
+    let QueryResponseFormat::Parquet(options) = format else {
+        panic!("parquet format expected");
+    };
     assert!(matches!(
-        get_first_compatible_format(&headers),
-        Some(QueryResponseFormat::JsonLINES)
+        options.compression,
+        parquet::basic::Compression::ZSTD(_)
     ));
+    assert_eq!(options.row_group_size, 10);
+    assert!(options.dictionary);
 
-    headers.remove(ACCEPT);
-    headers.insert(ACCEPT, "*/*".parse().unwrap());
+    let error = negotiate_format(
+        &headers,
+        vec![("compression".to_string(), "lzma".to_string())],
+    )
+    .unwrap_err();
+    assert_eq!(error.status_code, 400);
+    let error = negotiate_format(
+        &headers,
+        vec![("row_group_size".to_string(), "1048577".to_string())],
+    )
+    .unwrap_err();
+    assert_eq!(error.status_code, 400);
+
+    headers.insert(ACCEPT, "application/json".parse().unwrap());
     assert!(matches!(
-        get_first_compatible_format(&headers),
-        Some(QueryResponseFormat::Json)
+        negotiate_format(
+            &headers,
+            vec![("compression".to_string(), "lzma".to_string())]
+        ),
+        Ok(QueryResponseFormat::Json(_))
     ));
+}
+
+#[test]
+fn json_options_test() {
+    use crate::web::consumers::{JsonNumbers, JsonTimestamps};
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        ACCEPT,
+        "application/jsonl;int64=string;timestamp=iso"
+            .parse()
+            .unwrap(),
+    );
+    headers.insert(
+        JSON_OPTIONS_HEADER,
+        "decimal=string; timestamp=epoch_ms".parse().unwrap(),
+    );
+    let format = negotiate_format(
+        &headers,
+        vec![("nulls".to_string(), "explicit".to_string())],
+    )
+    .unwrap();
+    let QueryResponseFormat::JsonLINES(options) = format else {
+        panic!("json lines format expected");
+    };
+    assert_eq!(options.int64, JsonNumbers::String);
+    assert_eq!(options.decimal, JsonNumbers::String);
+    assert_eq!(options.timestamp, JsonTimestamps::EpochMillis);
+    assert!(options.explicit_nulls);
 
-    headers.remove(ACCEPT);
-    assert!(matches!(get_first_compatible_format(&headers), None));
+    headers.insert(JSON_OPTIONS_HEADER, "binary=base32".parse().unwrap());
+    let error = negotiate_format(&headers, vec![]).unwrap_err();
+    assert_eq!(error.status_code, 400);
 }
@@ -0,0 +1,490 @@
+use crate::core::error::UQueryError;
+use crate::core::params::{QueryParam, QueryParams, TypedParam};
+use crate::web::auth::Caller;
+use crate::web::queries;
+use crate::web::reload::{Fingerprint, Loaded, Reloadable, fingerprint};
+use crate::web::response::accepts_trailers;
+use crate::web::routers::{UQueryState, negotiate_format, stream_query};
+use arrow::compute::kernels::cast_utils::Parser;
+use arrow::datatypes::{Date32Type, TimestampMicrosecondType};
+use axum::Json;
+use axum::body::Bytes;
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::Response;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path as FsPath, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use tracing::{info, warn};
+
+const SQL_EXTENSION: &str = "sql";
+const FRONT_MATTER_DELIMITER: &str = "---";
+
+/// Type of a saved query parameter. Values are checked against it before binding.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ParamType {
+    Varchar,
+    Integer,
+    Double,
+    Boolean,
+    Date,
+    Timestamp,
+}
+
+impl FromStr for ParamType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "varchar" | "text" | "string" => Ok(ParamType::Varchar),
+            "integer" | "int" | "bigint" => Ok(ParamType::Integer),
+            "double" | "float" => Ok(ParamType::Double),
+            "boolean" | "bool" => Ok(ParamType::Boolean),
+            "date" => Ok(ParamType::Date),
+            "timestamp" => Ok(ParamType::Timestamp),
+            other => Err(format!("unknown parameter type [{other}]")),
+        }
+    }
+}
+
+impl ParamType {
+    /// Convert a JSON value, or the text of a query string value, to a parameter of
+    /// this type. `null` is accepted for every type.
+    fn bind(self, value: &Value) -> Result<QueryParam, String> {
+        let text = match value {
+            Value::Null => return Ok(QueryParam::Null),
+            Value::String(text) => Some(text.as_str()),
+            _ => None,
+        };
+        let param = match (self, value, text) {
+            (ParamType::Varchar, _, Some(text)) => Some(QueryParam::Text(text.to_string())),
+            (ParamType::Varchar, Value::Number(n), _) => Some(QueryParam::Text(n.to_string())),
+            (ParamType::Varchar, Value::Bool(b), _) => Some(QueryParam::Text(b.to_string())),
+            (ParamType::Integer, Value::Number(n), _) => n.as_i64().map(QueryParam::Int),
+            (ParamType::Integer, _, Some(text)) => text.trim().parse().ok().map(QueryParam::Int),
+            (ParamType::Double, Value::Number(n), _) => n.as_f64().map(QueryParam::Float),
+            (ParamType::Double, _, Some(text)) => text.trim().parse().ok().map(QueryParam::Float),
+            (ParamType::Boolean, Value::Bool(b), _) => Some(QueryParam::Bool(*b)),
+            (ParamType::Boolean, _, Some(text)) => text.trim().parse().ok().map(QueryParam::Bool),
+            (ParamType::Date, _, Some(text)) => Date32Type::parse(text)
+                .map(|_| QueryParam::Typed(TypedParam::Date(text.to_string()))),
+            (ParamType::Timestamp, _, Some(text)) => TimestampMicrosecondType::parse(text)
+                .map(|_| QueryParam::Typed(TypedParam::Timestamp(text.to_string()))),
+            _ => None,
+        };
+        param.ok_or_else(|| format!("{value} is not a valid {}", self.name()))
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            ParamType::Varchar => "varchar",
+            ParamType::Integer => "integer",
+            ParamType::Double => "double",
+            ParamType::Boolean => "boolean",
+            ParamType::Date => "date",
+            ParamType::Timestamp => "timestamp",
+        }
+    }
+}
+
+/// A parameter declared in the front matter of a saved query, as
+/// `name: type` or `name: type = default`.
+#[derive(Debug, Clone, Serialize)]
+pub struct SavedParam {
+    name: String,
+    #[serde(rename = "type")]
+    param_type: ParamType,
+    required: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    default: Option<QueryParam>,
+}
+
+/// A `.sql` file of the queries directory, exposed as `/q/{name}`.
+#[derive(Debug, Clone, Serialize)]
+pub struct SavedQuery {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    params: Vec<SavedParam>,
+    #[serde(skip)]
+    sql: String,
+}
+
+impl SavedQuery {
+    /// Parse a saved query: an optional front matter between `---` lines, declaring
+    /// a `description` and the `params`, then the SQL statement.
+    ///
+    /// ```text
+    /// ---
+    /// description: Trips of a vendor
+    /// params:
+    ///   vendor: integer
+    ///   limit: integer = 10
+    /// ---
+    /// SELECT * FROM trips WHERE vendor_id = $vendor LIMIT $limit
+    /// ```
+    pub fn parse(name: &str, content: &str) -> Result<Self, String> {
+        let mut query = SavedQuery {
+            name: name.to_string(),
+            description: None,
+            params: Vec::new(),
+            sql: content.trim().to_string(),
+        };
+        let mut lines = content.lines();
+        if lines.next().map(str::trim) == Some(FRONT_MATTER_DELIMITER) {
+            let mut in_params = false;
+            let mut closed = false;
+            for line in lines.by_ref() {
+                if line.trim() == FRONT_MATTER_DELIMITER {
+                    closed = true;
+                    break;
+                }
+                if line.trim().is_empty() || line.trim_start().starts_with('#') {
+                    continue;
+                }
+                let (key, value) = line
+                    .split_once(':')
+                    .ok_or_else(|| format!("invalid front matter line [{}]", line.trim()))?;
+                let (key, value) = (key.trim(), value.trim());
+                if line.starts_with(char::is_whitespace) {
+                    if !in_params {
+                        return Err(format!("unexpected indented line [{}]", line.trim()));
+                    }
+                    query.add_param(key, value)?;
+                    continue;
+                }
+                in_params = false;
+                match key {
+                    "description" => query.description = Some(unquote(value).to_string()),
+                    "params" if value.is_empty() => in_params = true,
+                    _ => return Err(format!("unknown front matter key [{key}]")),
+                }
+            }
+            if !closed {
+                return Err("front matter is not closed by ---".to_string());
+            }
+            query.sql = lines.collect::<Vec<_>>().join("\n").trim().to_string();
+        }
+        if query.sql.is_empty() {
+            return Err("no SQL statement".to_string());
+        }
+        Ok(query)
+    }
+
+    fn add_param(&mut self, name: &str, declaration: &str) -> Result<(), String> {
+        if !is_identifier(name) {
+            return Err(format!("invalid parameter name [{name}]"));
+        }
+        if self.params.iter().any(|param| param.name == name) {
+            return Err(format!("parameter [{name}] is declared twice"));
+        }
+        let (param_type, default) = match declaration.split_once('=') {
+            Some((param_type, default)) => (param_type, Some(default.trim())),
+            None => (declaration, None),
+        };
+        let param_type: ParamType = param_type.trim().parse()?;
+        let default = default
+            .map(|default| match default {
+                "null" | "NULL" => Ok(QueryParam::Null),
+                default => param_type.bind(&Value::String(unquote(default).to_string())),
+            })
+            .transpose()
+            .map_err(|e| format!("invalid default of parameter [{name}]: {e}"))?;
+        self.params.push(SavedParam {
+            name: name.to_string(),
+            param_type,
+            required: default.is_none(),
+            default,
+        });
+        Ok(())
+    }
+
+    /// Check `values` against the declared parameters and fill in the defaults.
+    fn bind(&self, values: BTreeMap<String, Value>) -> Result<QueryParams, String> {
+        if let Some(name) = values
+            .keys()
+            .find(|name| !self.params.iter().any(|param| &param.name == *name))
+        {
+            return Err(format!("unknown parameter [{name}]"));
+        }
+        let mut params = BTreeMap::new();
+        for param in &self.params {
+            let value = match (values.get(&param.name), &param.default) {
+                (Some(value), _) => param
+                    .param_type
+                    .bind(value)
+                    .map_err(|e| format!("parameter [{}]: {e}", param.name))?,
+                (None, Some(default)) => default.clone(),
+                (None, None) => return Err(format!("missing parameter [{}]", param.name)),
+            };
+            params.insert(param.name.clone(), value);
+        }
+        Ok(QueryParams::Named(params))
+    }
+
+    fn declares(&self, name: &str) -> bool {
+        self.params.iter().any(|param| param.name == name)
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn unquote(value: &str) -> &str {
+    ['"', '\'']
+        .iter()
+        .find_map(|quote| value.strip_prefix(*quote)?.strip_suffix(*quote))
+        .unwrap_or(value)
+}
+
+/// Fingerprint of every `.sql` file, to detect changes on disk.
+type DirFingerprint = Vec<(PathBuf, Fingerprint)>;
+
+/// The saved queries of a directory, reloaded when its `.sql` files change.
+#[derive(Default)]
+pub struct SavedQueries {
+    dir: Option<PathBuf>,
+    queries: RwLock<BTreeMap<String, Arc<SavedQuery>>>,
+    loaded: Loaded<DirFingerprint>,
+}
+
+impl SavedQueries {
+    pub fn load(dir: PathBuf) -> Result<Self, String> {
+        let saved = Self {
+            dir: Some(dir),
+            ..Self::default()
+        };
+        saved.reload_if_changed()?;
+        Ok(saved)
+    }
+
+    fn get(&self, name: &str) -> Option<Arc<SavedQuery>> {
+        self.queries.read().unwrap().get(name).cloned()
+    }
+
+    fn list(&self, caller: &Caller) -> Vec<SavedQuery> {
+        let queries = self.queries.read().unwrap();
+        queries
+            .values()
+            .filter(|query| caller.may_run(&query.name))
+            .map(|query| query.as_ref().clone())
+            .collect()
+    }
+}
+
+impl Reloadable for SavedQueries {
+    const NAME: &'static str = "saved queries";
+
+    /// Reload every query if a `.sql` file was added, changed or removed. Invalid
+    /// files are skipped with a warning so that the other queries stay available.
+    fn reload_if_changed(&self) -> Result<(), String> {
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+        self.loaded.load_if_changed(dir_fingerprint(dir)?, |files| {
+            let mut queries = BTreeMap::new();
+            for (path, _) in files {
+                match load_query(path) {
+                    Ok(query) => {
+                        queries.insert(query.name.clone(), Arc::new(query));
+                    }
+                    Err(e) => warn!("skipping saved query {}: {e}", path.display()),
+                }
+            }
+            info!(
+                "loaded {} saved queries from {}",
+                queries.len(),
+                dir.display()
+            );
+            *self.queries.write().unwrap() = queries;
+            Ok(())
+        })
+    }
+}
+
+fn dir_fingerprint(dir: &FsPath) -> Result<DirFingerprint, String> {
+    let entries = fs::read_dir(dir).map_err(|e| format!("{}: {e}", dir.display()))?;
+    let mut files: DirFingerprint = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == SQL_EXTENSION))
+        .filter_map(|path| {
+            let metadata = fs::metadata(&path).ok()?;
+            metadata.is_file().then(|| (path, fingerprint(&metadata)))
+        })
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+fn load_query(path: &FsPath) -> Result<SavedQuery, String> {
+    let name = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .filter(|stem| {
+            !stem.is_empty()
+                && stem
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        })
+        .ok_or("the file name must only contain letters, digits, _ and -")?;
+    let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    SavedQuery::parse(name, &content)
+}
+
+/// `GET /q`: the saved queries the caller may run, with their parameters.
+pub(crate) async fn list_saved_queries(
+    State(state): State<Arc<UQueryState>>,
+    caller: Caller,
+) -> Json<Vec<SavedQuery>> {
+    Json(state.saved_queries.list(&caller))
+}
+
+/// `GET` or `POST /q/{name}`: run a saved query. Parameters are read from the query
+/// string, then from the JSON object of the body; the other query string entries
+/// are format options.
+pub(crate) async fn run_saved_query(
+    State(state): State<Arc<UQueryState>>,
+    Path(name): Path<String>,
+    caller: Caller,
+    headers: HeaderMap,
+    Query(options): Query<Vec<(String, String)>>,
+    body: Bytes,
+) -> Result<Response, UQueryError> {
+    if !caller.may_run(&name) {
+        return Err(caller.forbidden_query(&name));
+    }
+    let query = state.saved_queries.get(&name).ok_or_else(|| UQueryError {
+        status_code: StatusCode::NOT_FOUND.as_u16(),
+        title: "Query Not Found".to_string(),
+        detail: format!("saved query [{name}] does not exist"),
+    })?;
+    let (values, format_options): (Vec<_>, Vec<_>) = options
+        .into_iter()
+        .partition(|(key, _)| query.declares(key));
+    let mut values: BTreeMap<String, Value> = values
+        .into_iter()
+        .map(|(key, value)| (key, Value::String(value)))
+        .collect();
+    if !body.is_empty() {
+        let body: BTreeMap<String, Value> =
+            serde_json::from_slice(&body).map_err(|e| UQueryError {
+                status_code: StatusCode::BAD_REQUEST.as_u16(),
+                title: "Invalid JSON".to_string(),
+                detail: e.to_string(),
+            })?;
+        values.extend(body);
+    }
+    let params = query.bind(values).map_err(|detail| UQueryError {
+        status_code: StatusCode::BAD_REQUEST.as_u16(),
+        title: "Invalid Parameter".to_string(),
+        detail,
+    })?;
+    let format = negotiate_format(&headers, format_options)?;
+    let query_id = queries::query_id(&headers)?;
+    let scope = caller.scope();
+    let timeouts = state.timeouts.lowered_by(&headers, &scope)?;
+    let engine = Arc::clone(&state.engine);
+    stream_query(
+        &state,
+        query_id,
+        caller.owner(),
+        format,
+        timeouts,
+        accepts_trailers(&headers),
+        move || engine.prepare(&query.sql, params, scope),
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const TRIPS: &str = "---
+description: Trips of a vendor
+params:
+  vendor: integer
+  since: date = '2024-01-01'
+  label: varchar = null
+---
+SELECT * FROM trips WHERE vendor_id = $vendor AND day >= $since
+";
+
+    #[test]
+    fn front_matter_is_parsed() {
+        let query = SavedQuery::parse("trips", TRIPS).unwrap();
+        assert_eq!(query.description.as_deref(), Some("Trips of a vendor"));
+        assert_eq!(
+            query.sql,
+            "SELECT * FROM trips WHERE vendor_id = $vendor AND day >= $since"
+        );
+        assert_eq!(query.params.len(), 3);
+        assert_eq!(query.params[0].param_type, ParamType::Integer);
+        assert!(query.params[0].required);
+        assert_eq!(
+            query.params[1].default,
+            Some(QueryParam::Typed(TypedParam::Date(
+                "2024-01-01".to_string()
+            )))
+        );
+        assert_eq!(query.params[2].default, Some(QueryParam::Null));
+    }
+
+    #[test]
+    fn query_without_front_matter() {
+        let query = SavedQuery::parse("one", "SELECT 1\n").unwrap();
+        assert!(query.params.is_empty());
+        assert_eq!(query.sql, "SELECT 1");
+    }
+
+    #[test]
+    fn invalid_front_matter_is_rejected() {
+        for content in [
+            "---\nparams:\n  n: integer\nSELECT $n",
+            "---\nparams:\n  n: uuid\n---\nSELECT $n",
+            "---\nparams:\n  n: integer = ten\n---\nSELECT $n",
+            "---\nparams:\n  1n: integer\n---\nSELECT 1",
+            "---\nauthor: me\n---\nSELECT 1",
+            "---\ndescription: nothing\n---\n",
+        ] {
+            assert!(SavedQuery::parse("invalid", content).is_err(), "{content}");
+        }
+    }
+
+    #[test]
+    fn values_are_checked_and_defaults_filled() {
+        let query = SavedQuery::parse("trips", TRIPS).unwrap();
+        let values = BTreeMap::from([("vendor".to_string(), json!("2"))]);
+        let QueryParams::Named(params) = query.bind(values).unwrap() else {
+            panic!("named parameters expected");
+        };
+        assert_eq!(params["vendor"], QueryParam::Int(2));
+        assert_eq!(params["label"], QueryParam::Null);
+
+        assert!(query.bind(BTreeMap::new()).is_err());
+        let values = BTreeMap::from([("vendor".to_string(), json!("two"))]);
+        assert!(query.bind(values).is_err());
+        let values = BTreeMap::from([
+            ("vendor".to_string(), json!(2)),
+            ("since".to_string(), json!("2024-13-45")),
+        ]);
+        assert!(query.bind(values).is_err());
+        let values = BTreeMap::from([
+            ("vendor".to_string(), json!(2)),
+            ("other".to_string(), json!(1)),
+        ]);
+        assert!(query.bind(values).is_err());
+    }
+}
@@ -0,0 +1,233 @@
+use crate::core::engine::QueryScope;
+use crate::core::error::UQueryError;
+use axum::http::{HeaderMap, StatusCode};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::AsyncWrite;
+use tokio::time::{Instant, Sleep};
+
+/// Request header lowering the time allowed until the first result batch, in seconds.
+pub const QUERY_TIMEOUT_HEADER: &str = "x-query-timeout";
+/// Request header lowering the time allowed for the whole execution, in seconds.
+pub const EXECUTION_TIMEOUT_HEADER: &str = "x-execution-timeout";
+
+/// Deadlines applied to a single query, both measured from the start of the request.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct QueryTimeouts {
+    /// Time allowed until the first batch is ready; the request fails with a 408 after it.
+    pub first_batch: Option<Duration>,
+    /// Time allowed for the whole execution; the query is interrupted mid-stream after it.
+    pub execution: Option<Duration>,
+}
+
+impl QueryTimeouts {
+    /// Apply the timeout of the caller in `scope` and the timeouts requested by the
+    /// client, which may only lower the server maximums in `self`.
+    pub fn lowered_by(self, headers: &HeaderMap, scope: &QueryScope) -> Result<Self, UQueryError> {
+        Ok(Self {
+            first_batch: lower(
+                lower(self.first_batch, scope.timeout),
+                header_timeout(headers, QUERY_TIMEOUT_HEADER)?,
+            ),
+            execution: lower(
+                lower(self.execution, scope.timeout),
+                header_timeout(headers, EXECUTION_TIMEOUT_HEADER)?,
+            ),
+        })
+    }
+
+    /// The shortest of both timeouts, for requests answered only once the query ended.
+    pub fn shortest(&self) -> Option<Duration> {
+        lower(self.first_batch, self.execution)
+    }
+}
+
+fn lower(max: Option<Duration>, requested: Option<Duration>) -> Option<Duration> {
+    match (max, requested) {
+        (Some(max), Some(requested)) => Some(max.min(requested)),
+        (max, requested) => max.or(requested),
+    }
+}
+
+fn header_timeout(headers: &HeaderMap, name: &str) -> Result<Option<Duration>, UQueryError> {
+    let Some(value) = headers.get(name) else {
+        return Ok(None);
+    };
+    value
+        .to_str()
+        .ok()
+        .and_then(|v| v.trim().parse::<f64>().ok())
+        .filter(|secs| *secs > 0.0)
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .map(Some)
+        .ok_or_else(|| UQueryError {
+            status_code: StatusCode::BAD_REQUEST.as_u16(),
+            title: "Invalid Timeout".to_string(),
+            detail: format!("{name} must be a positive number of seconds"),
+        })
+}
+
+/// Writer failing once `deadline` is reached, so a query blocked on a slow client
+/// gives its connection back when the execution timeout elapses.
+pub(crate) struct DeadlineWriter<W> {
+    inner: W,
+    deadline: Option<Pin<Box<Sleep>>>,
+}
+
+impl<W> DeadlineWriter<W> {
+    pub fn new(inner: W, deadline: Option<Instant>) -> Self {
+        Self {
+            inner,
+            deadline: deadline.map(|deadline| Box::pin(tokio::time::sleep_until(deadline))),
+        }
+    }
+
+    fn poll_deadline(&mut self, cx: &mut Context<'_>) -> Poll<io::Error> {
+        match self.deadline.as_mut() {
+            Some(deadline) => deadline
+                .as_mut()
+                .poll(cx)
+                .map(|_| io::Error::new(io::ErrorKind::TimedOut, "execution timeout")),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for DeadlineWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if let Poll::Ready(e) = self.poll_deadline(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Poll::Ready(e) = self.poll_deadline(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn no_header_keeps_server_timeouts() {
+        let server = QueryTimeouts {
+            first_batch: Some(Duration::from_secs(30)),
+            execution: None,
+        };
+        assert_eq!(
+            server
+                .lowered_by(&HeaderMap::new(), &QueryScope::default())
+                .unwrap(),
+            server
+        );
+    }
+
+    #[test]
+    fn header_lowers_server_timeouts() {
+        let server = QueryTimeouts {
+            first_batch: Some(Duration::from_secs(30)),
+            execution: Some(Duration::from_secs(60)),
+        };
+        let timeouts = server
+            .lowered_by(
+                &headers(&[
+                    (QUERY_TIMEOUT_HEADER, "0.5"),
+                    (EXECUTION_TIMEOUT_HEADER, "10"),
+                ]),
+                &QueryScope::default(),
+            )
+            .unwrap();
+        assert_eq!(timeouts.first_batch, Some(Duration::from_millis(500)));
+        assert_eq!(timeouts.execution, Some(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn header_cannot_raise_server_timeouts() {
+        let server = QueryTimeouts {
+            first_batch: Some(Duration::from_secs(30)),
+            execution: Some(Duration::from_secs(60)),
+        };
+        let timeouts = server
+            .lowered_by(
+                &headers(&[
+                    (QUERY_TIMEOUT_HEADER, "120"),
+                    (EXECUTION_TIMEOUT_HEADER, "3600"),
+                ]),
+                &QueryScope::default(),
+            )
+            .unwrap();
+        assert_eq!(timeouts, server);
+    }
+
+    #[test]
+    fn caller_timeout_lowers_server_and_header_timeouts() {
+        let server = QueryTimeouts {
+            first_batch: Some(Duration::from_secs(30)),
+            execution: None,
+        };
+        let scope = QueryScope {
+            timeout: Some(Duration::from_secs(10)),
+            ..QueryScope::default()
+        };
+        let timeouts = server
+            .lowered_by(
+                &headers(&[
+                    (QUERY_TIMEOUT_HEADER, "1.5"),
+                    (EXECUTION_TIMEOUT_HEADER, "60"),
+                ]),
+                &scope,
+            )
+            .unwrap();
+        assert_eq!(timeouts.first_batch, Some(Duration::from_millis(1500)));
+        assert_eq!(timeouts.execution, Some(Duration::from_secs(10)));
+        let timeouts = server.lowered_by(&HeaderMap::new(), &scope).unwrap();
+        assert_eq!(timeouts.first_batch, Some(Duration::from_secs(10)));
+        assert_eq!(timeouts.execution, Some(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn header_applies_without_server_timeout() {
+        let timeouts = QueryTimeouts::default()
+            .lowered_by(
+                &headers(&[(EXECUTION_TIMEOUT_HEADER, "5")]),
+                &QueryScope::default(),
+            )
+            .unwrap();
+        assert_eq!(timeouts.first_batch, None);
+        assert_eq!(timeouts.execution, Some(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn invalid_header_is_rejected() {
+        for value in ["abc", "-1", "0"] {
+            let result = QueryTimeouts::default().lowered_by(
+                &headers(&[(QUERY_TIMEOUT_HEADER, value)]),
+                &QueryScope::default(),
+            );
+            assert_eq!(result.unwrap_err().status_code, 400);
+        }
+    }
+}